@@ -10,6 +10,8 @@ pub static X_WEAVE_BYTES: &str = "x-weave-bytes";
 pub static X_WEAVE_TOTAL_RECORDS: &str = "x-weave-total-records";
 pub static X_WEAVE_TOTAL_BYTES: &str = "x-weave-total-bytes";
 pub static X_VERIFY_CODE: &str = "x-verify-code";
+pub static X_WEAVE_BACKOFF: &str = "x-weave-backoff";
+pub static X_REQUEST_ID: &str = "x-request-id";
 
 // max load size in bytes
 pub const MAX_SPANNER_LOAD_SIZE: usize = 100_000_000;
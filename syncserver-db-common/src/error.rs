@@ -56,6 +56,9 @@ pub enum DbErrorKind {
     #[error("User over quota")]
     Quota,
 
+    #[error("Batch exceeds the server's total record or byte limits")]
+    BatchTooLarge,
+
     #[error("Connection expired")]
     Expired,
 }
@@ -88,6 +91,10 @@ impl DbError {
         matches!(self.kind, DbErrorKind::Quota)
     }
 
+    pub fn is_batch_too_large(&self) -> bool {
+        matches!(self.kind, DbErrorKind::BatchTooLarge)
+    }
+
     pub fn is_bso_not_found(&self) -> bool {
         matches!(self.kind, DbErrorKind::BsoNotFound)
     }
@@ -102,7 +109,9 @@ impl From<DbErrorKind> for DbError {
         let status = match kind {
             DbErrorKind::CollectionNotFound | DbErrorKind::BsoNotFound => StatusCode::NOT_FOUND,
             // Matching the Python code here (a 400 vs 404)
-            DbErrorKind::BatchNotFound | DbErrorKind::SpannerTooLarge(_) => StatusCode::BAD_REQUEST,
+            DbErrorKind::BatchNotFound
+            | DbErrorKind::BatchTooLarge
+            | DbErrorKind::SpannerTooLarge(_) => StatusCode::BAD_REQUEST,
             // NOTE: the protocol specification states that we should return a
             // "409 Conflict" response here, but clients currently do not
             // handle these respones very well:
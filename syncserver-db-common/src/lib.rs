@@ -170,11 +170,20 @@ pub trait Db<'a>: Debug + 'a {
 
     fn put_bso(&self, params: params::PutBso) -> DbFuture<'_, results::PutBso>;
 
+    /// Create a batch holding its first items, subject to the same limits as
+    /// `append_to_batch`
     fn create_batch(&self, params: params::CreateBatch) -> DbFuture<'_, results::CreateBatch>;
 
     fn validate_batch(&self, params: params::ValidateBatch)
         -> DbFuture<'_, results::ValidateBatch>;
 
+    /// Append items to a batch
+    ///
+    /// Fails with `DbErrorKind::BatchTooLarge` when the append leaves the
+    /// batch exceeding the server's `max_total_records` or `max_total_bytes`
+    /// limits. The totals are read back after writing, so that items re-sent
+    /// within the batch replace, rather than add to, their earlier sizes; the
+    /// error aborts the request's transaction, discarding the append.
     fn append_to_batch(
         &self,
         params: params::AppendToBatch,
@@ -182,6 +191,11 @@ pub trait Db<'a>: Debug + 'a {
 
     fn get_batch(&self, params: params::GetBatch) -> DbFuture<'_, Option<results::GetBatch>>;
 
+    fn get_batch_status(
+        &self,
+        params: params::GetBatchStatus,
+    ) -> DbFuture<'_, Option<results::GetBatchStatus>>;
+
     fn commit_batch(&self, params: params::CommitBatch) -> DbFuture<'_, results::CommitBatch>;
 
     fn box_clone(&self) -> Box<dyn Db<'a>>;
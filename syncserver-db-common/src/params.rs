@@ -143,6 +143,9 @@ collection_data! {
     GetBatch {
         id: String,
     },
+    GetBatchStatus {
+        id: String,
+    },
     DeleteBatch {
         id: String,
     },
@@ -33,6 +33,19 @@ pub struct CreateBatch {
 pub type ValidateBatch = bool;
 pub type AppendToBatch = ();
 pub type GetBatch = params::Batch;
+
+/// The state of a pending batch.
+#[derive(Debug, Clone, Serialize)]
+pub struct GetBatchStatus {
+    pub id: String,
+    /// The number of records appended to the batch
+    pub count: u64,
+    /// The combined size of the payloads appended to the batch, in bytes
+    pub total_bytes: u64,
+    /// When the batch expires, if it isn't committed first
+    pub expiry: SyncTimestamp,
+}
+
 pub type DeleteBatch = ();
 pub type CommitBatch = SyncTimestamp;
 pub type ValidateBatchId = ();
@@ -21,15 +21,23 @@ pub struct Settings {
     pub port: u16,
     pub host: String,
     pub actix_keep_alive: Option<u32>,
-    /// The master secret, from which are derived
-    /// the signing secret and token secret
-    /// that are used during Hawk authentication.
+    /// The master secret (or an ordered list of them, the first of which
+    /// signs new tokens), from which are derived the signing secret and
+    /// token secret that are used during Hawk authentication.
     pub master_secret: Secrets,
 
     pub human_logs: bool,
 
     pub statsd_host: Option<String>,
     pub statsd_port: u16,
+    /// Whether to export metrics in the Prometheus format from
+    /// `/__metrics__` (alongside statsd, unless `statsd_host` is unset)
+    pub prometheus_enabled: bool,
+
+    /// The OTLP (gRPC) collector to export traces to, e.g. "localhost:4317"
+    pub otlp_endpoint: Option<String>,
+    /// The fraction of traces (not already sampled by the caller) to export
+    pub trace_sample_rate: f64,
 
     /// Cors Settings
     pub cors_allowed_origin: Option<String>,
@@ -85,29 +93,9 @@ impl Settings {
                         syncstorage_pool_max_size + tokenserver_pool_max_size
                     };
 
-                    let fxa_threads = if s.tokenserver.enabled
-                        && s.tokenserver.fxa_oauth_primary_jwk.is_none()
-                        && s.tokenserver.fxa_oauth_secondary_jwk.is_none()
-                    {
-                        s.tokenserver
-                            .additional_blocking_threads_for_fxa_requests
-                            .ok_or_else(|| {
-                                println!(
-                                    "If the Tokenserver OAuth JWK is not cached, additional blocking \
-                                        threads must be used to handle the requests to FxA."
-                                );
-
-                                let setting_name =
-                                    "tokenserver.additional_blocking_threads_for_fxa_requests";
-                                ConfigError::NotFound(String::from(setting_name))
-                            })?
-                    } else {
-                        0
-                    };
-
                     env::set_var(
                         "ACTIX_THREADPOOL",
-                        ((total_db_pool_size + fxa_threads) as usize)
+                        (total_db_pool_size as usize)
                             .max(num_cpus::get() * 5)
                             .to_string(),
                     );
@@ -175,6 +163,9 @@ impl Default for Settings {
             master_secret: Secrets::default(),
             statsd_host: Some("localhost".to_owned()),
             statsd_port: 8125,
+            prometheus_enabled: false,
+            otlp_endpoint: None,
+            trace_sample_rate: 1.0,
             human_logs: false,
             cors_allowed_origin: None,
             cors_allowed_methods: Some(
@@ -210,32 +201,66 @@ impl Default for Settings {
 }
 
 /// Secrets used during Hawk authentication.
+///
+/// Several master secrets may be configured, in order, so that they can be
+/// rotated without invalidating every outstanding token at once: tokens are
+/// signed with the first, while tokens signed with any of them are accepted.
 #[derive(Clone, Debug)]
 pub struct Secrets {
-    /// The master secret in byte array form.
+    /// The (first) master secret in byte array form.
     ///
     /// The signing secret and token secret are derived from this.
     pub master_secret: Vec<u8>,
 
     /// The signing secret used during Hawk authentication.
     pub signing_secret: [u8; 32],
+
+    /// Any further master secrets still accepted during Hawk authentication,
+    /// each paired with the signing secret derived from it.
+    pub previous_secrets: Vec<(Vec<u8>, [u8; 32])>,
 }
 
 impl Secrets {
     /// Decode the master secret to a byte array
     /// and derive the signing secret from it.
     pub fn new(master_secret: &str) -> Result<Self, String> {
-        let master_secret = master_secret.as_bytes().to_vec();
-        let signing_secret = syncserver_common::hkdf_expand_32(
-            b"services.mozilla.com/tokenlib/v1/signing",
-            None,
-            &master_secret,
-        )?;
+        Self::from_list(&[master_secret])
+    }
+
+    /// Decode an ordered list of master secrets, deriving each of their
+    /// signing secrets. The first is used for signing.
+    pub fn from_list<S: AsRef<str>>(master_secrets: &[S]) -> Result<Self, String> {
+        let mut secrets = master_secrets
+            .iter()
+            .map(|master_secret| {
+                let master_secret = master_secret.as_ref().as_bytes().to_vec();
+                let signing_secret = syncserver_common::hkdf_expand_32(
+                    b"services.mozilla.com/tokenlib/v1/signing",
+                    None,
+                    &master_secret,
+                )?;
+                Ok((master_secret, signing_secret))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if secrets.is_empty() {
+            return Err("At least one master secret is required".to_owned());
+        }
+        let (master_secret, signing_secret) = secrets.remove(0);
         Ok(Self {
             master_secret,
             signing_secret,
+            previous_secrets: secrets,
         })
     }
+
+    /// All of the master secrets and their signing secrets, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8; 32])> {
+        std::iter::once((self.master_secret.as_slice(), &self.signing_secret)).chain(
+            self.previous_secrets
+                .iter()
+                .map(|(master_secret, signing_secret)| (master_secret.as_slice(), signing_secret)),
+        )
+    }
 }
 
 impl Default for Secrets {
@@ -244,20 +269,30 @@ impl Default for Secrets {
         Self {
             master_secret: vec![],
             signing_secret: [0u8; 32],
+            previous_secrets: vec![],
         }
     }
 }
 
 impl<'d> Deserialize<'d> for Secrets {
     /// Deserialize the master secret and signing secret byte arrays
-    /// from a single master secret string.
+    /// from either a single master secret string or a list of them.
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'d>,
     {
-        let master_secret: String = Deserialize::deserialize(deserializer)?;
-        Secrets::new(&master_secret)
-            .map_err(|e| serde::de::Error::custom(format!("error: {:?}", e)))
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum MasterSecrets {
+            One(String),
+            Many(Vec<String>),
+        }
+
+        let result = match Deserialize::deserialize(deserializer)? {
+            MasterSecrets::One(master_secret) => Secrets::new(&master_secret),
+            MasterSecrets::Many(master_secrets) => Secrets::from_list(&master_secrets),
+        };
+        result.map_err(|e| serde::de::Error::custom(format!("error: {:?}", e)))
     }
 }
 
@@ -281,4 +316,28 @@ mod test {
         let settings = Settings::with_env_and_config_file(None).unwrap();
         assert!(!settings.tokenserver.enabled);
     }
+
+    #[test]
+    fn test_master_secret_list() {
+        let mut config = Config::default();
+        config
+            .set("master_secret", vec!["current", "previous"])
+            .unwrap();
+        let settings: Settings = config.try_into().unwrap();
+        let secrets = settings.master_secret;
+        assert_eq!(secrets.master_secret, b"current");
+        assert_eq!(
+            secrets.signing_secret,
+            Secrets::new("current").unwrap().signing_secret
+        );
+        let master_secrets: Vec<_> = secrets.iter().map(|(secret, _)| secret).collect();
+        assert_eq!(master_secrets, vec![&b"current"[..], &b"previous"[..]]);
+
+        let mut config = Config::default();
+        config.set("master_secret", "current").unwrap();
+        let settings: Settings = config.try_into().unwrap();
+        assert_eq!(settings.master_secret.iter().count(), 1);
+
+        assert!(Secrets::from_list::<&str>(&[]).is_err());
+    }
 }
@@ -0,0 +1,115 @@
+//! Process account-related events from FxA.
+//!
+//! This binary consumes the account events FxA publishes to its relying parties and applies them
+//! to the Tokenserver database. See `syncserver::tokenserver::account_events` for the supported
+//! event types.
+//!
+//! Note that this is a purely optional administrative task, highly specific to Mozilla's internal
+//! Firefox-Accounts-supported deployment.
+#[macro_use]
+extern crate slog_scope;
+
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufReader},
+};
+
+use docopt::Docopt;
+use serde::Deserialize;
+
+use syncserver::{
+    error::ApiError,
+    logging::init_logging,
+    server::metrics::{metrics_from_opts, Metrics},
+    tokenserver::{
+        account_events::{process_account_event, EventSource, LineEventSource},
+        db::{
+            params,
+            pool::{DbPool, TokenserverPool},
+        },
+    },
+};
+use syncserver_settings::Settings;
+
+const USAGE: &str = "
+Usage: process_account_events [options] [<events-file>]
+
+Reads account event messages, one per line, from the given file or from stdin if no file is
+given. Each line should contain the body of a message as delivered by the account events queue.
+
+Options:
+    -h, --help               Show this message.
+    --config=CONFIGFILE      Syncserver configuration file path.
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_config: Option<String>,
+    arg_events_file: Option<String>,
+}
+
+/// Processes events from the given source until it is exhausted. A message is only acknowledged
+/// once it has been processed, so messages that fail due to database errors will be redelivered.
+async fn process_account_events(
+    source: &mut dyn EventSource,
+    pool: &TokenserverPool,
+    service_id: i32,
+) -> Result<(), Box<dyn Error>> {
+    let db = pool.get().await.map_err(ApiError::from)?;
+
+    while let Some(body) = source.receive().await? {
+        process_account_event(&*db, service_id, &body)
+            .await
+            .map_err(ApiError::from)?;
+        source.acknowledge().await?;
+    }
+
+    Ok(())
+}
+
+#[actix_web::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    let settings = Settings::with_env_and_config_file(args.flag_config.as_deref())?;
+    init_logging(!settings.human_logs).expect("Logging failed to initialize");
+
+    let metrics = metrics_from_opts(
+        &settings.tokenserver.statsd_label,
+        settings.statsd_host.as_deref(),
+        settings.statsd_port,
+    )?;
+    let pool = TokenserverPool::new(&settings.tokenserver, &Metrics::from(&metrics), false)
+        .map_err(ApiError::from)?;
+    let service_id = pool
+        .get_sync()
+        .and_then(|db| {
+            db.get_service_id_sync(params::GetServiceId {
+                service: "sync-1.5".to_owned(),
+            })
+        })
+        .map_err(ApiError::from)?
+        .id;
+
+    let result = match &args.arg_events_file {
+        Some(path) => {
+            info!("Processing account events from {}", path);
+            let mut source = LineEventSource::new(BufReader::new(File::open(path)?));
+            process_account_events(&mut source, &pool, service_id).await
+        }
+        None => {
+            info!("Processing account events from stdin");
+            let stdin = io::stdin();
+            let mut source = LineEventSource::new(stdin.lock());
+            process_account_events(&mut source, &pool, service_id).await
+        }
+    };
+
+    if let Err(e) = &result {
+        error!("Error while processing account events: {}", e);
+    }
+
+    result
+}
@@ -0,0 +1,336 @@
+//! Purge user records that have been replaced.
+//!
+//! This binary purges any obsolete user records from the Tokenserver database. Obsolete records
+//! are those that have been replaced by a newer record for the same user. Before a record is
+//! deleted, the user's storage node is (optionally) asked to delete any data it still holds for
+//! the user.
+//!
+//! Note that this is a purely optional administrative task, since replaced records are handled
+//! internally by the assignment backend. But it should help reduce overheads, improve performance
+//! etc if run regularly.
+#[macro_use]
+extern crate slog_scope;
+
+use std::{
+    error::Error,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use docopt::Docopt;
+use hawk::{Credentials, DigestAlgorithm, Key, RequestBuilder};
+use rand::Rng;
+use reqwest::{header::AUTHORIZATION, Client as ReqwestClient, StatusCode};
+use serde::Deserialize;
+use url::Url;
+
+use syncserver::{
+    error::ApiError,
+    logging::init_logging,
+    server::metrics::{metrics_from_opts, Metrics},
+    tokenserver::{
+        auth::{MakeTokenPlaintext, Tokenlib, TokenserverOrigin},
+        db::{
+            models::Db,
+            params,
+            pool::{DbPool, TokenserverPool},
+            results::OldUser,
+        },
+    },
+};
+use syncserver_settings::Settings;
+
+const USAGE: &str = "
+Usage: purge_old_records [options]
+
+Options:
+    -h, --help                   Show this message.
+    --config=CONFIGFILE          Syncserver configuration file path.
+    --purge-interval=SECONDS     Interval to sleep between purging runs [default: 3600].
+    --grace-period=SECONDS       Number of seconds grace to allow on replacement [default: 86400].
+    --max-per-loop=N             Maximum number of records to fetch in one go [default: 10].
+    --max-offset=N               Use a random offset from 0 to N into the purgeable records
+                                 [default: 0].
+    --max-records=N              Maximum number of records to purge in a single run, or 0 for no
+                                 limit [default: 0].
+    --request-timeout=SECONDS    Timeout for storage node deletion requests [default: 60].
+    --skip-storage-delete        Only delete the Tokenserver records, without asking the storage
+                                 nodes to delete the users' data.
+    --dry-run                    Log the records that would be purged without purging them.
+    --oneshot                    Do a single purge run and then exit.
+";
+
+/// The path of a user's storage on their node, relative to the node's URL.
+const STORAGE_PATH_PATTERN: &str = "1.5";
+
+/// How long the tokens used to delete users' data from their storage nodes are valid.
+const DELETE_TOKEN_DURATION: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_config: Option<String>,
+    flag_purge_interval: u64,
+    flag_grace_period: u64,
+    flag_max_per_loop: i64,
+    flag_max_offset: i64,
+    flag_max_records: usize,
+    flag_request_timeout: u64,
+    flag_skip_storage_delete: bool,
+    flag_dry_run: bool,
+    flag_oneshot: bool,
+}
+
+struct Purger {
+    pool: TokenserverPool,
+    service_id: i32,
+    secret: String,
+    request_client: ReqwestClient,
+    metrics: Metrics,
+    grace_period: Duration,
+    max_per_loop: i64,
+    max_offset: i64,
+    max_records: Option<usize>,
+    skip_storage_delete: bool,
+    dry_run: bool,
+}
+
+impl Purger {
+    /// Purges old records from the database.
+    ///
+    /// This queries the old user records in the database in batches, issues a delete request to
+    /// each user's storage node, and then deletes the Tokenserver record for each of the users.
+    /// The result is a gradual pruning of expired items from each database.
+    ///
+    /// `max_offset` is used to select a random offset into the list of purgeable records. With
+    /// multiple tasks running concurrently, this will provide each a (likely) different set of
+    /// records to work on. A cheap, imperfect randomization.
+    async fn purge_old_records(&self) -> Result<usize, Box<dyn Error>> {
+        info!("Purging old user records");
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("purge_old_records.duration", None);
+
+        let db = self.pool.get().await.map_err(ApiError::from)?;
+        let replaced_before = now() - self.grace_period;
+        let mut offset = if self.max_offset > 0 {
+            rand::thread_rng().gen_range(0..=self.max_offset)
+        } else {
+            0
+        };
+        let mut purged = 0;
+
+        loop {
+            let users = db
+                .get_old_users(params::GetOldUsers {
+                    service_id: self.service_id,
+                    replaced_before: replaced_before.as_millis() as i64,
+                    limit: self.max_per_loop,
+                    offset,
+                })
+                .await
+                .map_err(ApiError::from)?;
+            info!("Fetched {} rows at offset {}", users.len(), offset);
+
+            let mut deleted = 0;
+            for user in &users {
+                if self.max_records.map_or(false, |max| purged >= max) {
+                    info!("Reached the maximum of {} purged records", purged);
+                    return Ok(purged);
+                }
+
+                if self.purge_user(&*db, user).await? {
+                    purged += 1;
+
+                    if !self.dry_run {
+                        deleted += 1;
+                    }
+                }
+            }
+
+            if (users.len() as i64) < self.max_per_loop {
+                break;
+            }
+
+            // Any records we didn't delete are still in the result set, so skip past them
+            offset += users.len() as i64 - deleted;
+        }
+
+        info!("Finished purging {} old user records", purged);
+        Ok(purged)
+    }
+
+    /// Purges a single user record, returning whether the record was (or in a dry run, would
+    /// have been) purged.
+    async fn purge_user(&self, db: &dyn Db, user: &OldUser) -> Result<bool, Box<dyn Error>> {
+        match &user.node {
+            // Don't attempt to purge data from downed nodes. Instead wait for them to either come
+            // back up or to be completely removed from service.
+            Some(node) if user.downed.unwrap_or(0) != 0 => {
+                info!("Skipping uid {} on downed node {}", user.uid, node);
+                self.metrics.incr("purge_old_records.skipped_downed");
+                return Ok(false);
+            }
+            Some(node) => {
+                info!("Purging uid {} on {}", user.uid, node);
+
+                if !self.skip_storage_delete && !self.dry_run {
+                    // Keep the record so the delete's retried on a later run, rather than
+                    // letting one unreachable node stop the whole run
+                    if let Err(e) = self.delete_service_data(node, user).await {
+                        warn!(
+                            "Failed to delete uid {}'s data from {}: {}",
+                            user.uid, node, e
+                        );
+                        self.metrics.incr("purge_old_records.storage_delete_error");
+                        return Ok(false);
+                    }
+                    self.metrics.incr("purge_old_records.storage_deleted");
+                }
+            }
+            None => info!("Deleting user record for uid {}", user.uid),
+        }
+
+        if !self.dry_run {
+            db.delete_user(params::DeleteUser {
+                service_id: self.service_id,
+                uid: user.uid,
+            })
+            .await
+            .map_err(ApiError::from)?;
+            self.metrics.incr("purge_old_records.user_deleted");
+        }
+
+        Ok(true)
+    }
+
+    /// Sends a data-deletion request to the user's storage node.
+    ///
+    /// This is a little bit of hackery to cause the user's storage node to remove any data it
+    /// still has stored for the user. We simulate a DELETE request from the user's own account.
+    async fn delete_service_data(&self, node: &str, user: &OldUser) -> Result<(), Box<dyn Error>> {
+        let fxa_kid = format!(
+            "{:013}-{}",
+            user.keys_changed_at.unwrap_or(user.generation),
+            base64::encode_config(&hex::decode(&user.client_state)?, base64::URL_SAFE_NO_PAD)
+        );
+        let plaintext = MakeTokenPlaintext {
+            node: node.to_owned(),
+            fxa_kid,
+            fxa_uid: user.email.split('@').next().unwrap_or_default().to_owned(),
+            hashed_device_id: String::new(),
+            hashed_fxa_uid: String::new(),
+            expires: (now() + DELETE_TOKEN_DURATION).as_secs(),
+            uid: user.uid,
+            tokenserver_origin: TokenserverOrigin::Rust,
+        };
+        let (token, derived_secret) =
+            Tokenlib::get_token_and_derived_secret(plaintext, &self.secret)?;
+
+        let endpoint = Url::parse(&format!(
+            "{}/{}/{}",
+            node.trim_end_matches('/'),
+            STORAGE_PATH_PATTERN,
+            user.uid
+        ))?;
+        let credentials = Credentials {
+            id: token,
+            key: Key::new(derived_secret.as_bytes(), DigestAlgorithm::Sha256)?,
+        };
+        let header = RequestBuilder::new(
+            "DELETE",
+            endpoint.host_str().unwrap_or_default(),
+            endpoint.port_or_known_default().unwrap_or(443),
+            endpoint.path(),
+        )
+        .request()
+        .make_header(&credentials)?;
+
+        let response = self
+            .request_client
+            .delete(endpoint)
+            .header(AUTHORIZATION, format!("Hawk {}", header))
+            .send()
+            .await?;
+
+        // A 404 means the node has no data for the user, which is fine
+        let status = response.status();
+        if (status.is_client_error() || status.is_server_error()) && status != StatusCode::NOT_FOUND
+        {
+            return Err(format!(
+                "Storage node returned {} when deleting uid {}",
+                status, user.uid
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+}
+
+#[actix_web::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    let settings = Settings::with_env_and_config_file(args.flag_config.as_deref())?;
+    init_logging(!settings.human_logs).expect("Logging failed to initialize");
+
+    let metrics = metrics_from_opts(
+        &settings.tokenserver.statsd_label,
+        settings.statsd_host.as_deref(),
+        settings.statsd_port,
+    )?;
+    let metrics = Metrics::from(&metrics);
+    let pool =
+        TokenserverPool::new(&settings.tokenserver, &metrics, false).map_err(ApiError::from)?;
+    let service_id = pool
+        .get_sync()
+        .and_then(|db| {
+            db.get_service_id_sync(params::GetServiceId {
+                service: "sync-1.5".to_owned(),
+            })
+        })
+        .map_err(ApiError::from)?
+        .id;
+
+    let purger = Purger {
+        pool,
+        service_id,
+        secret: String::from_utf8(settings.master_secret.master_secret.clone())?,
+        request_client: ReqwestClient::builder()
+            .timeout(Duration::from_secs(args.flag_request_timeout))
+            .use_rustls_tls()
+            .build()?,
+        metrics,
+        grace_period: Duration::from_secs(args.flag_grace_period),
+        max_per_loop: args.flag_max_per_loop,
+        max_offset: args.flag_max_offset,
+        max_records: Some(args.flag_max_records).filter(|&max| max > 0),
+        skip_storage_delete: args.flag_skip_storage_delete,
+        dry_run: args.flag_dry_run,
+    };
+
+    loop {
+        if let Err(e) = purger.purge_old_records().await {
+            error!("Error while purging old user records: {}", e);
+
+            if args.flag_oneshot {
+                return Err(e);
+            }
+        }
+
+        if args.flag_oneshot {
+            return Ok(());
+        }
+
+        // Randomize sleep interval +/- thirty percent to desynchronize instances of this binary
+        // running on multiple webheads.
+        let jitter = args.flag_purge_interval * 3 / 10;
+        let sleep_time = rand::thread_rng()
+            .gen_range(args.flag_purge_interval - jitter..=args.flag_purge_interval + jitter);
+        debug!("Sleeping for {} seconds", sleep_time);
+        tokio::time::delay_for(Duration::from_secs(sleep_time)).await;
+    }
+}
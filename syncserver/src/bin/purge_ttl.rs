@@ -8,6 +8,10 @@ use std::time::{Duration, Instant};
 use cadence::{
     BufferedUdpMetricSink, Metric, QueuingMetricSink, StatsdClient, Timed, DEFAULT_PORT,
 };
+use chrono::Utc;
+use diesel::{
+    mysql::MysqlConnection, sql_query, sql_types::BigInt, Connection, QueryableByName, RunQueryDsl,
+};
 use google_cloud_rust_raw::spanner::v1::{
     spanner::{
         BeginTransactionRequest, CommitRequest, CreateSessionRequest, ExecuteSqlRequest, Session,
@@ -20,11 +24,13 @@ use google_cloud_rust_raw::spanner::v1::{
 };
 use grpcio::{CallOption, ChannelBuilder, ChannelCredentials, EnvBuilder, MetadataBuilder};
 use log::{info, trace, warn};
+use syncserver_db_common::BATCH_LIFETIME;
 use url::{Host, Url};
 
 const SPANNER_ADDRESS: &str = "spanner.googleapis.com:443";
 const RETRY_ENV_VAR: &str = "PURGE_TTL_RETRY_COUNT"; // Default value = 10
 const SLEEP_ENV_VAR: &str = "PURGE_TTL_RETRY_SLEEP_MILLIS"; // Default value = 0
+const DRY_RUN_ENV_VAR: &str = "PURGE_TTL_DRY_RUN"; // Default value = false
 
 use protobuf::well_known_types::Value;
 
@@ -243,6 +249,58 @@ fn delete_all(
     Ok(())
 }
 
+#[derive(Debug, QueryableByName)]
+struct CountResult {
+    #[sql_type = "BigInt"]
+    count: i64,
+}
+
+/// Delete the rows of a MySQL table whose `column` is less than `cutoff`
+///
+/// Rows are deleted in chunks of `chunk_size`, each its own (autocommitted)
+/// statement to keep lock times and replication lag down, until no expired
+/// rows remain or `max_to_delete` rows were deleted. A `dry_run` only counts
+/// the rows that would be deleted.
+fn mysql_delete_incremental(
+    conn: &MysqlConnection,
+    table: &str,
+    column: &str,
+    cutoff: i64,
+    chunk_size: u64,
+    max_to_delete: u64,
+    dry_run: bool,
+) -> Result<u64, diesel::result::Error> {
+    if dry_run {
+        let count = sql_query(format!(
+            "SELECT COUNT(*) AS count FROM {} WHERE {} < ?",
+            table, column
+        ))
+        .bind::<BigInt, _>(cutoff)
+        .get_result::<CountResult>(conn)?
+        .count as u64;
+        let count = count.min(max_to_delete);
+        info!("DryRun: {}: would remove {} rows", table, count);
+        return Ok(count);
+    }
+
+    let mut total: u64 = 0;
+    while total < max_to_delete {
+        let limit = chunk_size.min(max_to_delete - total);
+        let delete_sql = format!("DELETE FROM {} WHERE {} < ? LIMIT {}", table, column, limit);
+        trace!("Deleting chunk with: {}", delete_sql);
+        let removed = sql_query(delete_sql)
+            .bind::<BigInt, _>(cutoff)
+            .execute(conn)? as u64;
+        total += removed;
+        info!("{}: removed {} rows", table, total);
+        if removed < limit {
+            break;
+        }
+    }
+    trace!("Delete: {}: done", table);
+    Ok(total)
+}
+
 fn retryable(err: &grpcio::Error) -> bool {
     // if it is NOT an ABORT, we should not retry this function.
     match err {
@@ -260,39 +318,34 @@ fn retryable(err: &grpcio::Error) -> bool {
     }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::try_init()?;
-
-    let chunk_size: u64 = env::var("PURGE_TTL_CHUNK_SIZE")
-        .unwrap_or_else(|_| "1000".to_string())
-        .parse()
-        .unwrap();
-    let max_to_delete: u64 = env::var("PURGE_TTL_MAX_TO_DELETE")
-        .unwrap_or_else(|_| "1000".to_string())
-        .parse()
-        .unwrap();
-
-    const INCREMENTAL_ENV: &str = "PURGE_TTL_INCREMENTAL";
-    let incremental = env::var(INCREMENTAL_ENV)
-        .map(|x| x == "1" || x.to_lowercase() == "true")
-        .unwrap_or(false);
-    info!("INCREMENTAL: {:?}", incremental);
+/// Options shared by the Spanner and MySQL purges
+struct PurgeOptions {
+    chunk_size: u64,
+    max_to_delete: u64,
+    incremental: bool,
+    dry_run: bool,
+    retries: u64,
+    nap_time: Duration,
+}
 
-    const DB_ENV: &str = "SYNC_SYNCSTORAGE__DATABASE_URL";
-    let db_url = env::var(DB_ENV).map_err(|_| format!("Invalid or undefined {}", DB_ENV))?;
-    let url = Url::parse(&db_url).map_err(|e| format!("Invalid {}: {}", DB_ENV, e))?;
-    if url.scheme() != "spanner" || url.host() != Some(Host::Domain("projects")) {
-        return Err(format!("Invalid {}", DB_ENV).into());
+fn purge_spanner(
+    db_url: &str,
+    opts: &PurgeOptions,
+    statsd: &StatsdClient,
+) -> Result<(), Box<dyn Error>> {
+    let PurgeOptions {
+        chunk_size,
+        max_to_delete,
+        incremental,
+        retries,
+        nap_time,
+        ..
+    } = *opts;
+    if opts.dry_run {
+        return Err(format!("{} is only supported for MySQL", DRY_RUN_ENV_VAR).into());
     }
-    let retries: u64 =
-        str::parse::<u64>(&env::var(RETRY_ENV_VAR).unwrap_or_else(|_| "10".to_owned()))
-            .unwrap_or(10);
-    let nap_time: Duration = Duration::from_millis(
-        str::parse::<u64>(&env::var(SLEEP_ENV_VAR).unwrap_or_else(|_| "0".to_owned())).unwrap_or(0),
-    );
 
     let database = db_url["spanner://".len()..].to_owned();
-    info!("Retries: {}, sleep: {}ms", retries, nap_time.as_millis());
     info!("For {}", database);
 
     // Set up the gRPC environment.
@@ -315,12 +368,10 @@ fn main() -> Result<(), Box<dyn Error>> {
     let opt = CallOption::default().headers(meta.build());
     let session = client.create_session_opt(&req, opt)?;
 
-    let statsd = statsd_from_env()?;
-
     {
-        let _timer_total = start_timer(&statsd, "purge_ttl.total_duration");
+        let _timer_total = start_timer(statsd, "purge_ttl.total_duration");
         {
-            let _timer_batches = start_timer(&statsd, "purge_ttl.batches_duration");
+            let _timer_batches = start_timer(statsd, "purge_ttl.batches_duration");
             let mut success = false;
             for i in 0..retries {
                 match if incremental {
@@ -358,7 +409,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             }
         }
         {
-            let _timer_bso = start_timer(&statsd, "purge_ttl.bso_duration");
+            let _timer_bso = start_timer(statsd, "purge_ttl.bso_duration");
             let mut success = false;
             for i in 0..retries {
                 match if incremental {
@@ -394,3 +445,212 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+/// Purge a MySQL storage node
+///
+/// The MySQL schema has no expiry for batches: their ids are their creation
+/// timestamps (in milliseconds), so batches older than `BATCH_LIFETIME` are
+/// purged. MySQL always purges incrementally.
+fn purge_mysql(
+    db_url: &str,
+    opts: &PurgeOptions,
+    statsd: &StatsdClient,
+) -> Result<(), Box<dyn Error>> {
+    let conn = MysqlConnection::establish(db_url)?;
+    let now = Utc::now().timestamp_millis();
+
+    let purge = |table: &str, column: &str, cutoff: i64| -> Result<u64, Box<dyn Error>> {
+        let mut i = 0;
+        loop {
+            match mysql_delete_incremental(
+                &conn,
+                table,
+                column,
+                cutoff,
+                opts.chunk_size,
+                opts.max_to_delete,
+                opts.dry_run,
+            ) {
+                Ok(total) => return Ok(total),
+                Err(e) => {
+                    warn!("{} transaction error {}: {:?}", table, i, e);
+                    i += 1;
+                    if i >= opts.retries {
+                        return Err(format!(
+                            "Could not delete expired {} after {} attempts",
+                            table, opts.retries
+                        )
+                        .into());
+                    }
+                    if opts.nap_time.as_millis() > 0 {
+                        thread::sleep(opts.nap_time);
+                    }
+                }
+            }
+        }
+    };
+
+    {
+        let _timer_total = start_timer(statsd, "purge_ttl.total_duration");
+        {
+            let _timer_batches = start_timer(statsd, "purge_ttl.batches_duration");
+            let cutoff = now - BATCH_LIFETIME;
+            purge("batch_upload_items", "batch", cutoff)?;
+            purge("batch_uploads", "batch", cutoff)?;
+        }
+        {
+            let _timer_bso = start_timer(statsd, "purge_ttl.bso_duration");
+            purge("bso", "ttl", now)?;
+        }
+        info!("Completed purge_ttl");
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::try_init()?;
+
+    let chunk_size: u64 = env::var("PURGE_TTL_CHUNK_SIZE")
+        .unwrap_or_else(|_| "1000".to_string())
+        .parse()
+        .unwrap();
+    let max_to_delete: u64 = env::var("PURGE_TTL_MAX_TO_DELETE")
+        .unwrap_or_else(|_| "1000".to_string())
+        .parse()
+        .unwrap();
+
+    const INCREMENTAL_ENV: &str = "PURGE_TTL_INCREMENTAL";
+    let incremental = env::var(INCREMENTAL_ENV)
+        .map(|x| x == "1" || x.to_lowercase() == "true")
+        .unwrap_or(false);
+    info!("INCREMENTAL: {:?}", incremental);
+    let dry_run = env::var(DRY_RUN_ENV_VAR)
+        .map(|x| x == "1" || x.to_lowercase() == "true")
+        .unwrap_or(false);
+    info!("DRY_RUN: {:?}", dry_run);
+
+    const DB_ENV: &str = "SYNC_SYNCSTORAGE__DATABASE_URL";
+    let db_url = env::var(DB_ENV).map_err(|_| format!("Invalid or undefined {}", DB_ENV))?;
+    let url = Url::parse(&db_url).map_err(|e| format!("Invalid {}: {}", DB_ENV, e))?;
+    let retries: u64 =
+        str::parse::<u64>(&env::var(RETRY_ENV_VAR).unwrap_or_else(|_| "10".to_owned()))
+            .unwrap_or(10);
+    let nap_time: Duration = Duration::from_millis(
+        str::parse::<u64>(&env::var(SLEEP_ENV_VAR).unwrap_or_else(|_| "0".to_owned())).unwrap_or(0),
+    );
+    info!("Retries: {}, sleep: {}ms", retries, nap_time.as_millis());
+
+    let opts = PurgeOptions {
+        chunk_size,
+        max_to_delete,
+        incremental,
+        dry_run,
+        retries,
+        nap_time,
+    };
+    let statsd = statsd_from_env()?;
+
+    match url.scheme() {
+        "spanner" if url.host() == Some(Host::Domain("projects")) => {
+            purge_spanner(&db_url, &opts, &statsd)
+        }
+        "mysql" => purge_mysql(&db_url, &opts, &statsd),
+        _ => Err(format!("Invalid {}", DB_ENV).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CUTOFF: i64 = 100;
+
+    /// Connect to the test MySQL db, creating a temporary `purge_test` table
+    /// with a row per ttl. None when not testing against MySQL.
+    fn purge_test_conn(ttls: &[i64]) -> Option<MysqlConnection> {
+        let db_url = env::var("SYNC_SYNCSTORAGE__DATABASE_URL").ok()?;
+        if Url::parse(&db_url).ok()?.scheme() != "mysql" {
+            return None;
+        }
+        let conn = MysqlConnection::establish(&db_url).unwrap();
+        sql_query(
+            "CREATE TEMPORARY TABLE purge_test (
+                 id INT AUTO_INCREMENT PRIMARY KEY,
+                 ttl BIGINT NOT NULL
+             )",
+        )
+        .execute(&conn)
+        .unwrap();
+        for ttl in ttls {
+            sql_query("INSERT INTO purge_test (ttl) VALUES (?)")
+                .bind::<BigInt, _>(*ttl)
+                .execute(&conn)
+                .unwrap();
+        }
+        Some(conn)
+    }
+
+    fn remaining(conn: &MysqlConnection) -> i64 {
+        sql_query("SELECT COUNT(*) AS count FROM purge_test")
+            .get_result::<CountResult>(conn)
+            .unwrap()
+            .count
+    }
+
+    /// 7 expired rows and 2 live ones
+    const TTLS: &[i64] = &[1, 2, 3, 4, 5, 6, 7, 200, 300];
+
+    #[test]
+    fn mysql_delete_chunks() {
+        let conn = match purge_test_conn(TTLS) {
+            Some(conn) => conn,
+            // Skip this test if we're not using mysql
+            None => return,
+        };
+        let total =
+            mysql_delete_incremental(&conn, "purge_test", "ttl", CUTOFF, 3, 1000, false).unwrap();
+        assert_eq!(total, 7);
+        assert_eq!(remaining(&conn), 2);
+    }
+
+    #[test]
+    fn mysql_delete_exact_chunks() {
+        let conn = match purge_test_conn(&TTLS[1..]) {
+            Some(conn) => conn,
+            None => return,
+        };
+        // The last full chunk's followed by an empty one
+        let total =
+            mysql_delete_incremental(&conn, "purge_test", "ttl", CUTOFF, 3, 1000, false).unwrap();
+        assert_eq!(total, 6);
+        assert_eq!(remaining(&conn), 2);
+    }
+
+    #[test]
+    fn mysql_delete_max_to_delete() {
+        let conn = match purge_test_conn(TTLS) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let total =
+            mysql_delete_incremental(&conn, "purge_test", "ttl", CUTOFF, 3, 5, false).unwrap();
+        assert_eq!(total, 5);
+        assert_eq!(remaining(&conn), 4);
+    }
+
+    #[test]
+    fn mysql_delete_dry_run() {
+        let conn = match purge_test_conn(TTLS) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let total =
+            mysql_delete_incremental(&conn, "purge_test", "ttl", CUTOFF, 3, 1000, true).unwrap();
+        assert_eq!(total, 7);
+        let total =
+            mysql_delete_incremental(&conn, "purge_test", "ttl", CUTOFF, 3, 5, true).unwrap();
+        assert_eq!(total, 5);
+        assert_eq!(remaining(&conn), TTLS.len() as i64);
+    }
+}
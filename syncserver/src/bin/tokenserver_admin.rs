@@ -0,0 +1,193 @@
+//! Administer the storage nodes known to Tokenserver.
+//!
+//! This binary adds, inspects, updates, unassigns and removes the nodes to which Tokenserver
+//! allocates users. Every command prints its result to stdout as a single line of JSON, so the
+//! output can be consumed by other tooling.
+use std::error::Error;
+
+use docopt::Docopt;
+use serde::Deserialize;
+use serde_json::json;
+
+use syncserver::{
+    error::ApiError,
+    server::metrics::Metrics,
+    tokenserver::db::{
+        models::{Db, DEFAULT_CAPACITY_RELEASE_RATE},
+        params,
+        pool::{DbPool, TokenserverPool},
+        results,
+    },
+};
+use syncserver_settings::Settings;
+
+const USAGE: &str = "
+Usage:
+    tokenserver-admin add-node [options] <node> <capacity>
+    tokenserver-admin get-node [options] <node>
+    tokenserver-admin update-node [options] <node>
+    tokenserver-admin unassign-node [options] <node>
+    tokenserver-admin remove-node [options] <node>
+    tokenserver-admin (-h | --help)
+
+Commands:
+    add-node         Add a node with the given capacity.
+    get-node         Show the details of a node.
+    update-node      Update the details of a node. Fields that are not given are left unchanged.
+    unassign-node    Clear any user assignments to a node.
+    remove-node      Remove a node and clear any user assignments to it.
+
+Options:
+    -h, --help             Show this message.
+    --config=CONFIGFILE    Syncserver configuration file path.
+    --capacity=N           How many user slots the node has overall.
+    --available=N          How many user slots the node has available. When adding a node, this
+                           defaults to a fraction of its capacity.
+    --current-load=N       How many user slots the node has occupied.
+    --downed=N             Mark the node as down (1) or up (0).
+    --backoff=N            Mark the node as backed-off (1) or not (0).
+";
+
+/// The name of the service whose nodes are administered.
+const SERVICE_NAME: &str = "sync-1.5";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    cmd_add_node: bool,
+    cmd_get_node: bool,
+    cmd_update_node: bool,
+    cmd_unassign_node: bool,
+    cmd_remove_node: bool,
+    arg_node: String,
+    arg_capacity: Option<i32>,
+    flag_config: Option<String>,
+    flag_capacity: Option<i32>,
+    flag_available: Option<i32>,
+    flag_current_load: Option<i32>,
+    flag_downed: Option<i32>,
+    flag_backoff: Option<i32>,
+}
+
+/// Looks up a node by name, returning its full record.
+async fn get_node(db: &dyn Db, service_id: i32, node: &str) -> Result<results::GetNode, ApiError> {
+    let id = db
+        .get_node_id(params::GetNodeId {
+            service_id,
+            node: node.to_owned(),
+        })
+        .await?
+        .id;
+
+    Ok(db.get_node(params::GetNode { id }).await?)
+}
+
+async fn add_node(
+    db: &dyn Db,
+    service_id: i32,
+    args: &Args,
+    capacity_release_rate: f32,
+) -> Result<serde_json::Value, ApiError> {
+    let capacity = args.arg_capacity.unwrap_or_default();
+    // Only a fraction of the node's capacity is released to start
+    let available = args
+        .flag_available
+        .unwrap_or_else(|| (capacity as f32 * capacity_release_rate).ceil() as i32);
+
+    db.post_node(params::PostNode {
+        service_id,
+        node: args.arg_node.clone(),
+        available,
+        current_load: args.flag_current_load.unwrap_or_default(),
+        capacity,
+        downed: args.flag_downed.unwrap_or_default(),
+        backoff: args.flag_backoff.unwrap_or_default(),
+    })
+    .await?;
+
+    Ok(json!(get_node(db, service_id, &args.arg_node).await?))
+}
+
+async fn update_node(
+    db: &dyn Db,
+    service_id: i32,
+    args: &Args,
+) -> Result<serde_json::Value, ApiError> {
+    // Make sure the node exists before attempting to update it
+    get_node(db, service_id, &args.arg_node).await?;
+
+    db.update_node(params::UpdateNode {
+        service_id,
+        node: args.arg_node.clone(),
+        available: args.flag_available,
+        current_load: args.flag_current_load,
+        capacity: args.flag_capacity,
+        downed: args.flag_downed,
+        backoff: args.flag_backoff,
+    })
+    .await?;
+
+    Ok(json!(get_node(db, service_id, &args.arg_node).await?))
+}
+
+async fn unassign_node(
+    db: &dyn Db,
+    service_id: i32,
+    node: &str,
+) -> Result<serde_json::Value, ApiError> {
+    let node_id = get_node(db, service_id, node).await?.id;
+    db.unassign_node(params::UnassignNode { node_id }).await?;
+
+    Ok(json!({ "id": node_id, "node": node, "unassigned": true }))
+}
+
+async fn remove_node(
+    db: &dyn Db,
+    service_id: i32,
+    node: &str,
+) -> Result<serde_json::Value, ApiError> {
+    let node_id = get_node(db, service_id, node).await?.id;
+    db.remove_node(params::RemoveNode { node_id }).await?;
+    db.unassign_node(params::UnassignNode { node_id }).await?;
+
+    Ok(json!({ "id": node_id, "node": node, "removed": true }))
+}
+
+#[actix_web::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    let settings = Settings::with_env_and_config_file(args.flag_config.as_deref())?;
+
+    let pool = TokenserverPool::new(&settings.tokenserver, &Metrics::noop(), false)
+        .map_err(ApiError::from)?;
+    let db = pool.get().await.map_err(ApiError::from)?;
+    let service_id = db
+        .get_service_id(params::GetServiceId {
+            service: SERVICE_NAME.to_owned(),
+        })
+        .await
+        .map_err(ApiError::from)?
+        .id;
+
+    let output = if args.cmd_add_node {
+        let capacity_release_rate = settings
+            .tokenserver
+            .node_capacity_release_rate
+            .unwrap_or(DEFAULT_CAPACITY_RELEASE_RATE);
+        add_node(&*db, service_id, &args, capacity_release_rate).await?
+    } else if args.cmd_get_node {
+        json!(get_node(&*db, service_id, &args.arg_node).await?)
+    } else if args.cmd_update_node {
+        update_node(&*db, service_id, &args).await?
+    } else if args.cmd_unassign_node {
+        unassign_node(&*db, service_id, &args.arg_node).await?
+    } else if args.cmd_remove_node {
+        remove_node(&*db, service_id, &args.arg_node).await?
+    } else {
+        unreachable!("docopt only accepts the commands listed in the usage")
+    };
+
+    println!("{}", output);
+    Ok(())
+}
@@ -102,6 +102,11 @@ impl<'a> Db<'a> for MockDb {
     mock_db_method!(validate_batch, ValidateBatch);
     mock_db_method!(append_to_batch, AppendToBatch);
     mock_db_method!(get_batch, GetBatch, Option<results::GetBatch>);
+    mock_db_method!(
+        get_batch_status,
+        GetBatchStatus,
+        Option<results::GetBatchStatus>
+    );
     mock_db_method!(commit_batch, CommitBatch);
 
     fn get_connection_info(&self) -> results::ConnectionInfo {
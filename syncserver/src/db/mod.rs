@@ -1,16 +1,21 @@
 //! Generic db abstration.
 
+mod diesel_common;
 pub mod mock;
 pub mod mysql;
+pub mod postgres;
 pub mod spanner;
+pub mod sqlite;
 #[cfg(test)]
 mod tests;
+pub mod traced;
 pub mod transaction;
 
 use std::time::Duration;
 
 use actix_web::{error::BlockingError, web};
 use cadence::{Gauged, StatsdClient};
+use futures::future::{abortable, AbortHandle};
 use syncserver_db_common::{
     error::{DbError, DbErrorKind},
     results, DbPool, GetPoolState, PoolState,
@@ -30,22 +35,25 @@ pub async fn pool_from_settings(
         Url::parse(&settings.database_url).map_err(|e| DbErrorKind::InvalidUrl(e.to_string()))?;
     Ok(match url.scheme() {
         "mysql" => Box::new(mysql::pool::MysqlDbPool::new(settings, metrics)?),
+        "postgres" | "postgresql" => Box::new(postgres::pool::PgDbPool::new(settings, metrics)?),
         "spanner" => Box::new(spanner::pool::SpannerDbPool::new(settings, metrics).await?),
+        "sqlite" => Box::new(sqlite::pool::SqliteDbPool::new(settings, metrics)?),
         _ => Err(DbErrorKind::InvalidUrl(settings.database_url.to_owned()))?,
     })
 }
 
-/// Emit DbPool metrics periodically
+/// Emit DbPool metrics periodically, until aborted via the returned handle
+/// (dropping its clone of the pool)
 pub fn spawn_pool_periodic_reporter<T: GetPoolState + Send + 'static>(
     interval: Duration,
     metrics: StatsdClient,
     pool: T,
-) -> Result<(), DbError> {
+) -> Result<AbortHandle, DbError> {
     let hostname = hostname::get()
         .expect("Couldn't get hostname")
         .into_string()
         .expect("Couldn't get hostname");
-    tokio::spawn(async move {
+    let (reporter, abort_handle) = abortable(async move {
         loop {
             let PoolState {
                 connections,
@@ -65,8 +73,9 @@ pub fn spawn_pool_periodic_reporter<T: GetPoolState + Send + 'static>(
             time::delay_for(interval).await;
         }
     });
+    tokio::spawn(reporter);
 
-    Ok(())
+    Ok(abort_handle)
 }
 
 pub async fn run_on_blocking_threadpool<F, T>(f: F) -> Result<T, DbError>
@@ -11,7 +11,9 @@ use diesel::{
 };
 use syncserver_db_common::{
     error::{DbError, DbErrorKind},
-    params, results, UserIdentifier, BATCH_LIFETIME,
+    params, results,
+    util::SyncTimestamp,
+    UserIdentifier, BATCH_LIFETIME,
 };
 
 use super::{
@@ -116,6 +118,33 @@ pub fn get(db: &MysqlDb, params: params::GetBatch) -> Result<Option<results::Get
     Ok(batch)
 }
 
+pub fn status(
+    db: &MysqlDb,
+    params: params::GetBatchStatus,
+) -> Result<Option<results::GetBatchStatus>> {
+    let is_valid = validate(
+        db,
+        params::ValidateBatch {
+            user_id: params.user_id.clone(),
+            collection: params.collection,
+            id: params.id.clone(),
+        },
+    )?;
+    if !is_valid {
+        return Ok(None);
+    }
+
+    let batch_id = decode_id(&params.id)?;
+    let (count, total_bytes) = db.batch_totals(batch_id, params.user_id.legacy_id as i64)?;
+    Ok(Some(results::GetBatchStatus {
+        id: params.id,
+        count: count as u64,
+        total_bytes: total_bytes as u64,
+        // Recall that the batchid is a millisecond timestamp
+        expiry: SyncTimestamp::from_i64(batch_id + BATCH_LIFETIME)?,
+    }))
+}
+
 pub fn delete(db: &MysqlDb, params: params::DeleteBatch) -> Result<()> {
     let batch_id = decode_id(&params.id)?;
     let user_id = params.user_id.legacy_id as i64;
@@ -225,7 +254,8 @@ pub fn do_append(
             diesel::update(
                 batch_upload_items::table
                     .filter(batch_upload_items::user_id.eq(user_id.legacy_id as i64))
-                    .filter(batch_upload_items::batch_id.eq(batch_id)),
+                    .filter(batch_upload_items::batch_id.eq(batch_id))
+                    .filter(batch_upload_items::id.eq(&bso.id)),
             )
             .set(&UpdateBatches {
                 payload: bso.payload,
@@ -250,7 +280,7 @@ pub fn do_append(
         }
     }
 
-    Ok(())
+    db.check_batch_totals(batch_id, user_id.legacy_id as i64)
 }
 
 pub fn validate_batch_id(id: &str) -> Result<()> {
@@ -268,12 +298,3 @@ fn decode_id(id: &str) -> Result<i64> {
         .parse::<i64>()
         .map_err(|e| DbError::internal(&format!("Invalid batch_id: {}", e)))
 }
-
-#[macro_export]
-macro_rules! batch_db_method {
-    ($name:ident, $batch_name:ident, $type:ident) => {
-        pub fn $name(&self, params: params::$type) -> Result<results::$type> {
-            batch::$batch_name(self, params)
-        }
-    };
-}
@@ -1,4 +1,3 @@
-#[macro_use]
 mod batch;
 mod diesel_ext;
 pub mod models;
@@ -1,11 +1,6 @@
 use async_trait::async_trait;
 
-use std::{
-    collections::HashMap,
-    fmt,
-    sync::{Arc, RwLock},
-    time::Duration,
-};
+use std::{fmt, sync::Arc, time::Duration};
 
 use diesel::{
     mysql::MysqlConnection,
@@ -14,13 +9,13 @@ use diesel::{
 };
 #[cfg(test)]
 use diesel_logger::LoggingConnection;
-use syncserver_db_common::{error::DbError, Db, DbPool, GetPoolState, PoolState, STD_COLLS};
-use syncstorage_settings::{Quota, Settings};
+use syncserver_db_common::{Db, DbPool, GetPoolState, PoolState};
+use syncstorage_settings::{Quota, ServerLimits, Settings};
 
 use super::models::{MysqlDb, Result};
 #[cfg(test)]
 use super::test::TestTransactionCustomizer;
-use crate::db;
+use crate::db::{self, diesel_common::CollectionCache};
 use crate::server::metrics::Metrics;
 
 embed_migrations!();
@@ -50,6 +45,7 @@ pub struct MysqlDbPool {
 
     metrics: Metrics,
     quota: Quota,
+    limits: Arc<ServerLimits>,
 }
 
 impl MysqlDbPool {
@@ -86,6 +82,7 @@ impl MysqlDbPool {
                 enabled: settings.enable_quota,
                 enforced: settings.enforce_quota,
             },
+            limits: Arc::new(settings.limits.clone()),
         })
     }
 
@@ -95,6 +92,7 @@ impl MysqlDbPool {
             Arc::clone(&self.coll_cache),
             &self.metrics,
             &self.quota,
+            Arc::clone(&self.limits),
         ))
     }
 }
@@ -130,68 +128,3 @@ impl fmt::Debug for MysqlDbPool {
             .finish()
     }
 }
-
-#[derive(Debug)]
-pub struct CollectionCache {
-    pub by_name: RwLock<HashMap<String, i32>>,
-    pub by_id: RwLock<HashMap<i32, String>>,
-}
-
-impl CollectionCache {
-    pub fn put(&self, id: i32, name: String) -> Result<()> {
-        // XXX: should this emit a metric?
-        // XXX: should probably either lock both simultaneously during
-        // writes or use an RwLock alternative
-        self.by_name
-            .write()
-            .map_err(|_| DbError::internal("by_name write"))?
-            .insert(name.clone(), id);
-        self.by_id
-            .write()
-            .map_err(|_| DbError::internal("by_id write"))?
-            .insert(id, name);
-        Ok(())
-    }
-
-    pub fn get_id(&self, name: &str) -> Result<Option<i32>> {
-        Ok(self
-            .by_name
-            .read()
-            .map_err(|_| DbError::internal("by_name read"))?
-            .get(name)
-            .cloned())
-    }
-
-    pub fn get_name(&self, id: i32) -> Result<Option<String>> {
-        Ok(self
-            .by_id
-            .read()
-            .map_err(|_| DbError::internal("by_id read"))?
-            .get(&id)
-            .cloned())
-    }
-
-    pub fn clear(&self) {
-        self.by_name.write().expect("by_name write").clear();
-        self.by_id.write().expect("by_id write").clear();
-    }
-}
-
-impl Default for CollectionCache {
-    fn default() -> Self {
-        Self {
-            by_name: RwLock::new(
-                STD_COLLS
-                    .iter()
-                    .map(|(k, v)| ((*v).to_owned(), *k))
-                    .collect(),
-            ),
-            by_id: RwLock::new(
-                STD_COLLS
-                    .iter()
-                    .map(|(k, v)| (*k, (*v).to_owned()))
-                    .collect(),
-            ),
-        }
-    }
-}
@@ -0,0 +1,238 @@
+use diesel::{
+    self,
+    dsl::sql,
+    insert_into,
+    result::{DatabaseErrorKind::UniqueViolation, Error as DieselError},
+    sql_query,
+    sql_types::{BigInt, Integer, Nullable, Text},
+    ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl,
+};
+use syncserver_db_common::{
+    error::{DbError, DbErrorKind},
+    params, results,
+    util::SyncTimestamp,
+    UserIdentifier, BATCH_LIFETIME,
+};
+
+use super::{
+    models::{PgDb, Result},
+    schema::{batch_upload_items, batch_uploads},
+};
+
+const MAXTTL: i32 = 2_100_000_000;
+
+pub fn create(db: &PgDb, params: params::CreateBatch) -> Result<results::CreateBatch> {
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    // Mix in the lowest digit of the uid, matching the MySQL backend's batch
+    // ids (see mysql::batch::create)
+    let batch_id = db.timestamp().as_i64() + (user_id % 10);
+    insert_into(batch_uploads::table)
+        .values((
+            batch_uploads::batch_id.eq(&batch_id),
+            batch_uploads::user_id.eq(&user_id),
+            batch_uploads::collection_id.eq(&collection_id),
+        ))
+        .execute(&db.conn)
+        .map_err(|e| -> DbError {
+            match e {
+                // The user tried to create two batches with the same timestamp
+                DieselError::DatabaseError(UniqueViolation, _) => DbErrorKind::Conflict.into(),
+                _ => e.into(),
+            }
+        })?;
+
+    do_append(db, batch_id, params.user_id, collection_id, params.bsos)?;
+    Ok(results::CreateBatch {
+        id: encode_id(batch_id),
+        size: None,
+    })
+}
+
+pub fn validate(db: &PgDb, params: params::ValidateBatch) -> Result<bool> {
+    let batch_id = decode_id(&params.id)?;
+    // Avoid hitting the db for batches that are obviously too old.  Recall
+    // that the batchid is a millisecond timestamp.
+    if (batch_id + BATCH_LIFETIME) < db.timestamp().as_i64() {
+        return Ok(false);
+    }
+
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    let exists = batch_uploads::table
+        .select(sql::<Integer>("1"))
+        .filter(batch_uploads::batch_id.eq(&batch_id))
+        .filter(batch_uploads::user_id.eq(&user_id))
+        .filter(batch_uploads::collection_id.eq(&collection_id))
+        .get_result::<i32>(&db.conn)
+        .optional()?;
+    Ok(exists.is_some())
+}
+
+pub fn append(db: &PgDb, params: params::AppendToBatch) -> Result<()> {
+    let exists = validate(
+        db,
+        params::ValidateBatch {
+            user_id: params.user_id.clone(),
+            collection: params.collection.clone(),
+            id: params.batch.id.clone(),
+        },
+    )?;
+
+    if !exists {
+        Err(DbErrorKind::BatchNotFound)?
+    }
+
+    let batch_id = decode_id(&params.batch.id)?;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    do_append(db, batch_id, params.user_id, collection_id, params.bsos)?;
+    Ok(())
+}
+
+pub fn get(db: &PgDb, params: params::GetBatch) -> Result<Option<results::GetBatch>> {
+    let is_valid = validate(
+        db,
+        params::ValidateBatch {
+            user_id: params.user_id,
+            collection: params.collection,
+            id: params.id.clone(),
+        },
+    )?;
+    let batch = if is_valid {
+        Some(results::GetBatch { id: params.id })
+    } else {
+        None
+    };
+    Ok(batch)
+}
+
+pub fn status(
+    db: &PgDb,
+    params: params::GetBatchStatus,
+) -> Result<Option<results::GetBatchStatus>> {
+    let is_valid = validate(
+        db,
+        params::ValidateBatch {
+            user_id: params.user_id.clone(),
+            collection: params.collection,
+            id: params.id.clone(),
+        },
+    )?;
+    if !is_valid {
+        return Ok(None);
+    }
+
+    let batch_id = decode_id(&params.id)?;
+    let (count, total_bytes) = db.batch_totals(batch_id, params.user_id.legacy_id as i64)?;
+    Ok(Some(results::GetBatchStatus {
+        id: params.id,
+        count: count as u64,
+        total_bytes: total_bytes as u64,
+        // Recall that the batchid is a millisecond timestamp
+        expiry: SyncTimestamp::from_i64(batch_id + BATCH_LIFETIME)?,
+    }))
+}
+
+pub fn delete(db: &PgDb, params: params::DeleteBatch) -> Result<()> {
+    let batch_id = decode_id(&params.id)?;
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    diesel::delete(batch_uploads::table)
+        .filter(batch_uploads::batch_id.eq(&batch_id))
+        .filter(batch_uploads::user_id.eq(&user_id))
+        .filter(batch_uploads::collection_id.eq(&collection_id))
+        .execute(&db.conn)?;
+    diesel::delete(batch_upload_items::table)
+        .filter(batch_upload_items::batch_id.eq(&batch_id))
+        .filter(batch_upload_items::user_id.eq(&user_id))
+        .execute(&db.conn)?;
+    Ok(())
+}
+
+/// Commits a batch to the bsos table, deleting the batch when succesful
+///
+/// PostgreSQL checks the NOT NULL constraints of an upsert's proposed row
+/// before resolving the conflict, so it can't fall back to the existing
+/// row's values for columns the batch left NULL: existing bsos are updated
+/// and then the new ones inserted.
+pub fn commit(db: &PgDb, params: params::CommitBatch) -> Result<results::CommitBatch> {
+    let batch_id = decode_id(&params.batch.id)?;
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    let timestamp = db.timestamp();
+    sql_query(include_str!("batch_commit_update.sql"))
+        .bind::<BigInt, _>(&timestamp.as_i64())
+        .bind::<BigInt, _>(&batch_id)
+        .bind::<BigInt, _>(user_id)
+        .bind::<Integer, _>(&collection_id)
+        .execute(&db.conn)?;
+    sql_query(include_str!("batch_commit_insert.sql"))
+        .bind::<BigInt, _>(user_id)
+        .bind::<Integer, _>(&collection_id)
+        .bind::<BigInt, _>(&timestamp.as_i64())
+        .bind::<BigInt, _>((MAXTTL as i64) * 1000) // XXX:
+        .bind::<BigInt, _>(&batch_id)
+        .execute(&db.conn)?;
+
+    db.update_collection(user_id as u32, collection_id)?;
+
+    delete(
+        db,
+        params::DeleteBatch {
+            user_id: params.user_id,
+            collection: params.collection,
+            id: params.batch.id,
+        },
+    )?;
+    Ok(timestamp)
+}
+
+pub fn do_append(
+    db: &PgDb,
+    batch_id: i64,
+    user_id: UserIdentifier,
+    _collection_id: i32,
+    bsos: Vec<params::PostCollectionBso>,
+) -> Result<()> {
+    // Items appended twice (whether within `bsos` or across appends) keep
+    // the prior values of any fields omitted from the later append
+    for bso in bsos {
+        let payload_size = bso.payload.as_ref().map(|p| p.len() as i64);
+        sql_query(
+            "INSERT INTO batch_upload_items
+                    (batch, userid, id, sortindex, payload, payload_size, ttl_offset)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)
+                 ON CONFLICT (batch, userid, id) DO UPDATE SET
+                    sortindex = COALESCE(EXCLUDED.sortindex, batch_upload_items.sortindex),
+                    payload = COALESCE(EXCLUDED.payload, batch_upload_items.payload),
+                    payload_size = COALESCE(EXCLUDED.payload_size, batch_upload_items.payload_size),
+                    ttl_offset = COALESCE(EXCLUDED.ttl_offset, batch_upload_items.ttl_offset)",
+        )
+        .bind::<BigInt, _>(&batch_id)
+        .bind::<BigInt, _>(user_id.legacy_id as i64)
+        .bind::<Text, _>(&bso.id)
+        .bind::<Nullable<Integer>, _>(bso.sortindex)
+        .bind::<Nullable<Text>, _>(bso.payload)
+        .bind::<Nullable<BigInt>, _>(payload_size)
+        .bind::<Nullable<Integer>, _>(bso.ttl.map(|ttl| ttl as i32))
+        .execute(&db.conn)?;
+    }
+
+    db.check_batch_totals(batch_id, user_id.legacy_id as i64)
+}
+
+pub fn validate_batch_id(id: &str) -> Result<()> {
+    decode_id(id).map(|_| ())
+}
+
+fn encode_id(id: i64) -> String {
+    base64::encode(&id.to_string())
+}
+
+fn decode_id(id: &str) -> Result<i64> {
+    let bytes = base64::decode(id).unwrap_or_else(|_| id.as_bytes().to_vec());
+    let decoded = std::str::from_utf8(&bytes).unwrap_or(id);
+    decoded
+        .parse::<i64>()
+        .map_err(|e| DbError::internal(&format!("Invalid batch_id: {}", e)))
+}
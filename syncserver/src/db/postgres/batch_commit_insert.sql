@@ -0,0 +1,14 @@
+INSERT INTO bso (userid, collection, id, modified, sortindex, ttl, payload, payload_size)
+SELECT
+       $1,
+       $2,
+       id,
+       $3,
+       sortindex,
+       COALESCE((ttl_offset::BIGINT * 1000) + $3, $4),
+       COALESCE(payload, ''),
+       COALESCE(payload_size, 0)
+  FROM batch_upload_items
+ WHERE batch = $5
+   AND userid = $1
+    ON CONFLICT (userid, collection, id) DO NOTHING
@@ -0,0 +1,12 @@
+UPDATE bso
+   SET modified = $1,
+       sortindex = COALESCE(b.sortindex, bso.sortindex),
+       ttl = COALESCE((b.ttl_offset::BIGINT * 1000) + $1, bso.ttl),
+       payload = COALESCE(b.payload, bso.payload),
+       payload_size = COALESCE(b.payload_size, bso.payload_size)
+  FROM batch_upload_items b
+ WHERE b.batch = $2
+   AND b.userid = $3
+   AND bso.userid = $3
+   AND bso.collection = $4
+   AND bso.id = b.id
@@ -0,0 +1,77 @@
+-- PostgreSQL mirror of the MySQL schema (as of 2020-08-24-091401_add_quota)
+-- using the same legacy column names.
+
+CREATE TABLE IF NOT EXISTS bso(
+    userid BIGINT                           NOT NULL,
+    collection INTEGER                      NOT NULL,
+    id VARCHAR(64)                          NOT NULL,
+
+    sortindex INTEGER,
+
+    payload TEXT                            NOT NULL,
+    -- not used, but legacy
+    payload_size BIGINT DEFAULT 0           NOT NULL,
+
+    -- last modified time in milliseconds since epoch
+    modified BIGINT                         NOT NULL,
+    -- expiration in milliseconds since epoch
+    ttl BIGINT DEFAULT 3153600000000        NOT NULL,
+
+    PRIMARY KEY (userid, collection, id)
+);
+CREATE INDEX IF NOT EXISTS bso_ttl_idx ON bso (ttl);
+CREATE INDEX IF NOT EXISTS bso_usr_col_mod_idx ON bso (userid, collection, modified);
+
+
+CREATE TABLE IF NOT EXISTS collections(
+    id SERIAL PRIMARY KEY                   NOT NULL,
+    name VARCHAR(32) UNIQUE                 NOT NULL
+);
+INSERT INTO collections (id, name) VALUES
+    ( 1, 'clients'),
+    ( 2, 'crypto'),
+    ( 3, 'forms'),
+    ( 4, 'history'),
+    ( 5, 'keys'),
+    ( 6, 'meta'),
+    ( 7, 'bookmarks'),
+    ( 8, 'prefs'),
+    ( 9, 'tabs'),
+    (10, 'passwords'),
+    (11, 'addons'),
+    (12, 'addresses'),
+    (13, 'creditcards'),
+    -- Reserve space for additions to the standard collections
+    (100, '');
+-- Explicit ids don't advance the sequence
+SELECT setval('collections_id_seq', 100);
+
+
+CREATE TABLE IF NOT EXISTS user_collections(
+    userid BIGINT                           NOT NULL,
+    collection INTEGER                      NOT NULL,
+    -- last modified time in milliseconds since epoch
+    last_modified BIGINT                    NOT NULL,
+    total_bytes BIGINT,
+    count INTEGER,
+    PRIMARY KEY (userid, collection)
+);
+
+
+CREATE TABLE IF NOT EXISTS batch_uploads(
+    batch BIGINT                            NOT NULL,
+    userid BIGINT                           NOT NULL,
+    collection INTEGER                      NOT NULL,
+    PRIMARY KEY (batch, userid)
+);
+
+CREATE TABLE IF NOT EXISTS batch_upload_items(
+    batch BIGINT                            NOT NULL,
+    userid BIGINT                           NOT NULL,
+    id VARCHAR(64)                          NOT NULL,
+    sortindex INTEGER DEFAULT NULL,
+    payload TEXT,
+    payload_size BIGINT DEFAULT NULL,
+    ttl_offset INTEGER DEFAULT NULL,
+    PRIMARY KEY (batch, userid, id)
+);
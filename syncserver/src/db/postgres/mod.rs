@@ -0,0 +1,10 @@
+mod batch;
+pub mod models;
+pub mod pool;
+mod schema;
+#[cfg(test)]
+mod test;
+
+pub use self::pool::PgDbPool;
+#[cfg(test)]
+pub use self::test::TestTransactionCustomizer;
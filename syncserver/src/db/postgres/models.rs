@@ -0,0 +1,203 @@
+use futures::future::TryFutureExt;
+
+use std::{self, cell::RefCell, collections::HashMap, fmt, ops::Deref, sync::Arc};
+
+use diesel::{
+    connection::TransactionManager,
+    delete,
+    dsl::max,
+    expression::sql_literal::sql,
+    pg::PgConnection,
+    r2d2::{ConnectionManager, PooledConnection},
+    sql_query,
+    sql_types::{BigInt, Integer, Nullable, Text},
+    Connection, ExpressionMethods, GroupByDsl, OptionalExtension, QueryDsl, QueryResult,
+    RunQueryDsl,
+};
+#[cfg(test)]
+use diesel_logger::LoggingConnection;
+use syncserver_db_common::{
+    error::{DbError, DbErrorKind},
+    params, results,
+    util::SyncTimestamp,
+    Db, DbFuture, Sorting, UserIdentifier, DEFAULT_BSO_TTL,
+};
+use syncstorage_settings::{Quota, ServerLimits};
+
+use super::{
+    batch,
+    schema::{batch_upload_items, bso, collections, user_collections},
+};
+use crate::db::{
+    self,
+    diesel_common::{
+        CollectionCache, CollectionLock, COLLECTION_ID, COUNT, DEFAULT_LIMIT, EXPIRY,
+        LAST_MODIFIED, MODIFIED, TOMBSTONE, TOTAL_BYTES, USER_ID,
+    },
+};
+use crate::diesel_db;
+use crate::server::metrics::Metrics;
+use crate::web::tags::Tags;
+
+pub type Result<T> = std::result::Result<T, DbError>;
+type Conn = PooledConnection<ConnectionManager<PgConnection>>;
+
+// LENGTH() counts a TEXT value's characters, OCTET_LENGTH() its bytes. SUM()
+// of a BIGINT is a NUMERIC and COUNT() is a BIGINT
+diesel_db! {
+    db: PgDb,
+    inner: PgDbInner,
+    session: PgDbSession,
+    payload_bytes: "OCTET_LENGTH(payload)",
+    bigint_cast: "::BIGINT",
+    integer_cast: "::INTEGER",
+}
+
+impl PgDb {
+    /// Read locks do SELECT ... FOR SHARE and write locks do SELECT ... FOR
+    /// UPDATE, as the MySQL backend does.
+    ///
+    /// Unlike MySQL's gap locks, these don't lock a row that doesn't exist
+    /// yet, so a (shared for reads) transaction level advisory lock on the
+    /// user's collection is taken first: otherwise two writers could both
+    /// find no row and both create it.
+    fn select_locked_modified(
+        &self,
+        user_id: i64,
+        collection_id: i32,
+        lock: &CollectionLock,
+    ) -> Result<Option<i64>> {
+        // Legacy user ids are 32 bits and collection ids are positive
+        let key = (user_id << 31) | i64::from(collection_id);
+        let advisory_lock = match lock {
+            CollectionLock::Read => "SELECT pg_advisory_xact_lock_shared($1)",
+            CollectionLock::Write => "SELECT pg_advisory_xact_lock($1)",
+        };
+        sql_query(advisory_lock)
+            .bind::<BigInt, _>(key)
+            .execute(&self.conn)?;
+
+        let query = user_collections::table
+            .select(user_collections::modified)
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.eq(collection_id));
+        Ok(match lock {
+            CollectionLock::Read => query.for_share().first(&self.conn).optional()?,
+            CollectionLock::Write => query.for_update().first(&self.conn).optional()?,
+        })
+    }
+
+    pub(super) fn begin(&self, for_write: bool) -> Result<()> {
+        self.conn
+            .transaction_manager()
+            .begin_transaction(&self.conn)?;
+        self.session.borrow_mut().in_transaction = true;
+        if for_write {
+            self.session.borrow_mut().in_write_transaction = true;
+        }
+        Ok(())
+    }
+
+    fn erect_tombstone(&self, user_id: i32) -> Result<()> {
+        sql_query(format!(
+            r#"INSERT INTO user_collections ({user_id}, {collection_id}, {modified})
+               VALUES ($1, $2, $3)
+                   ON CONFLICT ({user_id}, {collection_id}) DO UPDATE SET
+                      {modified} = EXCLUDED.{modified}"#,
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = LAST_MODIFIED
+        ))
+        .bind::<BigInt, _>(user_id as i64)
+        .bind::<Integer, _>(TOMBSTONE)
+        .bind::<BigInt, _>(self.timestamp().as_i64())
+        .execute(&self.conn)?;
+        Ok(())
+    }
+
+    fn insert_collection(&self, name: &str) -> QueryResult<usize> {
+        diesel::insert_into(collections::table)
+            .values(collections::name.eq(name))
+            .on_conflict_do_nothing()
+            .execute(&self.conn)
+    }
+
+    fn put_bso_query(bso: &params::PutBso) -> String {
+        let mut updates = vec![];
+        if bso.sortindex.is_some() {
+            updates.push("sortindex = EXCLUDED.sortindex".to_owned());
+        }
+        if bso.payload.is_some() {
+            updates.push("payload = EXCLUDED.payload".to_owned());
+        }
+        if bso.ttl.is_some() {
+            updates.push(format!("{expiry} = EXCLUDED.{expiry}", expiry = EXPIRY));
+        }
+        if bso.payload.is_some() || bso.sortindex.is_some() {
+            updates.push(format!(
+                "{modified} = EXCLUDED.{modified}",
+                modified = MODIFIED
+            ));
+        }
+        let on_conflict = if updates.is_empty() {
+            "DO NOTHING".to_owned()
+        } else {
+            format!("DO UPDATE SET {}", updates.join(", "))
+        };
+        format!(
+            r#"
+            INSERT INTO bso ({user_id}, {collection_id}, id, sortindex, payload, {modified}, {expiry})
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+                ON CONFLICT ({user_id}, {collection_id}, id) {on_conflict}
+            "#,
+            user_id = USER_ID,
+            modified = MODIFIED,
+            collection_id = COLLECTION_ID,
+            expiry = EXPIRY,
+            on_conflict = on_conflict
+        )
+    }
+
+    fn upsert_user_collection(
+        &self,
+        user_id: u32,
+        collection_id: i32,
+        quota: &results::GetQuotaUsage,
+    ) -> Result<()> {
+        let upsert = format!(
+            r#"
+                INSERT INTO user_collections ({user_id}, {collection_id}, {modified}, {total_bytes}, {count})
+                VALUES ($1, $2, $3, $4, $5)
+                    ON CONFLICT ({user_id}, {collection_id}) DO UPDATE SET
+                       {modified} = EXCLUDED.{modified},
+                       {total_bytes} = EXCLUDED.{total_bytes},
+                       {count} = EXCLUDED.{count}
+        "#,
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = LAST_MODIFIED,
+            count = COUNT,
+            total_bytes = TOTAL_BYTES,
+        );
+        sql_query(upsert)
+            .bind::<BigInt, _>(user_id as i64)
+            .bind::<Integer, _>(&collection_id)
+            .bind::<BigInt, _>(&self.timestamp().as_i64())
+            .bind::<BigInt, _>(quota.total_bytes as i64)
+            .bind::<Integer, _>(&quota.count)
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    fn check_sync(&self) -> Result<results::Check> {
+        // can we still reach the database?
+        let result = sql_query("SELECT 1 AS id").get_result::<IdResult>(&self.conn)?;
+        Ok(result.id == 1)
+    }
+}
+
+#[derive(Debug, QueryableByName)]
+struct IdResult {
+    #[sql_type = "Integer"]
+    id: i32,
+}
@@ -0,0 +1,128 @@
+use async_trait::async_trait;
+
+use std::{fmt, sync::Arc, time::Duration};
+
+use diesel::{
+    pg::PgConnection,
+    r2d2::{ConnectionManager, Pool},
+    Connection,
+};
+#[cfg(test)]
+use diesel_logger::LoggingConnection;
+use syncserver_db_common::{Db, DbPool, GetPoolState, PoolState};
+use syncstorage_settings::{Quota, ServerLimits, Settings};
+
+use super::models::{PgDb, Result};
+#[cfg(test)]
+use super::test::TestTransactionCustomizer;
+use crate::db::{self, diesel_common::CollectionCache};
+use crate::server::metrics::Metrics;
+
+embed_migrations!("src/db/postgres/migrations");
+
+/// Run the diesel embedded migrations
+///
+/// Unlike MySQL, PostgreSQL's DDL is transactional, but migrations still run
+/// on their own separate conn to keep them out of PgDbPool's
+/// begin_test_transaction during tests.
+pub fn run_embedded_migrations(database_url: &str) -> Result<()> {
+    let conn = PgConnection::establish(database_url)?;
+    #[cfg(test)]
+    embedded_migrations::run(&LoggingConnection::new(conn))?;
+    #[cfg(not(test))]
+    embedded_migrations::run(&conn)?;
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct PgDbPool {
+    /// Pool of db connections
+    pool: Pool<ConnectionManager<PgConnection>>,
+    /// In-memory cache of collection_ids and their names
+    coll_cache: Arc<CollectionCache>,
+
+    metrics: Metrics,
+    quota: Quota,
+    limits: Arc<ServerLimits>,
+}
+
+impl PgDbPool {
+    /// Creates a new pool of PostgreSQL db connections.
+    ///
+    /// Also initializes the PostgreSQL db, ensuring all migrations are ran.
+    pub fn new(settings: &Settings, metrics: &Metrics) -> Result<Self> {
+        run_embedded_migrations(&settings.database_url)?;
+        Self::new_without_migrations(settings, metrics)
+    }
+
+    pub fn new_without_migrations(settings: &Settings, metrics: &Metrics) -> Result<Self> {
+        let manager = ConnectionManager::<PgConnection>::new(settings.database_url.clone());
+        let builder = Pool::builder()
+            .max_size(settings.database_pool_max_size)
+            .connection_timeout(Duration::from_secs(
+                settings.database_pool_connection_timeout.unwrap_or(30) as u64,
+            ))
+            .min_idle(settings.database_pool_min_idle);
+
+        #[cfg(test)]
+        let builder = if settings.database_use_test_transactions {
+            builder.connection_customizer(Box::new(TestTransactionCustomizer))
+        } else {
+            builder
+        };
+
+        Ok(Self {
+            pool: builder.build(manager)?,
+            coll_cache: Default::default(),
+            metrics: metrics.clone(),
+            quota: Quota {
+                size: settings.limits.max_quota_limit as usize,
+                enabled: settings.enable_quota,
+                enforced: settings.enforce_quota,
+            },
+            limits: Arc::new(settings.limits.clone()),
+        })
+    }
+
+    pub fn get_sync(&self) -> Result<PgDb> {
+        Ok(PgDb::new(
+            self.pool.get()?,
+            Arc::clone(&self.coll_cache),
+            &self.metrics,
+            &self.quota,
+            Arc::clone(&self.limits),
+        ))
+    }
+}
+
+#[async_trait]
+impl DbPool for PgDbPool {
+    async fn get<'a>(&'a self) -> Result<Box<dyn Db<'a>>> {
+        let pool = self.clone();
+        let db = db::run_on_blocking_threadpool(move || pool.get_sync()).await?;
+
+        Ok(Box::new(db) as Box<dyn Db<'a>>)
+    }
+
+    fn validate_batch_id(&self, id: String) -> Result<()> {
+        super::batch::validate_batch_id(&id)
+    }
+
+    fn box_clone(&self) -> Box<dyn DbPool> {
+        Box::new(self.clone())
+    }
+}
+
+impl GetPoolState for PgDbPool {
+    fn state(&self) -> PoolState {
+        self.pool.state().into()
+    }
+}
+
+impl fmt::Debug for PgDbPool {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("PgDbPool")
+            .field("coll_cache", &self.coll_cache)
+            .finish()
+    }
+}
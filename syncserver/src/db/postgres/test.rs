@@ -0,0 +1,85 @@
+use std::{collections::HashMap, result::Result as StdResult};
+
+use diesel::{
+    pg::PgConnection,
+    r2d2::{CustomizeConnection, Error as PoolError},
+    Connection, ExpressionMethods, QueryDsl, RunQueryDsl, TextExpressionMethods,
+};
+use syncserver_settings::Settings as SyncserverSettings;
+use syncstorage_settings::Settings as SyncstorageSettings;
+use url::Url;
+
+use crate::db::postgres::{
+    models::{PgDb, Result},
+    pool::PgDbPool,
+    schema::collections,
+};
+use crate::server::metrics;
+
+#[derive(Debug)]
+pub struct TestTransactionCustomizer;
+
+impl CustomizeConnection<PgConnection, PoolError> for TestTransactionCustomizer {
+    fn on_acquire(&self, conn: &mut PgConnection) -> StdResult<(), PoolError> {
+        conn.begin_test_transaction().map_err(PoolError::QueryError)
+    }
+}
+
+pub fn db(settings: &SyncstorageSettings) -> Result<PgDb> {
+    let _ = env_logger::try_init();
+    // inherit SYNC_SYNCSTORAGE__DATABASE_URL from the env
+
+    let pool = PgDbPool::new(settings, &metrics::Metrics::noop())?;
+    pool.get_sync()
+}
+
+#[test]
+fn static_collection_id() -> Result<()> {
+    let settings = SyncserverSettings::test_settings().syncstorage;
+    if !matches!(
+        Url::parse(&settings.database_url).unwrap().scheme(),
+        "postgres" | "postgresql"
+    ) {
+        // Skip this test if we're not using postgres
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    // ensure DB actually has predefined common collections
+    let cols: Vec<(i32, _)> = vec![
+        (1, "clients"),
+        (2, "crypto"),
+        (3, "forms"),
+        (4, "history"),
+        (5, "keys"),
+        (6, "meta"),
+        (7, "bookmarks"),
+        (8, "prefs"),
+        (9, "tabs"),
+        (10, "passwords"),
+        (11, "addons"),
+        (12, "addresses"),
+        (13, "creditcards"),
+    ];
+    let results: HashMap<i32, String> = collections::table
+        .select((collections::id, collections::name))
+        .filter(collections::name.ne(""))
+        .filter(collections::name.not_like("xxx%")) // from most integration tests
+        .filter(collections::name.ne("col2")) // from older intergration tests
+        .load(&db.inner.conn)?
+        .into_iter()
+        .collect();
+    assert_eq!(results.len(), cols.len(), "mismatched columns");
+    for (id, name) in &cols {
+        assert_eq!(results.get(id).unwrap(), name);
+    }
+
+    for (id, name) in &cols {
+        let result = db.get_collection_id(name)?;
+        assert_eq!(result, *id);
+    }
+
+    let cid = db.get_or_create_collection_id("col1")?;
+    assert!(cid >= 100);
+    Ok(())
+}
@@ -11,7 +11,7 @@ use protobuf::{
 use syncserver_db_common::{
     error::{DbError, DbErrorKind},
     params, results,
-    util::to_rfc3339,
+    util::{to_rfc3339, SyncTimestamp},
     UserIdentifier, BATCH_LIFETIME, DEFAULT_BSO_TTL,
 };
 use uuid::Uuid;
@@ -144,6 +144,46 @@ pub async fn get_async(
     Ok(batch)
 }
 
+pub async fn get_status_async(
+    db: &SpannerDb,
+    params: params::GetBatchStatus,
+) -> Result<Option<results::GetBatchStatus>> {
+    let collection_id = db.get_collection_id_async(&params.collection).await?;
+    let (sqlparams, sqlparam_types) = params! {
+        "fxa_uid" => params.user_id.fxa_uid.clone(),
+        "fxa_kid" => params.user_id.fxa_kid.clone(),
+        "collection_id" => collection_id,
+        "batch_id" => params.id.clone(),
+    };
+    let result = db
+        .sql(
+            "SELECT expiry
+               FROM batches
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid
+                AND collection_id = @collection_id
+                AND batch_id = @batch_id
+                AND expiry > CURRENT_TIMESTAMP()",
+        )?
+        .params(sqlparams)
+        .param_types(sqlparam_types)
+        .execute_async(&db.conn)?
+        .one_or_none()
+        .await?;
+    let expiry = match result {
+        Some(row) => SyncTimestamp::from_rfc3339(row[0].get_string_value())?,
+        None => return Ok(None),
+    };
+
+    let (count, total_bytes) = totals_async(db, &params.user_id, collection_id, &params.id).await?;
+    Ok(Some(results::GetBatchStatus {
+        id: params.id,
+        count,
+        total_bytes,
+        expiry,
+    }))
+}
+
 pub async fn delete_async(db: &SpannerDb, params: params::DeleteBatch) -> Result<()> {
     let collection_id = db.get_collection_id_async(&params.collection).await?;
     let (sqlparams, sqlparam_types) = params! {
@@ -514,9 +554,64 @@ pub async fn do_append_async(
         }
     }
 
+    check_totals_async(db, &user_id, collection_id, &batch.id).await
+}
+
+/// Enforces the batch size limits described on `Db::append_to_batch`, once
+/// the items have been written
+async fn check_totals_async(
+    db: &SpannerDb,
+    user_id: &UserIdentifier,
+    collection_id: i32,
+    batch_id: &str,
+) -> Result<()> {
+    let (count, total_bytes) = totals_async(db, user_id, collection_id, batch_id).await?;
+    if count > u64::from(db.limits.max_total_records)
+        || total_bytes > u64::from(db.limits.max_total_bytes)
+    {
+        Err(DbErrorKind::BatchTooLarge)?
+    }
     Ok(())
 }
 
+/// The number of items in a batch and the combined size of their payloads
+async fn totals_async(
+    db: &SpannerDb,
+    user_id: &UserIdentifier,
+    collection_id: i32,
+    batch_id: &str,
+) -> Result<(u64, u64)> {
+    let (sqlparams, sqlparam_types) = params! {
+        "fxa_uid" => user_id.fxa_uid.clone(),
+        "fxa_kid" => user_id.fxa_kid.clone(),
+        "collection_id" => collection_id,
+        "batch_id" => batch_id.to_owned(),
+    };
+    let result = db
+        .sql(
+            "SELECT COUNT(*), COALESCE(SUM(BYTE_LENGTH(payload)), 0)
+               FROM batch_bsos
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid
+                AND collection_id = @collection_id
+                AND batch_id = @batch_id",
+        )?
+        .params(sqlparams)
+        .param_types(sqlparam_types)
+        .execute_async(&db.conn)?
+        .one()
+        .await?;
+    let count = result[0]
+        .get_string_value()
+        .parse::<u64>()
+        .map_err(|e| DbErrorKind::Integrity(e.to_string()))?;
+    let total_bytes = result[1]
+        .get_string_value()
+        .parse::<u64>()
+        .map_err(|e| DbErrorKind::Integrity(e.to_string()))?;
+    Ok((count, total_bytes))
+}
+
 /// Ensure a parent row exists in user_collections prior to creating a child
 /// row in the batches table.
 ///
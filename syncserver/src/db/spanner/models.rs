@@ -29,7 +29,7 @@ use syncserver_db_common::{
     util::SyncTimestamp,
     Db, DbFuture, Sorting, UserIdentifier, DEFAULT_BSO_TTL, FIRST_CUSTOM_COLLECTION_ID,
 };
-use syncstorage_settings::Quota;
+use syncstorage_settings::{Quota, ServerLimits};
 
 use crate::{db::spanner::now, server::metrics::Metrics, web::tags::Tags};
 
@@ -83,6 +83,7 @@ pub struct SpannerDb {
 
     pub metrics: Metrics,
     pub quota: Quota,
+    pub limits: Arc<ServerLimits>,
 }
 
 pub struct SpannerDbInner {
@@ -111,6 +112,7 @@ impl SpannerDb {
         coll_cache: Arc<CollectionCache>,
         metrics: &Metrics,
         quota: Quota,
+        limits: Arc<ServerLimits>,
     ) -> Self {
         let inner = SpannerDbInner {
             conn,
@@ -121,6 +123,7 @@ impl SpannerDb {
             coll_cache,
             metrics: metrics.clone(),
             quota,
+            limits,
         }
     }
 
@@ -2075,6 +2078,18 @@ impl<'a> Db<'a> for SpannerDb {
         Box::pin(async move { batch::get_async(&db, param).map_err(Into::into).await })
     }
 
+    fn get_batch_status(
+        &self,
+        param: params::GetBatchStatus,
+    ) -> DbFuture<'_, Option<results::GetBatchStatus>> {
+        let db = self.clone();
+        Box::pin(async move {
+            batch::get_status_async(&db, param)
+                .map_err(Into::into)
+                .await
+        })
+    }
+
     fn commit_batch(&self, param: params::CommitBatch) -> DbFuture<'_, results::CommitBatch> {
         let db = self.clone();
         Box::pin(async move { batch::commit_async(&db, param).map_err(Into::into).await })
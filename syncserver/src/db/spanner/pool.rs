@@ -3,7 +3,7 @@ use std::{collections::HashMap, fmt, sync::Arc, time::Duration};
 use async_trait::async_trait;
 use bb8::ErrorSink;
 use syncserver_db_common::{error::DbError, Db, DbPool, GetPoolState, PoolState, STD_COLLS};
-use syncstorage_settings::{Quota, Settings};
+use syncstorage_settings::{Quota, ServerLimits, Settings};
 use tokio::sync::RwLock;
 
 use crate::server::metrics::Metrics;
@@ -35,6 +35,7 @@ pub struct SpannerDbPool {
 
     metrics: Metrics,
     quota: Quota,
+    limits: Arc<ServerLimits>,
 }
 
 impl SpannerDbPool {
@@ -66,6 +67,7 @@ impl SpannerDbPool {
                 enabled: settings.enable_quota,
                 enforced: settings.enforce_quota,
             },
+            limits: Arc::new(settings.limits.clone()),
         })
     }
 
@@ -81,6 +83,7 @@ impl SpannerDbPool {
             Arc::clone(&self.coll_cache),
             &self.metrics,
             self.quota,
+            Arc::clone(&self.limits),
         ))
     }
 }
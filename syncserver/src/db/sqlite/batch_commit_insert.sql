@@ -0,0 +1,17 @@
+INSERT INTO bso (userid, collection, id, modified, sortindex, ttl, payload, payload_size)
+SELECT
+       ?,
+       ?,
+       id,
+       ?,
+       sortindex,
+       COALESCE((ttl_offset * 1000) + ?, ?),
+       COALESCE(payload, ''),
+       COALESCE(payload_size, 0)
+  FROM batch_upload_items
+ WHERE batch = ?
+   AND userid = ?
+   AND id NOT IN (SELECT id
+                    FROM bso
+                   WHERE userid = ?
+                     AND collection = ?)
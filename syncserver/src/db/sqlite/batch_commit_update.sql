@@ -0,0 +1,18 @@
+UPDATE bso
+   SET (sortindex, ttl, payload, payload_size) = (
+       SELECT COALESCE(b.sortindex, bso.sortindex),
+              COALESCE((b.ttl_offset * 1000) + ?, bso.ttl),
+              COALESCE(b.payload, bso.payload),
+              COALESCE(b.payload_size, bso.payload_size)
+         FROM batch_upload_items b
+        WHERE b.batch = ?
+          AND b.userid = ?
+          AND b.id = bso.id
+       ),
+       modified = ?
+ WHERE userid = ?
+   AND collection = ?
+   AND id IN (SELECT id
+                FROM batch_upload_items
+               WHERE batch = ?
+                 AND userid = ?)
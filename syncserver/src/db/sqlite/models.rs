@@ -0,0 +1,193 @@
+use futures::future::TryFutureExt;
+
+use std::{self, cell::RefCell, collections::HashMap, fmt, ops::Deref, sync::Arc};
+
+use diesel::{
+    connection::TransactionManager,
+    delete,
+    dsl::max,
+    expression::sql_literal::sql,
+    r2d2::{ConnectionManager, PooledConnection},
+    sql_query,
+    sql_types::{BigInt, Integer, Nullable, Text},
+    sqlite::SqliteConnection,
+    Connection, ExpressionMethods, GroupByDsl, OptionalExtension, QueryDsl, QueryResult,
+    RunQueryDsl,
+};
+#[cfg(test)]
+use diesel_logger::LoggingConnection;
+use syncserver_db_common::{
+    error::{DbError, DbErrorKind},
+    params, results,
+    util::SyncTimestamp,
+    Db, DbFuture, Sorting, UserIdentifier, DEFAULT_BSO_TTL,
+};
+use syncstorage_settings::{Quota, ServerLimits};
+
+use super::{
+    batch,
+    schema::{batch_upload_items, bso, collections, user_collections},
+};
+use crate::db::{
+    self,
+    diesel_common::{
+        CollectionCache, CollectionLock, COLLECTION_ID, COUNT, DEFAULT_LIMIT, EXPIRY,
+        LAST_MODIFIED, MODIFIED, TOMBSTONE, TOTAL_BYTES, USER_ID,
+    },
+};
+use crate::diesel_db;
+use crate::server::metrics::Metrics;
+use crate::web::tags::Tags;
+
+pub type Result<T> = std::result::Result<T, DbError>;
+type Conn = PooledConnection<ConnectionManager<SqliteConnection>>;
+
+// LENGTH() counts a TEXT value's characters, so payloads are measured as BLOBs
+diesel_db! {
+    db: SqliteDb,
+    inner: SqliteDbInner,
+    session: SqliteDbSession,
+    payload_bytes: "LENGTH(CAST(payload AS BLOB))",
+    bigint_cast: "",
+    integer_cast: "",
+}
+
+impl SqliteDb {
+    /// SQLite has no row level locks: read locks start a deferred
+    /// transaction (taking a shared lock on the first read) and write locks
+    /// start an immediate transaction, which takes the database's single
+    /// write lock up front. Transactions are serializable either way.
+    fn select_locked_modified(
+        &self,
+        user_id: i64,
+        collection_id: i32,
+        _lock: &CollectionLock,
+    ) -> Result<Option<i64>> {
+        Ok(user_collections::table
+            .select(user_collections::modified)
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.eq(collection_id))
+            .first(&self.conn)
+            .optional()?)
+    }
+
+    pub(super) fn begin(&self, for_write: bool) -> Result<()> {
+        let transaction_manager = self.conn.transaction_manager();
+        // Nested (e.g. test) transactions are savepoints, which can't be
+        // IMMEDIATE: the write lock is then taken by the first write instead
+        let depth =
+            TransactionManager::<SqliteConnection>::get_transaction_depth(transaction_manager);
+        if for_write && depth == 0 {
+            transaction_manager.begin_transaction_sql(&self.conn, "BEGIN IMMEDIATE")?;
+        } else {
+            transaction_manager.begin_transaction(&self.conn)?;
+        }
+        self.session.borrow_mut().in_transaction = true;
+        if for_write {
+            self.session.borrow_mut().in_write_transaction = true;
+        }
+        Ok(())
+    }
+
+    fn erect_tombstone(&self, user_id: i32) -> Result<()> {
+        sql_query(format!(
+            r#"INSERT INTO user_collections ({user_id}, {collection_id}, {modified})
+               VALUES (?, ?, ?)
+                   ON CONFLICT ({user_id}, {collection_id}) DO UPDATE SET
+                      {modified} = excluded.{modified}"#,
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = LAST_MODIFIED
+        ))
+        .bind::<BigInt, _>(user_id as i64)
+        .bind::<Integer, _>(TOMBSTONE)
+        .bind::<BigInt, _>(self.timestamp().as_i64())
+        .execute(&self.conn)?;
+        Ok(())
+    }
+
+    fn insert_collection(&self, name: &str) -> QueryResult<usize> {
+        diesel::insert_or_ignore_into(collections::table)
+            .values(collections::name.eq(name))
+            .execute(&self.conn)
+    }
+
+    fn put_bso_query(bso: &params::PutBso) -> String {
+        let mut updates = vec![];
+        if bso.sortindex.is_some() {
+            updates.push("sortindex = excluded.sortindex".to_owned());
+        }
+        if bso.payload.is_some() {
+            updates.push("payload = excluded.payload".to_owned());
+        }
+        if bso.ttl.is_some() {
+            updates.push(format!("{expiry} = excluded.{expiry}", expiry = EXPIRY));
+        }
+        if bso.payload.is_some() || bso.sortindex.is_some() {
+            updates.push(format!(
+                "{modified} = excluded.{modified}",
+                modified = MODIFIED
+            ));
+        }
+        let on_conflict = if updates.is_empty() {
+            "DO NOTHING".to_owned()
+        } else {
+            format!("DO UPDATE SET {}", updates.join(", "))
+        };
+        format!(
+            r#"
+            INSERT INTO bso ({user_id}, {collection_id}, id, sortindex, payload, {modified}, {expiry})
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+                ON CONFLICT ({user_id}, {collection_id}, id) {on_conflict}
+            "#,
+            user_id = USER_ID,
+            modified = MODIFIED,
+            collection_id = COLLECTION_ID,
+            expiry = EXPIRY,
+            on_conflict = on_conflict
+        )
+    }
+
+    fn upsert_user_collection(
+        &self,
+        user_id: u32,
+        collection_id: i32,
+        quota: &results::GetQuotaUsage,
+    ) -> Result<()> {
+        let upsert = format!(
+            r#"
+                INSERT INTO user_collections ({user_id}, {collection_id}, {modified}, {total_bytes}, {count})
+                VALUES (?, ?, ?, ?, ?)
+                    ON CONFLICT ({user_id}, {collection_id}) DO UPDATE SET
+                       {modified} = excluded.{modified},
+                       {total_bytes} = excluded.{total_bytes},
+                       {count} = excluded.{count}
+        "#,
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = LAST_MODIFIED,
+            count = COUNT,
+            total_bytes = TOTAL_BYTES,
+        );
+        sql_query(upsert)
+            .bind::<BigInt, _>(user_id as i64)
+            .bind::<Integer, _>(&collection_id)
+            .bind::<BigInt, _>(&self.timestamp().as_i64())
+            .bind::<BigInt, _>(quota.total_bytes as i64)
+            .bind::<Integer, _>(&quota.count)
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    fn check_sync(&self) -> Result<results::Check> {
+        // can we still read from the database file?
+        let result = sql_query("SELECT 1 AS id").get_result::<IdResult>(&self.conn)?;
+        Ok(result.id == 1)
+    }
+}
+
+#[derive(Debug, QueryableByName)]
+struct IdResult {
+    #[sql_type = "Integer"]
+    id: i32,
+}
@@ -0,0 +1,158 @@
+use async_trait::async_trait;
+
+use std::{fmt, result::Result as StdResult, sync::Arc, time::Duration};
+
+use diesel::{
+    connection::SimpleConnection,
+    r2d2::{ConnectionManager, CustomizeConnection, Error as PoolError, Pool},
+    sqlite::SqliteConnection,
+    Connection,
+};
+#[cfg(test)]
+use diesel_logger::LoggingConnection;
+use syncserver_db_common::{error::DbError, Db, DbPool, GetPoolState, PoolState};
+use syncstorage_settings::{Quota, ServerLimits, Settings};
+
+use super::models::{Result, SqliteDb};
+#[cfg(test)]
+use super::test::TestTransactionCustomizer;
+use crate::db::{self, diesel_common::CollectionCache};
+use crate::server::metrics::Metrics;
+
+embed_migrations!("src/db/sqlite/migrations");
+
+/// How long a connection waits on another connection's write lock before
+/// giving up with `SQLITE_BUSY`, in milliseconds
+const BUSY_TIMEOUT: u32 = 5_000;
+
+/// Run the diesel embedded migrations
+///
+/// Also switches the database to WAL mode, which persists in the database
+/// file and lets readers proceed while a write transaction is open.
+pub fn run_embedded_migrations(database_path: &str) -> Result<()> {
+    let conn = SqliteConnection::establish(database_path)?;
+    conn.batch_execute("PRAGMA journal_mode = WAL;")?;
+    #[cfg(test)]
+    embedded_migrations::run(&LoggingConnection::new(conn))?;
+    #[cfg(not(test))]
+    embedded_migrations::run(&conn)?;
+    Ok(())
+}
+
+/// Per connection SQLite settings that aren't persisted in the database file
+pub(super) fn init_connection(conn: &SqliteConnection) -> diesel::QueryResult<()> {
+    conn.batch_execute(&format!(
+        "PRAGMA busy_timeout = {}; PRAGMA synchronous = NORMAL;",
+        BUSY_TIMEOUT
+    ))
+}
+
+#[derive(Debug)]
+pub struct SqliteConnectionCustomizer;
+
+impl CustomizeConnection<SqliteConnection, PoolError> for SqliteConnectionCustomizer {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> StdResult<(), PoolError> {
+        init_connection(conn).map_err(PoolError::QueryError)
+    }
+}
+
+#[derive(Clone)]
+pub struct SqliteDbPool {
+    /// Pool of db connections
+    pool: Pool<ConnectionManager<SqliteConnection>>,
+    /// In-memory cache of collection_ids and their names
+    coll_cache: Arc<CollectionCache>,
+
+    metrics: Metrics,
+    quota: Quota,
+    limits: Arc<ServerLimits>,
+}
+
+impl SqliteDbPool {
+    /// Creates a new pool of SQLite db connections.
+    ///
+    /// Also initializes the SQLite db, ensuring all migrations are ran.
+    pub fn new(settings: &Settings, metrics: &Metrics) -> Result<Self> {
+        run_embedded_migrations(database_path(settings)?)?;
+        Self::new_without_migrations(settings, metrics)
+    }
+
+    pub fn new_without_migrations(settings: &Settings, metrics: &Metrics) -> Result<Self> {
+        let manager = ConnectionManager::<SqliteConnection>::new(database_path(settings)?);
+        let builder = Pool::builder()
+            .max_size(settings.database_pool_max_size)
+            .connection_timeout(Duration::from_secs(
+                settings.database_pool_connection_timeout.unwrap_or(30) as u64,
+            ))
+            .min_idle(settings.database_pool_min_idle)
+            .connection_customizer(Box::new(SqliteConnectionCustomizer));
+
+        #[cfg(test)]
+        let builder = if settings.database_use_test_transactions {
+            builder.connection_customizer(Box::new(TestTransactionCustomizer))
+        } else {
+            builder
+        };
+
+        Ok(Self {
+            pool: builder.build(manager)?,
+            coll_cache: Default::default(),
+            metrics: metrics.clone(),
+            quota: Quota {
+                size: settings.limits.max_quota_limit as usize,
+                enabled: settings.enable_quota,
+                enforced: settings.enforce_quota,
+            },
+            limits: Arc::new(settings.limits.clone()),
+        })
+    }
+
+    pub fn get_sync(&self) -> Result<SqliteDb> {
+        Ok(SqliteDb::new(
+            self.pool.get()?,
+            Arc::clone(&self.coll_cache),
+            &self.metrics,
+            &self.quota,
+            Arc::clone(&self.limits),
+        ))
+    }
+}
+
+/// The SQLite database file named by `settings.database_url`
+fn database_path(settings: &Settings) -> Result<&str> {
+    settings
+        .sqlite_database_path()
+        .ok_or_else(|| DbError::internal("database_url is not a sqlite:// url"))
+}
+
+#[async_trait]
+impl DbPool for SqliteDbPool {
+    async fn get<'a>(&'a self) -> Result<Box<dyn Db<'a>>> {
+        let pool = self.clone();
+        let db = db::run_on_blocking_threadpool(move || pool.get_sync()).await?;
+
+        Ok(Box::new(db) as Box<dyn Db<'a>>)
+    }
+
+    fn validate_batch_id(&self, id: String) -> Result<()> {
+        super::batch::validate_batch_id(&id)
+    }
+
+    fn box_clone(&self) -> Box<dyn DbPool> {
+        Box::new(self.clone())
+    }
+}
+
+impl GetPoolState for SqliteDbPool {
+    fn state(&self) -> PoolState {
+        self.pool.state().into()
+    }
+}
+
+impl fmt::Debug for SqliteDbPool {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("SqliteDbPool")
+            .field("coll_cache", &self.coll_cache)
+            .finish()
+    }
+}
@@ -156,6 +156,39 @@ async fn append_commit() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn append_updates_only_resent_item() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = 1;
+    let coll = "clients";
+    let bsos = vec![
+        postbso("b0", Some("payload 0"), None, None),
+        postbso("b1", Some("payload 1"), None, None),
+    ];
+    let new_batch = db.create_batch(cb(uid, coll, bsos)).await?;
+
+    // Re-sending one item updates that item alone, not every item in the batch
+    let bsos = vec![postbso("b0", Some("payload 0 resent"), None, None)];
+    db.append_to_batch(ab(uid, coll, new_batch.clone(), bsos))
+        .await?;
+
+    let batch = db.get_batch(gb(uid, coll, new_batch.id)).await?.unwrap();
+    db.commit_batch(params::CommitBatch {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        batch,
+    })
+    .await?;
+
+    let bso_0 = db.get_bso(gbso(uid, coll, "b0")).await?.unwrap();
+    assert_eq!(bso_0.payload, "payload 0 resent");
+    let bso_1 = db.get_bso(gbso(uid, coll, "b1")).await?.unwrap();
+    assert_eq!(bso_1.payload, "payload 1");
+    Ok(())
+}
+
 #[tokio::test]
 async fn quota_test_create_batch() -> Result<()> {
     let mut settings = Settings::test_settings().syncstorage;
@@ -243,6 +276,119 @@ async fn quota_test_append_batch() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn append_total_limits() -> Result<()> {
+    let mut settings = Settings::test_settings().syncstorage;
+    settings.limits.max_total_records = 2;
+    settings.limits.max_total_bytes = 20;
+
+    let pool = db_pool(Some(settings)).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = 1;
+    let coll = "clients";
+    let new_batch = db
+        .create_batch(cb(
+            uid,
+            coll,
+            vec![
+                postbso("b0", Some("0123456789"), None, None),
+                postbso("b1", Some("0123456789"), None, None),
+            ],
+        ))
+        .await?;
+
+    // Re-sending an item replaces it rather than adding to the totals
+    db.append_to_batch(ab(
+        uid,
+        coll,
+        new_batch.clone(),
+        vec![postbso("b1", Some("9876543210"), None, None)],
+    ))
+    .await?;
+
+    // One record too many
+    let result = db
+        .append_to_batch(ab(
+            uid,
+            coll,
+            new_batch,
+            vec![postbso("b2", Some("x"), None, None)],
+        ))
+        .await;
+    assert!(result.unwrap_err().is_batch_too_large());
+
+    // One byte too many (in a new batch, for another user, as batch ids are
+    // derived from the timestamp)
+    let uid = 2;
+    let new_batch = db.create_batch(cb(uid, coll, vec![])).await?;
+    let result = db
+        .append_to_batch(ab(
+            uid,
+            coll,
+            new_batch,
+            vec![postbso("b0", Some("012345678901234567890"), None, None)],
+        ))
+        .await;
+    assert!(result.unwrap_err().is_batch_too_large());
+    Ok(())
+}
+
+#[tokio::test]
+async fn status() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = 1;
+    let coll = "clients";
+    let new_batch = db
+        .create_batch(cb(
+            uid,
+            coll,
+            vec![
+                postbso("b0", Some("payload 0"), None, None),
+                postbso("b1", Some("payload 1"), None, None),
+            ],
+        ))
+        .await?;
+    db.append_to_batch(ab(
+        uid,
+        coll,
+        new_batch.clone(),
+        vec![postbso("b1", Some("payload one"), None, None)],
+    ))
+    .await?;
+
+    let status = db
+        .get_batch_status(params::GetBatchStatus {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            id: new_batch.id.clone(),
+        })
+        .await?
+        .unwrap();
+    assert_eq!(status.id, new_batch.id);
+    assert_eq!(status.count, 2);
+    assert_eq!(status.total_bytes, 20);
+    assert!(status.expiry > db.timestamp());
+
+    db.delete_batch(params::DeleteBatch {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        id: new_batch.id.clone(),
+    })
+    .await?;
+    let status = db
+        .get_batch_status(params::GetBatchStatus {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            id: new_batch.id,
+        })
+        .await?;
+    assert!(status.is_none());
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_append_async_w_null() -> Result<()> {
     let settings = Settings::test_settings().syncstorage;
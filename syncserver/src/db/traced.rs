@@ -0,0 +1,110 @@
+//! A `Db` recording a tracing span for each call.
+use syncserver_db_common::{params, results, util::SyncTimestamp, Db, DbFuture};
+
+use crate::telemetry;
+
+#[derive(Debug)]
+pub struct TracedDb<'a> {
+    inner: Box<dyn Db<'a>>,
+}
+
+impl<'a> TracedDb<'a> {
+    pub fn new(inner: Box<dyn Db<'a>>) -> Self {
+        Self { inner }
+    }
+}
+
+macro_rules! traced_db_method {
+    ($name:ident, $type:ident) => {
+        traced_db_method!($name, $type, results::$type);
+    };
+    ($name:ident, $type:ident, $result:ty) => {
+        fn $name(&self, params: params::$type) -> DbFuture<'_, $result> {
+            Box::pin(telemetry::in_span(
+                concat!("db.", stringify!($name)),
+                self.inner.$name(params),
+            ))
+        }
+    };
+}
+
+impl<'a> Db<'a> for TracedDb<'a> {
+    fn begin(&self, for_write: bool) -> DbFuture<'_, ()> {
+        Box::pin(telemetry::in_span("db.begin", self.inner.begin(for_write)))
+    }
+
+    fn commit(&self) -> DbFuture<'_, ()> {
+        Box::pin(telemetry::in_span("db.commit", self.inner.commit()))
+    }
+
+    fn rollback(&self) -> DbFuture<'_, ()> {
+        Box::pin(telemetry::in_span("db.rollback", self.inner.rollback()))
+    }
+
+    fn box_clone(&self) -> Box<dyn Db<'a>> {
+        Box::new(TracedDb::new(self.inner.box_clone()))
+    }
+
+    fn check(&self) -> DbFuture<'_, results::Check> {
+        Box::pin(telemetry::in_span("db.check", self.inner.check()))
+    }
+
+    traced_db_method!(lock_for_read, LockCollection);
+    traced_db_method!(lock_for_write, LockCollection);
+    traced_db_method!(get_collection_timestamps, GetCollectionTimestamps);
+    traced_db_method!(get_collection_timestamp, GetCollectionTimestamp);
+    traced_db_method!(get_collection_counts, GetCollectionCounts);
+    traced_db_method!(get_collection_usage, GetCollectionUsage);
+    traced_db_method!(get_storage_timestamp, GetStorageTimestamp);
+    traced_db_method!(get_storage_usage, GetStorageUsage);
+    traced_db_method!(get_quota_usage, GetQuotaUsage);
+    traced_db_method!(delete_storage, DeleteStorage);
+    traced_db_method!(delete_collection, DeleteCollection);
+    traced_db_method!(delete_bsos, DeleteBsos);
+    traced_db_method!(get_bsos, GetBsos);
+    traced_db_method!(get_bso_ids, GetBsoIds);
+    traced_db_method!(post_bsos, PostBsos);
+    traced_db_method!(delete_bso, DeleteBso);
+    traced_db_method!(get_bso, GetBso, Option<results::GetBso>);
+    traced_db_method!(get_bso_timestamp, GetBsoTimestamp);
+    traced_db_method!(put_bso, PutBso);
+    traced_db_method!(create_batch, CreateBatch);
+    traced_db_method!(validate_batch, ValidateBatch);
+    traced_db_method!(append_to_batch, AppendToBatch);
+    traced_db_method!(get_batch, GetBatch, Option<results::GetBatch>);
+    traced_db_method!(
+        get_batch_status,
+        GetBatchStatus,
+        Option<results::GetBatchStatus>
+    );
+    traced_db_method!(commit_batch, CommitBatch);
+
+    fn get_connection_info(&self) -> results::ConnectionInfo {
+        self.inner.get_connection_info()
+    }
+
+    traced_db_method!(get_collection_id, GetCollectionId);
+    traced_db_method!(create_collection, CreateCollection);
+    traced_db_method!(update_collection, UpdateCollection);
+
+    fn timestamp(&self) -> SyncTimestamp {
+        self.inner.timestamp()
+    }
+
+    fn set_timestamp(&self, timestamp: SyncTimestamp) {
+        self.inner.set_timestamp(timestamp)
+    }
+
+    traced_db_method!(delete_batch, DeleteBatch);
+
+    fn clear_coll_cache(&self) -> DbFuture<'_, ()> {
+        Box::pin(telemetry::in_span(
+            "db.clear_coll_cache",
+            self.inner.clear_coll_cache(),
+        ))
+    }
+
+    fn set_quota(&mut self, enabled: bool, limit: usize, enforce: bool) {
+        self.inner.set_quota(enabled, limit, enforce)
+    }
+}
@@ -1,5 +1,6 @@
 use std::cell::RefMut;
 use std::future::Future;
+use std::sync::Arc;
 
 use actix_http::http::{HeaderValue, Method, StatusCode};
 use actix_http::{Error, Extensions};
@@ -12,10 +13,12 @@ use futures::FutureExt;
 use syncserver_common::X_LAST_MODIFIED;
 use syncserver_db_common::{params, Db, DbPool, UserIdentifier};
 
-use crate::db::results::ConnectionInfo;
+use crate::db::{results::ConnectionInfo, traced::TracedDb};
 use crate::error::{ApiError, ApiErrorKind};
+use crate::server::changes::{Change, ChangeFanout};
 use crate::server::metrics::Metrics;
-use crate::server::ServerState;
+use crate::server::{shutdown::InFlight, ServerState};
+use crate::telemetry;
 use crate::web::extractors::{
     BsoParam, CollectionParam, HawkIdentifier, PreConditionHeader, PreConditionHeaderOpt,
 };
@@ -29,6 +32,8 @@ pub struct DbTransactionPool {
     collection: Option<String>,
     bso_opt: Option<String>,
     precondition: PreConditionHeaderOpt,
+    changes: Arc<dyn ChangeFanout>,
+    transactions: Arc<InFlight>,
 }
 
 fn set_extra(exts: &mut RefMut<'_, Extensions>, connection_info: ConnectionInfo) {
@@ -60,7 +65,9 @@ impl DbTransactionPool {
         F: Future<Output = Result<R, ApiError>> + 'a,
     {
         // Get connection from pool
-        let db = self.pool.get().await?;
+        let db: Box<dyn Db<'a>> = Box::new(TracedDb::new(
+            telemetry::in_span("db.pool.get", self.pool.get()).await?,
+        ));
         let db2 = db.clone();
 
         // Lock for transaction
@@ -105,11 +112,15 @@ impl DbTransactionPool {
         A: FnOnce(Box<dyn Db<'a>>) -> F,
         F: Future<Output = Result<R, ApiError>> + 'a,
     {
-        let (resp, db) = self.transaction_internal(request, action).await?;
+        telemetry::in_span("db.transaction", async move {
+            let _in_flight = self.transactions.begin();
+            let (resp, db) = self.transaction_internal(request, action).await?;
 
-        // No further processing before commit is possible
-        db.commit().await?;
-        Ok(resp)
+            // No further processing before commit is possible
+            db.commit().await?;
+            Ok(resp)
+        })
+        .await
     }
 
     /// Perform an action inside of a DB transaction. This method will rollback
@@ -177,17 +188,36 @@ impl DbTransactionPool {
             }
         };
 
-        let (resp, db) = self
-            .transaction_internal(request.clone(), check_precondition)
-            .await?;
-        // match on error and return a composed HttpResponse (so we can use the tags?)
+        telemetry::in_span("db.transaction", async move {
+            let _in_flight = self.transactions.begin();
+            let (resp, db) = self
+                .transaction_internal(request.clone(), check_precondition)
+                .await?;
+            // match on error and return a composed HttpResponse (so we can use the tags?)
 
-        // HttpResponse can contain an internal error
-        match resp.error() {
-            None => db.commit().await?,
-            Some(_) => db.rollback().await?,
-        };
-        Ok(resp)
+            // HttpResponse can contain an internal error
+            match resp.error() {
+                None => {
+                    db.commit().await?;
+
+                    if resp.status().is_success() && resp.extensions().contains::<StorageChanged>()
+                    {
+                        self.publish_change();
+                    }
+                }
+                Some(_) => db.rollback().await?,
+            };
+            Ok(resp)
+        })
+        .await
+    }
+
+    /// Notify anyone waiting on changes to the user's storage of a committed write
+    fn publish_change(&self) {
+        self.changes.publish(Change {
+            user_id: self.user_id.legacy_id,
+            collection: self.collection.clone(),
+        });
     }
 
     /// Create a lock collection if there is a collection to lock
@@ -201,6 +231,16 @@ impl DbTransactionPool {
     }
 }
 
+/// Marks the response to a write that bumped a collection or storage timestamp, so that
+/// `transaction_http` publishes the change once it's committed.
+pub struct StorageChanged;
+
+/// Mark `resp` as the response to a write that changed the user's storage.
+pub fn storage_changed(mut resp: HttpResponse) -> HttpResponse {
+    resp.extensions_mut().insert(StorageChanged);
+    resp
+}
+
 impl FromRequest for DbTransactionPool {
     type Error = Error;
     type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
@@ -213,7 +253,7 @@ impl FromRequest for DbTransactionPool {
         }
 
         let req = req.clone();
-        async move {
+        telemetry::in_span("extract.db_transaction_pool", async move {
             let no_agent = HeaderValue::from_str("NONE")
                 .expect("Could not get no_agent in DbTransactionPool::from_request");
             let useragent = req
@@ -257,11 +297,13 @@ impl FromRequest for DbTransactionPool {
                 collection,
                 bso_opt,
                 precondition,
+                changes: Arc::clone(&state.changes),
+                transactions: Arc::clone(&state.transactions),
             };
 
             req.extensions_mut().insert(pool.clone());
             Ok(pool)
-        }
+        })
         .boxed_local()
     }
 }
@@ -25,7 +25,7 @@ use syncserver_common::{from_error, impl_fmt_display, ReportableError};
 use syncserver_db_common::error::DbError;
 use thiserror::Error;
 
-use crate::web::error::{HawkError, ValidationError};
+use crate::web::error::{HawkError, HawkErrorKind, ValidationError};
 use std::error::Error;
 
 /// Legacy Sync 1.1 error codes, which Sync 1.5 also returns by replacing the descriptive JSON
@@ -100,6 +100,7 @@ impl ApiError {
         match &self.kind {
             ApiErrorKind::Validation(ver) => ver.weave_error_code(),
             ApiErrorKind::Db(dber) if dber.is_quota() => WeaveError::OverQuota,
+            ApiErrorKind::Db(dber) if dber.is_batch_too_large() => WeaveError::SizeLimitExceeded,
             _ => WeaveError::UnknownError,
         }
     }
@@ -131,6 +132,10 @@ impl ApiError {
         matches!(&self.kind, ApiErrorKind::Db(dbe) if dbe.is_quota())
     }
 
+    pub fn is_batch_too_large(&self) -> bool {
+        matches!(&self.kind, ApiErrorKind::Db(dbe) if dbe.is_batch_too_large())
+    }
+
     pub fn is_bso_not_found(&self) -> bool {
         matches!(&self.kind, ApiErrorKind::Db(dbe) if dbe.is_bso_not_found())
     }
@@ -191,6 +196,18 @@ impl ResponseError for ApiError {
         if self.is_conflict() {
             resp.header("Retry-After", RETRY_AFTER.to_string());
         };
+        if let ApiErrorKind::Hawk(ref error) = self.kind {
+            if let HawkErrorKind::StaleTimestamp { ts, tsm } = error.kind() {
+                // Tell the client our time, so it can correct for clock skew
+                resp.header(
+                    "WWW-Authenticate",
+                    format!(
+                        "Hawk ts=\"{}\", tsm=\"{}\", error=\"Stale timestamp\"",
+                        ts, tsm
+                    ),
+                );
+            }
+        }
         resp.json(self.weave_error_code() as i32)
     }
 }
@@ -15,5 +15,6 @@ pub mod error;
 pub mod db;
 pub mod logging;
 pub mod server;
+pub mod telemetry;
 pub mod tokenserver;
 pub mod web;
@@ -8,7 +8,7 @@ use docopt::Docopt;
 use serde::Deserialize;
 
 use logging::init_logging;
-use syncserver::{logging, server};
+use syncserver::{logging, server, telemetry};
 use syncserver_settings::Settings;
 
 const USAGE: &str = "
@@ -31,6 +31,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .unwrap_or_else(|e| e.exit());
     let settings = Settings::with_env_and_config_file(args.flag_config.as_deref())?;
     init_logging(!settings.human_logs).expect("Logging failed to initialize");
+    telemetry::init_tracing(&settings).expect("Tracing failed to initialize");
     debug!("Starting up...");
     // Set SENTRY_DSN environment variable to enable Sentry.
     // Avoid its default reqwest transport for now due to issues w/
@@ -48,16 +49,23 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     // Setup and run the server
     let banner = settings.banner();
-    let server = if !settings.syncstorage.enabled {
-        server::Server::tokenserver_only_with_settings(settings)
+    let (server, shutdown) = if !settings.syncstorage.enabled {
+        let server = server::Server::tokenserver_only_with_settings(settings)
             .await
-            .unwrap()
+            .unwrap();
+        (server, None)
     } else {
-        server::Server::with_settings(settings).await.unwrap()
+        let (server, shutdown) = server::Server::with_settings(settings).await.unwrap();
+        (server, Some(shutdown))
     };
     info!("Server running on {}", banner);
     server.await?;
+    // Wait for the shutdown to finish closing the database pool
+    if let Some(shutdown) = shutdown {
+        let _ = shutdown.await;
+    }
     info!("Server closing");
+    telemetry::shutdown_tracing();
     logging::reset_logging();
 
     Ok(())
@@ -0,0 +1,190 @@
+//! Change notifications for users' storage.
+//!
+//! Every committed write that bumps a collection or storage timestamp publishes a [`Change`]
+//! through the server's [`ChangeFanout`], waking up any `/1.5/{uid}/changes` long-poll requests waiting on that user's
+//! storage. [`BroadcastHub`] only fans changes out within a single process; deployments running
+//! several instances can plug in their own `ChangeFanout` backed by an external pub/sub system.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures::stream::{self, BoxStream, StreamExt};
+use tokio::sync::broadcast::{self, RecvError};
+
+/// The number of changes the in-process hub buffers, per user, for subscribers that fall behind.
+pub const DEFAULT_HUB_CAPACITY: usize = 16;
+
+/// A change to a user's storage.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Change {
+    /// The legacy id of the user whose storage changed.
+    pub user_id: u64,
+    /// The collection that changed, or `None` if any of the user's collections may have changed.
+    pub collection: Option<String>,
+}
+
+/// Distributes change notifications from the requests that make changes to the requests waiting
+/// on them.
+pub trait ChangeFanout: Send + Sync {
+    /// Publishes a change to every subscriber to the changed user's storage.
+    fn publish(&self, change: Change);
+
+    /// Subscribes to changes to the given user's storage. Implementations that may drop changes
+    /// (e.g. because a subscriber fell behind) should yield a `Change` with no collection in their
+    /// place, so the subscriber knows to recheck the user's storage.
+    fn subscribe(&self, user_id: u64) -> BoxStream<'static, Change>;
+}
+
+type Senders = Arc<Mutex<HashMap<u64, broadcast::Sender<Change>>>>;
+
+/// An in-process `ChangeFanout` with a broadcast channel per subscribed user, so that a change
+/// only wakes up the changed user's subscribers.
+pub struct BroadcastHub {
+    capacity: usize,
+    senders: Senders,
+}
+
+impl BroadcastHub {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            senders: Default::default(),
+        }
+    }
+}
+
+impl Default for BroadcastHub {
+    fn default() -> Self {
+        Self::new(DEFAULT_HUB_CAPACITY)
+    }
+}
+
+impl ChangeFanout for BroadcastHub {
+    fn publish(&self, change: Change) {
+        let senders = self.senders.lock().expect("BroadcastHub lock poisoned");
+        if let Some(sender) = senders.get(&change.user_id) {
+            // Sending only fails if nobody is subscribed, in which case there's nobody to notify
+            let _ = sender.send(change);
+        }
+    }
+
+    fn subscribe(&self, user_id: u64) -> BoxStream<'static, Change> {
+        let receiver = self
+            .senders
+            .lock()
+            .expect("BroadcastHub lock poisoned")
+            .entry(user_id)
+            .or_insert_with(|| broadcast::channel(self.capacity).0)
+            .subscribe();
+        let subscription = Subscription {
+            user_id,
+            receiver: Some(receiver),
+            senders: Arc::clone(&self.senders),
+        };
+
+        stream::unfold(subscription, move |mut subscription| async move {
+            let change = match subscription.receiver.as_mut()?.recv().await {
+                Ok(change) => change,
+                Err(RecvError::Lagged(_)) => Change {
+                    user_id,
+                    collection: None,
+                },
+                Err(RecvError::Closed) => return None,
+            };
+            Some((change, subscription))
+        })
+        .boxed()
+    }
+}
+
+/// A subscriber's receiver, which removes its user's channel from the hub once the user's last
+/// subscriber is dropped.
+struct Subscription {
+    user_id: u64,
+    receiver: Option<broadcast::Receiver<Change>>,
+    senders: Senders,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // Drop the receiver under the lock, so that a concurrent subscription isn't lost with
+        // the removed channel
+        let mut senders = self.senders.lock().expect("BroadcastHub lock poisoned");
+        self.receiver.take();
+        if let Some(sender) = senders.get(&self.user_id) {
+            if sender.receiver_count() == 0 {
+                senders.remove(&self.user_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hub_only_delivers_changes_for_subscribed_user() {
+        let hub = BroadcastHub::default();
+        let mut subscription = hub.subscribe(1);
+
+        hub.publish(Change {
+            user_id: 2,
+            collection: Some("bookmarks".to_owned()),
+        });
+        hub.publish(Change {
+            user_id: 1,
+            collection: Some("tabs".to_owned()),
+        });
+
+        assert_eq!(
+            subscription.next().await,
+            Some(Change {
+                user_id: 1,
+                collection: Some("tabs".to_owned()),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hub_reports_dropped_changes() {
+        let hub = BroadcastHub::new(1);
+        let mut subscription = hub.subscribe(1);
+
+        for collection in &["bookmarks", "tabs"] {
+            hub.publish(Change {
+                user_id: 1,
+                collection: Some((*collection).to_owned()),
+            });
+        }
+
+        // The first change was dropped, so the subscriber is told to recheck everything
+        assert_eq!(
+            subscription.next().await,
+            Some(Change {
+                user_id: 1,
+                collection: None,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hub_forgets_users_without_subscribers() {
+        let hub = BroadcastHub::default();
+        let first = hub.subscribe(1);
+        let second = hub.subscribe(1);
+        assert_eq!(hub.senders.lock().unwrap().len(), 1);
+
+        drop(first);
+        assert_eq!(hub.senders.lock().unwrap().len(), 1);
+        drop(second);
+        assert!(hub.senders.lock().unwrap().is_empty());
+
+        // Changes to users nobody's waiting on go nowhere
+        hub.publish(Change {
+            user_id: 1,
+            collection: Some("tabs".to_owned()),
+        });
+        assert!(hub.senders.lock().unwrap().is_empty());
+    }
+}
@@ -1,17 +1,23 @@
 use std::net::UdpSocket;
+use std::panic::RefUnwindSafe;
+use std::sync::Arc;
 use std::time::Instant;
 
 use actix_web::{
     dev::Payload, error::ErrorInternalServerError, web::Data, Error, FromRequest, HttpRequest,
 };
 use cadence::{
-    BufferedUdpMetricSink, Counted, Metric, NopMetricSink, QueuingMetricSink, StatsdClient, Timed,
+    BufferedUdpMetricSink, Counted, Metric, MetricSink, NopMetricSink, QueuingMetricSink,
+    StatsdClient, StatsdClientBuilder, Timed,
 };
 use futures::future;
 use futures::future::Ready;
 
 use crate::error::ApiError;
-use crate::server::ServerState;
+use crate::server::{
+    prometheus::{PrometheusRegistry, PrometheusSink},
+    ServerState,
+};
 use crate::web::tags::Tags;
 
 #[derive(Debug, Clone)]
@@ -189,6 +195,17 @@ pub fn metrics_from_opts(
     label: &str,
     host: Option<&str>,
     port: u16,
+) -> Result<StatsdClient, ApiError> {
+    metrics_with_prometheus(label, host, port, None)
+}
+
+/// Like `metrics_from_opts`, but also records every metric in the Prometheus
+/// registry, when one's supplied.
+pub fn metrics_with_prometheus(
+    label: &str,
+    host: Option<&str>,
+    port: u16,
+    prometheus: Option<Arc<PrometheusRegistry>>,
 ) -> Result<StatsdClient, ApiError> {
     let builder = if let Some(statsd_host) = host {
         let socket = UdpSocket::bind("0.0.0.0:0")?;
@@ -197,9 +214,9 @@ pub fn metrics_from_opts(
         let host = (statsd_host, port);
         let udp_sink = BufferedUdpMetricSink::from(host, socket)?;
         let sink = QueuingMetricSink::from(udp_sink);
-        StatsdClient::builder(label, sink)
+        client_builder(label, sink, prometheus)
     } else {
-        StatsdClient::builder(label, NopMetricSink)
+        client_builder(label, NopMetricSink, prometheus)
     };
     Ok(builder
         .with_error_handler(|err| {
@@ -208,6 +225,20 @@ pub fn metrics_from_opts(
         .build())
 }
 
+fn client_builder<T>(
+    label: &str,
+    sink: T,
+    prometheus: Option<Arc<PrometheusRegistry>>,
+) -> StatsdClientBuilder
+where
+    T: MetricSink + Send + Sync + RefUnwindSafe + 'static,
+{
+    match prometheus {
+        Some(registry) => StatsdClient::builder(label, PrometheusSink::new(registry, sink)),
+        None => StatsdClient::builder(label, sink),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -14,22 +14,30 @@ use cadence::StatsdClient;
 use syncserver_db_common::DbPool;
 use syncserver_settings::Settings;
 use syncstorage_settings::{Deadman, ServerLimits};
-use tokio::sync::RwLock;
+use tokio::sync::{oneshot, RwLock};
 
 use crate::db::{pool_from_settings, spawn_pool_periodic_reporter};
 use crate::error::ApiError;
-use crate::server::metrics::Metrics;
+use crate::server::changes::{BroadcastHub, ChangeFanout};
+use crate::server::{metrics::Metrics, prometheus::PrometheusRegistry, shutdown::InFlight};
 use crate::tokenserver;
-use crate::web::{handlers, middleware};
+use crate::web::{
+    auth::NonceCache,
+    handlers, middleware,
+    middleware::{backoff, ratelimit::RateLimiter},
+};
 
 pub const BSO_ID_REGEX: &str = r"[ -~]{1,64}";
 pub const COLLECTION_ID_REGEX: &str = r"[a-zA-Z0-9._-]{1,32}";
 pub const SYNC_DOCS_URL: &str =
     "https://mozilla-services.readthedocs.io/en/latest/storage/apis-1.5.html";
 const MYSQL_UID_REGEX: &str = r"[0-9]{1,10}";
-const SYNC_VERSION_PATH: &str = "1.5";
+pub const SYNC_VERSION_PATH: &str = "1.5";
 
+pub mod changes;
 pub mod metrics;
+pub mod prometheus;
+pub mod shutdown;
 #[cfg(test)]
 mod test;
 pub mod user_agent;
@@ -53,6 +61,37 @@ pub struct ServerState {
     pub quota_enabled: bool,
 
     pub deadman: Arc<RwLock<Deadman>>,
+
+    /// Fans out notifications of changes to users' storage
+    pub changes: Arc<dyn ChangeFanout>,
+
+    /// The longest time (in seconds) a `/changes` long-poll request may wait
+    pub max_changes_poll_timeout: u64,
+
+    /// The smallest storage or info response body (in bytes) to compress
+    pub response_compression_min_bytes: usize,
+
+    /// Per-user rate limits, when enabled
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+
+    /// Time (in seconds) clients are asked to back off for when under load
+    pub backoff_interval: u32,
+
+    /// Percentage of `backoff_interval` to jitter
+    pub backoff_interval_jitter: u32,
+
+    /// Whether clients are asked to back off while the pool is saturated
+    pub backoff_on_pool_saturation: bool,
+
+    /// Nonces of recent Hawk requests, to reject replays
+    pub hawk_nonces: Arc<NonceCache>,
+
+    /// Fraction of storage requests recorded in the access log
+    pub access_log_sample_rate: f64,
+
+    /// Database transactions in progress, counted to report those aborted
+    /// by a shutdown
+    pub transactions: Arc<InFlight>,
 }
 
 pub fn cfg_path(path: &str) -> String {
@@ -69,21 +108,33 @@ pub struct Server;
 
 #[macro_export]
 macro_rules! build_app {
-    ($syncstorage_state: expr, $tokenserver_state: expr, $secrets: expr, $limits: expr, $cors: expr) => {
+    ($syncstorage_state: expr, $tokenserver_state: expr, $secrets: expr, $limits: expr, $cors: expr, $prometheus: expr) => {
         App::new()
             .data($syncstorage_state)
             .data($tokenserver_state)
             .data($secrets)
+            // Metrics exported by `/__metrics__`, when enabled
+            .data($prometheus)
             // Middleware is applied LIFO
             // These will wrap all outbound responses with matching status codes.
             .wrap(ErrorHandlers::new().handler(StatusCode::NOT_FOUND, ApiError::render_404))
             // These are our wrappers
             .wrap(middleware::weave::WeaveTimestamp::new())
+            .wrap(middleware::backoff::WeaveBackoff::default())
+            // Compresses the response only after its headers are finalized
+            .wrap(middleware::compress::ResponseCompression::new())
+            // Logs the response as sent, after compression
+            .wrap(middleware::access_log::AccessLog::default())
             .wrap(tokenserver::logging::LoggingWrapper::new())
             .wrap(middleware::sentry::SentryWrapper::default())
+            .wrap(middleware::ratelimit::UserRateLimit::default())
             .wrap(middleware::rejectua::RejectUA::default())
             .wrap($cors)
             .wrap_fn(middleware::emit_http_status_with_tokenserver_origin)
+            // Identifies the request to all of the above
+            .wrap(middleware::request_id::RequestIdHeader::default())
+            // Traces the request through all of the above
+            .wrap(middleware::trace::RequestTracing::default())
             .service(
                 web::resource(&cfg_path("/info/collections"))
                     .route(web::get().to(handlers::get_collections)),
@@ -103,6 +154,9 @@ macro_rules! build_app {
             .service(
                 web::resource(&cfg_path("/info/quota")).route(web::get().to(handlers::get_quota)),
             )
+            .service(
+                web::resource(&cfg_path("/changes")).route(web::get().to(handlers::get_changes)),
+            )
             .service(web::resource(&cfg_path("")).route(web::delete().to(handlers::delete_all)))
             .service(
                 web::resource(&cfg_path("/storage")).route(web::delete().to(handlers::delete_all)),
@@ -141,6 +195,8 @@ macro_rules! build_app {
                 web::resource("/1.0/{application}/{version}")
                     .route(web::get().to(tokenserver::handlers::get_tokenserver_result)),
             )
+            // Tokenserver admin API, which is disabled unless an admin secret is configured
+            .configure(tokenserver::admin::configure)
             // Dockerflow
             // Remember to update .::web::middleware::DOCKER_FLOW_ENDPOINTS
             // when applying changes to endpoint names.
@@ -165,6 +221,7 @@ macro_rules! build_app {
                 })),
             )
             .service(web::resource("/__error__").route(web::get().to(handlers::test_error)))
+            .service(web::resource("/__metrics__").route(web::get().to(handlers::metrics)))
             .service(web::resource("/").route(web::get().to(|_: HttpRequest| {
                 HttpResponse::Found()
                     .header(LOCATION, SYNC_DOCS_URL)
@@ -175,10 +232,11 @@ macro_rules! build_app {
 
 #[macro_export]
 macro_rules! build_app_without_syncstorage {
-    ($state: expr, $secrets: expr, $cors: expr) => {
+    ($state: expr, $secrets: expr, $cors: expr, $prometheus: expr) => {
         App::new()
             .data($state)
             .data($secrets)
+            .data($prometheus)
             // Middleware is applied LIFO
             // These will wrap all outbound responses with matching status codes.
             .wrap(ErrorHandlers::new().handler(StatusCode::NOT_FOUND, ApiError::render_404))
@@ -192,10 +250,14 @@ macro_rules! build_app_without_syncstorage {
             // For now, let's be permissive and use NGINX (the wrapping server)
             // for finer grained specification.
             .wrap($cors)
+            .wrap(middleware::request_id::RequestIdHeader::default())
+            .wrap(middleware::trace::RequestTracing::default())
             .service(
                 web::resource("/1.0/{application}/{version}")
                     .route(web::get().to(tokenserver::handlers::get_tokenserver_result)),
             )
+            // Tokenserver admin API, which is disabled unless an admin secret is configured
+            .configure(tokenserver::admin::configure)
             // Dockerflow
             // Remember to update .::web::middleware::DOCKER_FLOW_ENDPOINTS
             // when applying changes to endpoint names.
@@ -223,6 +285,7 @@ macro_rules! build_app_without_syncstorage {
             .service(
                 web::resource("/__error__").route(web::get().to(tokenserver::handlers::test_error)),
             )
+            .service(web::resource("/__metrics__").route(web::get().to(handlers::metrics)))
             .service(web::resource("/").route(web::get().to(|_: HttpRequest| {
                 HttpResponse::Found()
                     .header(LOCATION, SYNC_DOCS_URL)
@@ -232,12 +295,22 @@ macro_rules! build_app_without_syncstorage {
 }
 
 impl Server {
-    pub async fn with_settings(settings: Settings) -> Result<dev::Server, ApiError> {
+    /// Start the server, returning it along with a receiver that resolves
+    /// once it's finished shutting down
+    pub async fn with_settings(
+        settings: Settings,
+    ) -> Result<(dev::Server, oneshot::Receiver<()>), ApiError> {
         let settings_copy = settings.clone();
-        let metrics = metrics::metrics_from_opts(
+        let prometheus = if settings.prometheus_enabled {
+            Some(Arc::new(PrometheusRegistry::new()))
+        } else {
+            None
+        };
+        let metrics = metrics::metrics_with_prometheus(
             &settings.syncstorage.statsd_label,
             settings.statsd_host.as_deref(),
             settings.statsd_port,
+            prometheus.clone(),
         )?;
         let host = settings.host.clone();
         let port = settings.port;
@@ -249,13 +322,41 @@ impl Server {
         let secrets = Arc::new(settings.master_secret);
         let quota_enabled = settings.syncstorage.enable_quota;
         let actix_keep_alive = settings.actix_keep_alive;
+        let changes: Arc<dyn ChangeFanout> = Arc::new(BroadcastHub::default());
+        let max_changes_poll_timeout = settings.syncstorage.max_changes_poll_timeout;
+        let response_compression_min_bytes =
+            settings.syncstorage.response_compression_min_bytes as usize;
+        let rate_limiter = if settings.syncstorage.rate_limits.enabled {
+            Some(Arc::new(RateLimiter::new(
+                settings.syncstorage.rate_limits.clone(),
+            )))
+        } else {
+            None
+        };
+        let backoff_interval = settings.syncstorage.backoff_interval;
+        let backoff_interval_jitter = settings.syncstorage.backoff_interval_jitter;
+        let backoff_on_pool_saturation = settings.syncstorage.backoff_on_pool_saturation;
+        let hawk_nonces = Arc::new(NonceCache::from(&settings.syncstorage));
+        let access_log_sample_rate = settings.syncstorage.access_log_sample_rate;
+        let transactions = Arc::new(InFlight::default());
+        let shutdown_drain_period = Duration::from_secs(settings.syncstorage.shutdown_drain_period);
+        let shutdown_lb_drain_period =
+            Duration::from_secs(settings.syncstorage.shutdown_lb_drain_period);
+        if let Some(path) = settings.syncstorage.maintenance_file.clone() {
+            backoff::spawn_maintenance_watcher(
+                Duration::from_secs(settings.syncstorage.maintenance_check_interval),
+                path,
+                Arc::clone(&deadman),
+            );
+        }
         let tokenserver_state = if settings.tokenserver.enabled {
             let state = tokenserver::ServerState::from_settings(
                 &settings.tokenserver,
-                metrics::metrics_from_opts(
+                metrics::metrics_with_prometheus(
                     &settings.tokenserver.statsd_label,
                     settings.statsd_host.as_deref(),
                     settings.statsd_port,
+                    prometheus.clone(),
                 )?,
             )?;
 
@@ -270,8 +371,16 @@ impl Server {
             None
         };
 
-        spawn_pool_periodic_reporter(Duration::from_secs(10), metrics.clone(), db_pool.clone())?;
+        let pool_reporter = spawn_pool_periodic_reporter(
+            Duration::from_secs(10),
+            metrics.clone(),
+            db_pool.clone(),
+        )?;
 
+        let shutdown_deadman = Arc::clone(&deadman);
+        let shutdown_transactions = Arc::clone(&transactions);
+        let shutdown_db_pool = db_pool.clone();
+        let shutdown_metrics = Metrics::from(&metrics);
         let mut server = HttpServer::new(move || {
             let syncstorage_state = ServerState {
                 db_pool: db_pool.clone(),
@@ -281,6 +390,16 @@ impl Server {
                 port,
                 quota_enabled,
                 deadman: Arc::clone(&deadman),
+                changes: Arc::clone(&changes),
+                max_changes_poll_timeout,
+                response_compression_min_bytes,
+                rate_limiter: rate_limiter.clone(),
+                backoff_interval,
+                backoff_interval_jitter,
+                backoff_on_pool_saturation,
+                hawk_nonces: Arc::clone(&hawk_nonces),
+                access_log_sample_rate,
+                transactions: Arc::clone(&transactions),
             };
 
             build_app!(
@@ -288,7 +407,8 @@ impl Server {
                 tokenserver_state.clone(),
                 Arc::clone(&secrets),
                 limits,
-                build_cors(&settings_copy)
+                build_cors(&settings_copy),
+                prometheus.clone()
             )
         });
 
@@ -299,8 +419,20 @@ impl Server {
         let server = server
             .bind(format!("{}:{}", host, port))
             .expect("Could not get Server in Server::with_settings")
+            // Signals are handled by `shutdown::spawn_shutdown_handler`
+            .disable_signals()
             .run();
-        Ok(server)
+        let shutdown = shutdown::spawn_shutdown_handler(
+            server.clone(),
+            shutdown_drain_period,
+            shutdown_lb_drain_period,
+            shutdown_deadman,
+            shutdown_transactions,
+            shutdown_db_pool,
+            pool_reporter,
+            shutdown_metrics,
+        );
+        Ok((server, shutdown))
     }
 
     pub async fn tokenserver_only_with_settings(
@@ -310,12 +442,18 @@ impl Server {
         let host = settings.host.clone();
         let port = settings.port;
         let secrets = Arc::new(settings.master_secret.clone());
+        let prometheus = if settings.prometheus_enabled {
+            Some(Arc::new(PrometheusRegistry::new()))
+        } else {
+            None
+        };
         let tokenserver_state = tokenserver::ServerState::from_settings(
             &settings.tokenserver,
-            metrics::metrics_from_opts(
+            metrics::metrics_with_prometheus(
                 &settings.tokenserver.statsd_label,
                 settings.statsd_host.as_deref(),
                 settings.statsd_port,
+                prometheus.clone(),
             )?,
         )?;
 
@@ -329,7 +467,8 @@ impl Server {
             build_app_without_syncstorage!(
                 Some(tokenserver_state.clone()),
                 Arc::clone(&secrets),
-                build_cors(&settings_copy)
+                build_cors(&settings_copy),
+                prometheus.clone()
             )
         });
 
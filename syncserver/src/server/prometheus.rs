@@ -0,0 +1,330 @@
+//! Prometheus exporter for the server's metrics
+//!
+//! All of our instrumentation goes through cadence `StatsdClient`s, so rather
+//! than instrumenting everything twice, the exporter sits behind them as a
+//! `MetricSink`: each metric is parsed from the (DogStatsD tagged) statsd
+//! format and accumulated in a `PrometheusRegistry`, while still being passed
+//! on to statsd when that's configured. The registry is rendered in the
+//! Prometheus text exposition format by the `/__metrics__` endpoint.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use cadence::MetricSink;
+
+/// Upper bounds (in milliseconds) of the buckets that timings are counted in
+const TIMER_BUCKETS: [f64; 12] = [
+    1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0,
+];
+
+/// A metric's tags, sorted by name
+type Labels = Vec<(String, String)>;
+
+#[derive(Debug, PartialEq)]
+enum MetricKind {
+    Counter,
+    Gauge,
+    Timer,
+}
+
+/// A single metric parsed from the statsd format
+#[derive(Debug, PartialEq)]
+struct Sample {
+    name: String,
+    kind: MetricKind,
+    value: f64,
+    labels: Labels,
+}
+
+impl Sample {
+    /// Parse a metric like `syncstorage.request:1|c|@0.5|#tag:value`.
+    ///
+    /// Returns `None` for malformed metrics and those of kinds we don't
+    /// export (sets).
+    fn parse(metric: &str) -> Option<Self> {
+        let mut parts = metric.trim_end().split('|');
+        let (name, value) = parts.next()?.split_once(':')?;
+        let mut value: f64 = value.parse().ok()?;
+        let kind = match parts.next()? {
+            "c" | "m" => MetricKind::Counter,
+            "g" => MetricKind::Gauge,
+            "ms" | "h" | "d" => MetricKind::Timer,
+            _ => return None,
+        };
+
+        let mut labels = Labels::new();
+        for part in parts {
+            if let Some(rate) = part.strip_prefix('@') {
+                // Counters are only sent for a sample of events
+                let rate: f64 = rate.parse().ok()?;
+                if kind == MetricKind::Counter && rate > 0.0 {
+                    value /= rate;
+                }
+            } else if let Some(tags) = part.strip_prefix('#') {
+                for tag in tags.split(',').filter(|tag| !tag.is_empty()) {
+                    let (key, value) = tag.split_once(':').unwrap_or((tag, ""));
+                    labels.push((sanitize(key), value.to_owned()));
+                }
+            }
+        }
+        labels.sort();
+        labels.dedup_by(|a, b| a.0 == b.0);
+
+        Some(Self {
+            name: sanitize(name),
+            kind,
+            value,
+            labels,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Non-cumulative counts for each of the `TIMER_BUCKETS`
+    buckets: [u64; TIMER_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+#[derive(Default)]
+struct Families {
+    counters: BTreeMap<String, BTreeMap<Labels, f64>>,
+    gauges: BTreeMap<String, BTreeMap<Labels, f64>>,
+    timers: BTreeMap<String, BTreeMap<Labels, Histogram>>,
+}
+
+/// Accumulates the metrics sent through a `PrometheusSink`.
+#[derive(Default)]
+pub struct PrometheusRegistry {
+    families: Mutex<Families>,
+}
+
+impl PrometheusRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a metric in the statsd format.
+    fn record(&self, metric: &str) {
+        let sample = match Sample::parse(metric) {
+            Some(sample) => sample,
+            None => {
+                trace!("Not exporting metric to Prometheus: {:?}", metric);
+                return;
+            }
+        };
+
+        let mut families = self
+            .families
+            .lock()
+            .expect("PrometheusRegistry lock poisoned");
+        match sample.kind {
+            MetricKind::Counter => {
+                *families
+                    .counters
+                    .entry(sample.name)
+                    .or_default()
+                    .entry(sample.labels)
+                    .or_default() += sample.value;
+            }
+            MetricKind::Gauge => {
+                families
+                    .gauges
+                    .entry(sample.name)
+                    .or_default()
+                    .insert(sample.labels, sample.value);
+            }
+            MetricKind::Timer => {
+                let histogram = families
+                    .timers
+                    .entry(sample.name)
+                    .or_default()
+                    .entry(sample.labels)
+                    .or_default();
+                if let Some(bucket) = TIMER_BUCKETS.iter().position(|le| sample.value <= *le) {
+                    histogram.buckets[bucket] += 1;
+                }
+                histogram.count += 1;
+                histogram.sum += sample.value;
+            }
+        }
+    }
+
+    /// Render every metric recorded so far in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let families = self
+            .families
+            .lock()
+            .expect("PrometheusRegistry lock poisoned");
+        let mut out = String::new();
+
+        for (name, series) in &families.counters {
+            let name = format!("{}_total", name);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (labels, value) in series {
+                let _ = writeln!(out, "{}{} {}", name, format_labels(labels, None), value);
+            }
+        }
+
+        for (name, series) in &families.gauges {
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for (labels, value) in series {
+                let _ = writeln!(out, "{}{} {}", name, format_labels(labels, None), value);
+            }
+        }
+
+        for (name, series) in &families.timers {
+            let name = format!("{}_milliseconds", name);
+            let _ = writeln!(out, "# TYPE {} histogram", name);
+            for (labels, histogram) in series {
+                let mut cumulative = 0;
+                for (le, count) in TIMER_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                    cumulative += count;
+                    let le = le.to_string();
+                    let _ = writeln!(
+                        out,
+                        "{}_bucket{} {}",
+                        name,
+                        format_labels(labels, Some(&le)),
+                        cumulative
+                    );
+                }
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    name,
+                    format_labels(labels, Some("+Inf")),
+                    histogram.count
+                );
+                let labels = format_labels(labels, None);
+                let _ = writeln!(out, "{}_sum{} {}", name, labels, histogram.sum);
+                let _ = writeln!(out, "{}_count{} {}", name, labels, histogram.count);
+            }
+        }
+
+        out
+    }
+}
+
+/// Replace the characters that aren't valid in Prometheus metric and label
+/// names (e.g. the `.` separators used in statsd)
+fn sanitize(name: &str) -> String {
+    let mut name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+fn format_labels(labels: &[(String, String)], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+/// A `MetricSink` that records every metric in a `PrometheusRegistry` before
+/// passing it on to another sink.
+pub struct PrometheusSink<T> {
+    registry: Arc<PrometheusRegistry>,
+    inner: T,
+}
+
+impl<T> PrometheusSink<T> {
+    pub fn new(registry: Arc<PrometheusRegistry>, inner: T) -> Self {
+        Self { registry, inner }
+    }
+}
+
+impl<T: MetricSink> MetricSink for PrometheusSink<T> {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        self.registry.record(metric);
+        self.inner.emit(metric)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cadence::{Counted, Gauged, NopMetricSink, StatsdClient, Timed};
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Sample::parse("syncstorage.request.error.hawk.expired:1|c|@0.5|#uri.method:GET"),
+            Some(Sample {
+                name: "syncstorage_request_error_hawk_expired".to_owned(),
+                kind: MetricKind::Counter,
+                value: 2.0,
+                labels: vec![("uri_method".to_owned(), "GET".to_owned())],
+            })
+        );
+        assert_eq!(
+            Sample::parse("syncstorage.storage.pool.connections.idle:3|g"),
+            Some(Sample {
+                name: "syncstorage_storage_pool_connections_idle".to_owned(),
+                kind: MetricKind::Gauge,
+                value: 3.0,
+                labels: vec![],
+            })
+        );
+        assert_eq!(Sample::parse("syncstorage.users:42|s"), None);
+        assert_eq!(Sample::parse("syncstorage.request"), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let registry = Arc::new(PrometheusRegistry::new());
+        let client = StatsdClient::from_sink(
+            "syncstorage",
+            PrometheusSink::new(Arc::clone(&registry), NopMetricSink),
+        );
+        client
+            .count_with_tags("storage.backoff", 1)
+            .with_tag("reason", "maintenance")
+            .try_send()
+            .unwrap();
+        client
+            .count_with_tags("storage.backoff", 2)
+            .with_tag("reason", "maintenance")
+            .try_send()
+            .unwrap();
+        client.gauge("storage.pool.connections.active", 5).unwrap();
+        client.gauge("storage.pool.connections.active", 4).unwrap();
+        client.time("storage.request", 20).unwrap();
+
+        let rendered = registry.render();
+        assert!(rendered.contains("# TYPE syncstorage_storage_backoff_total counter\n"));
+        assert!(rendered.contains("syncstorage_storage_backoff_total{reason=\"maintenance\"} 3\n"));
+        assert!(rendered.contains("syncstorage_storage_pool_connections_active 4\n"));
+        assert!(rendered.contains(
+            "syncstorage_storage_request_milliseconds_bucket{le=\"10\"} 0\n\
+             syncstorage_storage_request_milliseconds_bucket{le=\"25\"} 1\n"
+        ));
+        assert!(
+            rendered.contains("syncstorage_storage_request_milliseconds_bucket{le=\"+Inf\"} 1\n")
+        );
+        assert!(rendered.contains("syncstorage_storage_request_milliseconds_sum 20\n"));
+        assert!(rendered.contains("syncstorage_storage_request_milliseconds_count 1\n"));
+    }
+}
@@ -0,0 +1,165 @@
+//! Graceful shutdown
+//!
+//! On SIGTERM the server fails its `/__lbheartbeat__` health check, so load
+//! balancers drain traffic from it, and keeps serving for at least
+//! `shutdown_lb_drain_period` seconds, then until its in-flight transactions
+//! (e.g. batch commits) have finished, for at most `shutdown_drain_period`
+//! seconds in all. It then stops, counting any transactions it aborts, and
+//! closes its database pool once every connection's been returned to it.
+//! SIGINT stops the server immediately.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::dev::Server;
+use futures::future::AbortHandle;
+use syncserver_db_common::{DbPool, GetPoolState, PoolState};
+use syncstorage_settings::Deadman;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{oneshot, RwLock},
+    time::delay_for,
+};
+
+use crate::server::metrics::Metrics;
+
+/// How often to check whether in-flight transactions have finished, and
+/// connections been returned, while shutting down
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Counts the database transactions in progress.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    count: AtomicUsize,
+}
+
+impl InFlight {
+    /// Record a transaction as in progress until the returned guard is
+    /// dropped.
+    pub fn begin(self: &Arc<Self>) -> InFlightGuard {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(Arc::clone(self))
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+}
+
+pub struct InFlightGuard(Arc<InFlight>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Handle SIGTERM (and SIGINT) by shutting the server down, in place of
+/// actix's own signal handling.
+///
+/// The returned receiver resolves once the shutdown has finished, which is
+/// after the server itself has stopped.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_shutdown_handler(
+    server: Server,
+    drain_period: Duration,
+    lb_drain_period: Duration,
+    deadman: Arc<RwLock<Deadman>>,
+    transactions: Arc<InFlight>,
+    db_pool: Box<dyn DbPool>,
+    pool_reporter: AbortHandle,
+    metrics: Metrics,
+) -> oneshot::Receiver<()> {
+    let (done, finished) = oneshot::channel();
+    actix_rt::spawn(async move {
+        let (mut sigterm, mut sigint) = match (
+            signal(SignalKind::terminate()),
+            signal(SignalKind::interrupt()),
+        ) {
+            (Ok(sigterm), Ok(sigint)) => (sigterm, sigint),
+            (Err(e), _) | (_, Err(e)) => {
+                // actix's own signal handling is disabled, so the server
+                // couldn't be stopped gracefully: don't leave it running
+                error!("⚠️ Couldn't listen for shutdown signals, stopping: {:?}", e);
+                server.stop(true).await;
+                return;
+            }
+        };
+        tokio::select! {
+            _ = sigterm.recv() => (),
+            _ = sigint.recv() => {
+                info!("SIGINT received, stopping");
+                server.stop(false).await;
+                return;
+            }
+        }
+
+        info!("SIGTERM received, draining for up to {:?}", drain_period);
+        metrics.incr("storage.shutdown");
+        deadman.write().await.shutting_down = true;
+        let start = Instant::now();
+        let deadline = start + drain_period;
+        // Keep serving until load balancers have noticed the failing health
+        // check, however soon the transactions finish
+        let lb_deadline = start + lb_drain_period;
+        while (Instant::now() < lb_deadline || transactions.count() > 0)
+            && Instant::now() < deadline
+        {
+            delay_for(SHUTDOWN_POLL_INTERVAL).await;
+        }
+
+        let aborted = transactions.count();
+        if aborted > 0 {
+            warn!("Aborting {} in-flight transactions", aborted);
+        }
+        metrics.count("storage.shutdown.aborted", aborted as i64);
+        server.stop(false).await;
+
+        // The stopped workers drop their requests, returning their
+        // connections to the pool, which closes them once its last clone's
+        // dropped
+        pool_reporter.abort();
+        let deadline = Instant::now() + drain_period;
+        let mut state = db_pool.state();
+        while state.connections > state.idle_connections && Instant::now() < deadline {
+            delay_for(SHUTDOWN_POLL_INTERVAL).await;
+            state = db_pool.state();
+        }
+        drop(db_pool);
+        let PoolState {
+            connections,
+            idle_connections,
+        } = state;
+        if connections > idle_connections {
+            warn!(
+                "Gave up waiting for database connections to be returned";
+                "connections" => connections,
+                "idle_connections" => idle_connections
+            );
+        } else {
+            info!("Closed the database pool"; "connections" => connections);
+            metrics.incr("storage.shutdown.pool_closed");
+        }
+        // Let main exit
+        let _ = done.send(());
+    });
+    finished
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_flight() {
+        let transactions = Arc::new(InFlight::default());
+        let first = transactions.begin();
+        {
+            let _second = transactions.begin();
+            assert_eq!(transactions.count(), 2);
+        }
+        assert_eq!(transactions.count(), 1);
+        drop(first);
+        assert_eq!(transactions.count(), 0);
+    }
+}
@@ -15,7 +15,7 @@ use rand::{thread_rng, Rng};
 use serde::de::DeserializeOwned;
 use serde_json::json;
 use sha2::Sha256;
-use syncserver_common::{self, X_LAST_MODIFIED};
+use syncserver_common::{self, X_LAST_MODIFIED, X_REQUEST_ID};
 use syncserver_db_common::{
     params,
     results::{DeleteBso, GetBso, PostBsos, PutBso},
@@ -28,7 +28,10 @@ use super::*;
 use crate::build_app;
 use crate::db::pool_from_settings;
 use crate::tokenserver;
-use crate::web::{auth::HawkPayload, extractors::BsoBody};
+use crate::web::{
+    auth::{HawkPayload, NonceCache},
+    extractors::BsoBody,
+};
 
 lazy_static! {
     static ref SERVER_LIMITS: Arc<ServerLimits> = Arc::new(ServerLimits::default());
@@ -76,6 +79,17 @@ async fn get_test_state(settings: &Settings) -> ServerState {
         port: settings.port,
         quota_enabled: settings.syncstorage.enable_quota,
         deadman: Arc::new(RwLock::new(Deadman::from(&settings.syncstorage))),
+        changes: Arc::new(BroadcastHub::default()),
+        max_changes_poll_timeout: settings.syncstorage.max_changes_poll_timeout,
+        response_compression_min_bytes: settings.syncstorage.response_compression_min_bytes
+            as usize,
+        rate_limiter: None,
+        backoff_interval: settings.syncstorage.backoff_interval,
+        backoff_interval_jitter: settings.syncstorage.backoff_interval_jitter,
+        backoff_on_pool_saturation: settings.syncstorage.backoff_on_pool_saturation,
+        hawk_nonces: Arc::new(NonceCache::from(&settings.syncstorage)),
+        access_log_sample_rate: settings.syncstorage.access_log_sample_rate,
+        transactions: Arc::new(InFlight::default()),
     }
 }
 
@@ -95,7 +109,8 @@ macro_rules! init_app {
                 None::<tokenserver::ServerState>,
                 Arc::clone(&SECRETS),
                 limits,
-                build_cors(&$settings)
+                build_cors(&$settings),
+                None::<Arc<PrometheusRegistry>>
             ))
             .await
         }
@@ -148,6 +163,7 @@ fn create_hawk_header(method: &str, port: u16, path: &str) -> String {
         fxa_kid: format!("xxx_test_kid_{}", *RAND_UID),
         device_id: "xxx_test".to_owned(),
         tokenserver_origin: Default::default(),
+        secret_index: 0,
     };
     let payload =
         serde_json::to_string(&payload).expect("Could not get payload in create_hawk_header");
@@ -212,7 +228,8 @@ where
         None::<tokenserver::ServerState>,
         Arc::clone(&SECRETS),
         limits,
-        build_cors(&settings)
+        build_cors(&settings),
+        None::<Arc<PrometheusRegistry>>
     ))
     .await;
 
@@ -253,7 +270,8 @@ async fn test_endpoint_with_body(
         None::<tokenserver::ServerState>,
         Arc::clone(&SECRETS),
         limits,
-        build_cors(&settings)
+        build_cors(&settings),
+        None::<Arc<PrometheusRegistry>>
     ))
     .await;
     let req = create_request(method, path, None, Some(body)).to_request();
@@ -276,6 +294,98 @@ async fn collections() {
     .await;
 }
 
+#[actix_rt::test]
+async fn changes_times_out_without_changes() {
+    test_endpoint(
+        http::Method::GET,
+        "/1.5/42/changes?timeout=0",
+        Some(StatusCode::NOT_MODIFIED),
+        None,
+    )
+    .await;
+}
+
+#[actix_rt::test]
+async fn changes_wakes_on_committed_write() {
+    let mut settings = get_test_settings();
+    // persist the db across requests, so the write commits
+    settings.syncstorage.database_use_test_transactions = false;
+    let mut app = init_app!(settings).await;
+
+    // Clear out any data that's already in the store.
+    let req = create_request(http::Method::DELETE, "/1.5/42/storage", None, None).to_request();
+    let resp = app.call(req).await.unwrap();
+    assert!(resp.response().status().is_success());
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/changes?timeout=10", None, None).to_request();
+    let changes = app.call(req);
+    let req = create_request(
+        http::Method::PUT,
+        "/1.5/42/storage/xxx_col_changes/12345",
+        None,
+        Some(json!({"payload": "foo"})),
+    )
+    .to_request();
+    let write = async {
+        // Give the long-poll request the chance to start waiting first
+        actix_rt::time::delay_for(Duration::from_millis(100)).await;
+        app.call(req).await.unwrap()
+    };
+    let (changes, write) = futures::future::join(changes, write).await;
+    assert_eq!(write.status(), StatusCode::OK);
+
+    let changes = changes.unwrap();
+    assert_eq!(changes.status(), StatusCode::OK);
+    let changed: HashMap<String, SyncTimestamp> =
+        serde_json::from_slice(&test::read_body(changes).await).unwrap();
+    assert_eq!(changed.keys().collect::<Vec<_>>(), vec!["xxx_col_changes"]);
+
+    // Delete any persisted data
+    let req = create_request(http::Method::DELETE, "/1.5/42/storage", None, None).to_request();
+    let resp = app.call(req).await.unwrap();
+    assert!(resp.response().status().is_success());
+}
+
+#[actix_rt::test]
+async fn changes_ignores_batch_appends() {
+    let mut settings = get_test_settings();
+    // persist the db across requests, so the batch append commits
+    settings.syncstorage.database_use_test_transactions = false;
+    let mut app = init_app!(settings).await;
+
+    // Clear out any data that's already in the store.
+    let req = create_request(http::Method::DELETE, "/1.5/42/storage", None, None).to_request();
+    let resp = app.call(req).await.unwrap();
+    assert!(resp.response().status().is_success());
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/changes?timeout=1", None, None).to_request();
+    let changes = app.call(req);
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/xxx_col_changes?batch=true",
+        None,
+        Some(json!([{"id": "123", "payload": "xxx"}])),
+    )
+    .to_request();
+    let write = async {
+        // Give the long-poll request the chance to start waiting first
+        actix_rt::time::delay_for(Duration::from_millis(100)).await;
+        app.call(req).await.unwrap()
+    };
+    let (changes, write) = futures::future::join(changes, write).await;
+    assert_eq!(write.status(), StatusCode::ACCEPTED);
+
+    // Appending to a batch doesn't change any collection
+    assert_eq!(changes.unwrap().status(), StatusCode::NOT_MODIFIED);
+
+    // Delete any persisted data
+    let req = create_request(http::Method::DELETE, "/1.5/42/storage", None, None).to_request();
+    let resp = app.call(req).await.unwrap();
+    assert!(resp.response().status().is_success());
+}
+
 #[actix_rt::test]
 async fn collection_counts() {
     test_endpoint(
@@ -432,6 +542,55 @@ async fn get_bso() {
     .await;
 }
 
+#[actix_rt::test]
+async fn get_collection_compressed() {
+    use std::io::Read;
+
+    let mut settings = get_test_settings();
+    settings.syncstorage.response_compression_min_bytes = 0;
+    let mut app = init_app!(settings).await;
+
+    let mut headers = HashMap::new();
+    headers.insert("Accept-Encoding", "gzip".to_owned());
+    let req = create_request(
+        http::Method::GET,
+        "/1.5/42/storage/bookmarks",
+        Some(headers),
+        None,
+    )
+    .to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+    assert_eq!(
+        sresp.headers().get(http::header::CONTENT_ENCODING).unwrap(),
+        "gzip"
+    );
+    assert_eq!(
+        sresp.headers().get(http::header::VARY).unwrap(),
+        "Accept-Encoding"
+    );
+
+    let body = test::read_body(sresp).await;
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(&body[..])
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, "[]");
+
+    // Uncompressed responses vary on the request's encodings too
+    let req =
+        create_request(http::Method::GET, "/1.5/42/storage/bookmarks", None, None).to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert!(sresp
+        .headers()
+        .get(http::header::CONTENT_ENCODING)
+        .is_none());
+    assert_eq!(
+        sresp.headers().get(http::header::VARY).unwrap(),
+        "Accept-Encoding"
+    );
+}
+
 #[actix_rt::test]
 async fn put_bso() {
     let start = SyncTimestamp::default();
@@ -545,6 +704,72 @@ async fn invalid_batch_post() {
     assert_eq!(body, "0");
 }
 
+#[actix_rt::test]
+async fn batch_status_and_abort() {
+    let mut settings = get_test_settings();
+    // persist the db across requests, so the batch outlives the request
+    // creating it
+    settings.syncstorage.database_use_test_transactions = false;
+    let mut app = init_app!(settings).await;
+
+    // Clear out any data that's already in the store.
+    let req = create_request(http::Method::DELETE, "/1.5/42/storage", None, None).to_request();
+    let resp = app.call(req).await.unwrap();
+    assert!(resp.response().status().is_success());
+
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/xxx_col_batch?batch=true",
+        None,
+        Some(json!([{"id": "123", "payload": "xxx"}])),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    let batch = body["batch"].as_str().unwrap().to_owned();
+    let path = format!(
+        "/1.5/42/storage/xxx_col_batch?batch={}",
+        urlencoding::encode(&batch)
+    );
+
+    let req = create_request(http::Method::GET, &path, None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let status: serde_json::Value =
+        serde_json::from_slice(&test::read_body(response).await).unwrap();
+    assert_eq!(status["id"], batch.as_str());
+    assert_eq!(status["count"], 1);
+    assert_eq!(status["total_bytes"], 3);
+
+    // Batches are only queried or aborted by their id
+    for method in &[http::Method::GET, http::Method::DELETE] {
+        let req = create_request(
+            method.clone(),
+            "/1.5/42/storage/xxx_col_batch?batch=true",
+            None,
+            None,
+        )
+        .to_request();
+        let response = app.call(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    let req = create_request(http::Method::DELETE, &path, None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    // The aborted batch is gone
+    let req = create_request(http::Method::GET, &path, None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // Delete any persisted data
+    let req = create_request(http::Method::DELETE, "/1.5/42/storage", None, None).to_request();
+    let resp = app.call(req).await.unwrap();
+    assert!(resp.response().status().is_success());
+}
+
 #[actix_rt::test]
 async fn accept_new_or_dev_ios() {
     let mut app = init_app!().await;
@@ -633,6 +858,26 @@ async fn reject_old_ios() {
     assert_eq!(body, "0");
 }
 
+#[actix_rt::test]
+async fn replayed_hawk_request() {
+    let mut app = init_app!().await;
+    let path = "/1.5/42/info/collections";
+    let header = create_hawk_header("GET", get_test_settings().port, path);
+    let request = || {
+        test::TestRequest::with_uri(path)
+            .header("Authorization", header.clone())
+            .to_request()
+    };
+
+    let response = app.call(request()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app.call(request()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    // Only stale timestamps are challenged
+    assert!(response.headers().get("WWW-Authenticate").is_none());
+}
+
 #[actix_rt::test]
 async fn info_configuration_xlm() {
     let mut app = init_app!().await;
@@ -790,3 +1035,50 @@ async fn lbheartbeat_ttl_check() {
     let sresp = app.call(lb_req).await.unwrap();
     assert_eq!(sresp.status(), StatusCode::INTERNAL_SERVER_ERROR);
 }
+
+#[actix_rt::test]
+async fn request_id_header() {
+    let mut app = init_app!().await;
+
+    let mut headers: HashMap<&str, String> = HashMap::new();
+    headers.insert("X-Request-Id", "abc-123".to_owned());
+    let req = create_request(http::Method::GET, "/__heartbeat__", Some(headers), None).to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(
+        sresp.headers().get(X_REQUEST_ID).unwrap().to_str().unwrap(),
+        "abc-123"
+    );
+
+    // Generated when the request doesn't have one
+    let req = create_request(http::Method::GET, "/__heartbeat__", None, None).to_request();
+    let sresp = app.call(req).await.unwrap();
+    let request_id = sresp.headers().get(X_REQUEST_ID).unwrap().to_str().unwrap();
+    assert!(uuid::Uuid::parse_str(request_id).is_ok());
+}
+
+#[actix_rt::test]
+async fn lbheartbeat_shutting_down_check() {
+    let settings = get_test_settings();
+    let state = get_test_state(&settings).await;
+    let deadman = Arc::clone(&state.deadman);
+    let limits = Arc::new(settings.syncstorage.limits.clone());
+    let mut app = test::init_service(build_app!(
+        state,
+        None::<tokenserver::ServerState>,
+        Arc::clone(&SECRETS),
+        limits,
+        build_cors(&settings),
+        None::<Arc<PrometheusRegistry>>
+    ))
+    .await;
+
+    let lb_req = create_request(http::Method::GET, "/__lbheartbeat__", None, None).to_request();
+    let sresp = app.call(lb_req).await.unwrap();
+    assert!(sresp.status().is_success());
+
+    deadman.write().await.shutting_down = true;
+
+    let lb_req = create_request(http::Method::GET, "/__lbheartbeat__", None, None).to_request();
+    let sresp = app.call(lb_req).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
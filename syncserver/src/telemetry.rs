@@ -0,0 +1,136 @@
+//! OpenTelemetry distributed tracing
+//!
+//! Spans are recorded for each request, its `DbTransactionPool` and
+//! `BsoBodies` extraction, Hawk validation, the request's database transaction
+//! and each `Db` call within it (syncstorage's and tokenserver's alike), and
+//! exported over OTLP (gRPC) when an `otlp_endpoint` is configured. Without one, the global
+//! tracer is a no-op. Incoming W3C `traceparent` headers continue the
+//! caller's trace.
+use std::future::Future;
+
+use actix_web::http::HeaderMap;
+use opentelemetry::{
+    global::{self, BoxedTracer},
+    propagation::Extractor,
+    sdk::{
+        propagation::TraceContextPropagator,
+        trace::{self as sdktrace, Sampler},
+        Resource,
+    },
+    trace::{FutureExt, StatusCode, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use syncserver_settings::Settings;
+
+use crate::error::{ApiErrorKind, ApiResult};
+
+/// The name our spans are recorded under
+const TRACER_NAME: &str = "syncstorage";
+
+pub fn init_tracing(settings: &Settings) -> ApiResult<()> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let endpoint = match settings.otlp_endpoint.as_deref() {
+        Some(endpoint) => endpoint,
+        None => return Ok(()),
+    };
+    let config = sdktrace::config()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            settings.trace_sample_rate,
+        ))))
+        .with_resource(Resource::new(vec![
+            KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        ]));
+    // Spans are exported one at a time from the simple processor's own
+    // thread, as the batch processor needs an async runtime: opentelemetry's
+    // tokio runtime requires tokio 1.x, which our tokio 0.2 runtime can't host.
+    // Likewise the exporter uses grpcio (as Spanner does) rather than tonic.
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .grpcio()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(config)
+        .install_simple()
+        .map_err(|e| ApiErrorKind::Internal(format!("Couldn't initialize tracing: {}", e)))?;
+    info!("Exporting traces to {}", endpoint);
+    Ok(())
+}
+
+/// Export any spans still buffered.
+pub fn shutdown_tracing() {
+    global::shutdown_tracer_provider();
+}
+
+pub fn tracer() -> BoxedTracer {
+    global::tracer(TRACER_NAME)
+}
+
+/// The trace context propagated in a request's headers, if any.
+pub fn extract_context(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+/// Run `future` in a child span of the current context, marking the span as
+/// errored if the future fails.
+pub async fn in_span<F, T, E>(name: &'static str, future: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+    E: ToString,
+{
+    let cx = Context::current_with_span(tracer().start(name));
+    let result = future.with_context(cx.clone()).await;
+    if let Err(e) = &result {
+        cx.span().set_status(StatusCode::Error, e.to_string());
+    }
+    cx.span().end();
+    result
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use opentelemetry::trace::TraceId;
+
+    use super::*;
+
+    #[test]
+    fn test_extract_traceparent() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("traceparent"),
+            HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        );
+        let cx = extract_context(&headers);
+        let span_context = cx.span().span_context().clone();
+        assert!(span_context.is_remote());
+        assert!(span_context.is_sampled());
+        assert_eq!(
+            span_context.trace_id(),
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap()
+        );
+
+        assert!(!extract_context(&HeaderMap::new())
+            .span()
+            .span_context()
+            .is_valid());
+    }
+}
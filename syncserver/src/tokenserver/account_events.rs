@@ -0,0 +1,454 @@
+//! Processing of the account events FxA publishes to its relying parties, as documented
+//! [here](https://github.com/mozilla/fxa/blob/main/packages/fxa-auth-server/docs/service_notifications.md).
+//!
+//! The following event types are currently supported:
+//!
+//!   * "delete": the account was deleted; we mark their records as retired so they'll be cleaned
+//!     up by our garbage-collection process.
+//!   * "reset": the account password was reset; we update our copy of their generation number to
+//!     disconnect other devices.
+//!   * "passwordChange": the account password was changed; we update our copy of their generation
+//!     number to disconnect other devices.
+use std::{
+    io::{self, BufRead},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use super::db::{
+    models::{Db, DbResult},
+    params,
+};
+
+/// A source of account event messages, such as an SQS queue. Each message body is expected to be
+/// an SNS notification wrapping the event itself.
+#[async_trait(?Send)]
+pub trait EventSource {
+    /// Waits for the next message body. Returns `None` once the source has been exhausted.
+    async fn receive(&mut self) -> io::Result<Option<String>>;
+
+    /// Acknowledges the most recently received message so that it is not delivered again.
+    async fn acknowledge(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An `EventSource` that reads one message body per line, e.g. from a file or stdin. This is
+/// intended to be used as a stand-in for a real queue during local testing.
+pub struct LineEventSource<R: BufRead> {
+    reader: R,
+}
+
+impl<R: BufRead> LineEventSource<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+}
+
+#[async_trait(?Send)]
+impl<R: BufRead> EventSource for LineEventSource<R> {
+    async fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let mut line = String::new();
+
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+
+            // Skip blank lines so hand-written files can be spaced out for readability
+            if !line.trim().is_empty() {
+                return Ok(Some(line.trim_end().to_owned()));
+            }
+        }
+    }
+}
+
+/// An account event, along with the email of the user it applies to.
+#[derive(Debug, Eq, PartialEq)]
+pub enum AccountEvent {
+    Delete { email: String },
+    Reset { email: String, generation: i64 },
+    PasswordChange { email: String, generation: i64 },
+    Unknown { event_type: String },
+}
+
+impl AccountEvent {
+    /// Parses an account event from the body of a queue message.
+    pub fn parse(body: &str) -> Result<Self, String> {
+        let notification: Notification =
+            serde_json::from_str(body).map_err(|e| format!("Invalid notification: {}", e))?;
+        let RawEvent {
+            event: event_type,
+            uid,
+            iss,
+            generation,
+        } = serde_json::from_str(&notification.message)
+            .map_err(|e| format!("Invalid event: {}", e))?;
+
+        // Older versions of the fxa-auth-server would send an email-like identifier in the "uid"
+        // field, but that doesn't make sense for any relier other than Tokenserver. Newer versions
+        // send just the raw uid in the "uid" field, and include the domain in a separate "iss"
+        // field.
+        let email = match iss {
+            Some(iss) => format!("{}@{}", uid, iss),
+            None if uid.contains('@') => uid,
+            None => return Err("uid field does not contain issuer info".to_owned()),
+        };
+        let require_generation =
+            || generation.ok_or_else(|| format!("{} event is missing a generation", event_type));
+
+        Ok(match event_type.as_str() {
+            "delete" => AccountEvent::Delete { email },
+            "reset" => AccountEvent::Reset {
+                email,
+                generation: require_generation()?,
+            },
+            "passwordChange" => AccountEvent::PasswordChange {
+                email,
+                generation: require_generation()?,
+            },
+            _ => AccountEvent::Unknown { event_type },
+        })
+    }
+}
+
+/// Parses and processes a single account event. Messages that can't be parsed are logged and
+/// dropped rather than treated as errors, so that junk in the queue can't block the events behind
+/// it. Database errors are returned so that the message can be redelivered.
+pub async fn process_account_event(db: &dyn Db, service_id: i32, body: &str) -> DbResult<()> {
+    let event = match AccountEvent::parse(body) {
+        Ok(event) => event,
+        Err(e) => {
+            error!("Invalid account message: {}", e);
+            return Ok(());
+        }
+    };
+
+    match event {
+        AccountEvent::Delete { email } => {
+            // Mark the user as retired. Actual cleanup is done by a separate process.
+            info!("Processing account delete for {:?}", email);
+            db.replace_users(params::ReplaceUsers {
+                email,
+                service_id,
+                replaced_at: now_millis(),
+            })
+            .await
+        }
+        AccountEvent::Reset { email, generation } => {
+            info!("Processing account reset for {:?}", email);
+            update_generation_number(db, service_id, email, generation).await
+        }
+        AccountEvent::PasswordChange { email, generation } => {
+            info!("Processing password change for {:?}", email);
+            update_generation_number(db, service_id, email, generation).await
+        }
+        AccountEvent::Unknown { event_type } => {
+            warn!("Dropping unknown event type {:?}", event_type);
+            Ok(())
+        }
+    }
+}
+
+/// Update the maximum recorded generation number for the given user.
+///
+/// When the FxA server sends us an update to the user's generation number, we want to update our
+/// high-water-mark in the DB in order to immediately lock out disconnected devices. However, since
+/// we don't know the new value of the client state that goes with it, we can't just record the new
+/// generation number in the DB. If we did, the first device that tried to sync with the new
+/// generation number would appear to have an incorrect client state value, and would be rejected.
+///
+/// Instead, we take advantage of the fact that it's a timestamp, and write it into the DB at one
+/// millisecond less than its current value. This ensures that we lock out any devices with an
+/// older generation number while avoiding errors with client state handling.
+async fn update_generation_number(
+    db: &dyn Db,
+    service_id: i32,
+    email: String,
+    generation: i64,
+) -> DbResult<()> {
+    let users = db
+        .get_users(params::GetUsers {
+            service_id,
+            email: email.clone(),
+        })
+        .await?;
+
+    // The users are ordered by creation time, so the first active user is the current one
+    match users.into_iter().find(|user| user.replaced_at.is_none()) {
+        Some(user) => {
+            db.put_user(params::PutUser {
+                service_id,
+                email,
+                generation: generation - 1,
+                keys_changed_at: user.keys_changed_at,
+            })
+            .await
+        }
+        None => Ok(()),
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+/// The SNS notification wrapping an account event.
+#[derive(Deserialize)]
+struct Notification {
+    #[serde(rename = "Message")]
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct RawEvent {
+    event: String,
+    uid: String,
+    iss: Option<String>,
+    generation: Option<i64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::tokenserver::db::{
+        pool::DbPool,
+        results,
+        test::{db_pool, setup},
+    };
+
+    const EMAIL: &str = "test@example.com";
+
+    fn message_body(event: serde_json::Value) -> String {
+        json!({ "Message": event.to_string() }).to_string()
+    }
+
+    #[test]
+    fn test_parse_account_events() {
+        assert_eq!(
+            AccountEvent::parse(&message_body(json!({
+                "event": "delete",
+                "uid": "test",
+                "iss": "example.com",
+            }))),
+            Ok(AccountEvent::Delete {
+                email: EMAIL.to_owned()
+            })
+        );
+
+        // Legacy events include the issuer in the uid
+        assert_eq!(
+            AccountEvent::parse(&message_body(json!({
+                "event": "delete",
+                "uid": EMAIL,
+            }))),
+            Ok(AccountEvent::Delete {
+                email: EMAIL.to_owned()
+            })
+        );
+
+        assert_eq!(
+            AccountEvent::parse(&message_body(json!({
+                "event": "passwordChange",
+                "uid": "test",
+                "iss": "example.com",
+                "generation": 42,
+            }))),
+            Ok(AccountEvent::PasswordChange {
+                email: EMAIL.to_owned(),
+                generation: 42,
+            })
+        );
+
+        assert_eq!(
+            AccountEvent::parse(&message_body(json!({
+                "event": "party",
+                "uid": "test",
+                "iss": "example.com",
+            }))),
+            Ok(AccountEvent::Unknown {
+                event_type: "party".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_account_events() {
+        assert!(AccountEvent::parse("not json").is_err());
+        assert!(AccountEvent::parse(&json!({ "Message": "not json" }).to_string()).is_err());
+
+        // Missing issuer info
+        assert!(AccountEvent::parse(&message_body(json!({
+            "event": "delete",
+            "uid": "test",
+        })))
+        .is_err());
+
+        // Missing generation
+        assert!(AccountEvent::parse(&message_body(json!({
+            "event": "reset",
+            "uid": "test",
+            "iss": "example.com",
+        })))
+        .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_line_event_source() -> io::Result<()> {
+        let mut source = LineEventSource::new("first\n\n  \nsecond\n".as_bytes());
+
+        assert_eq!(source.receive().await?, Some("first".to_owned()));
+        assert_eq!(source.receive().await?, Some("second".to_owned()));
+        assert_eq!(source.receive().await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_user() -> DbResult<()> {
+        let pool = db_pool().await?;
+        let db = pool.get().await?;
+        let (service_id, node_id) = setup(&*db).await?;
+
+        // Add a user with a record that has already been replaced
+        for replaced_at in &[Some(1), None] {
+            let uid = db
+                .post_user(params::PostUser {
+                    service_id,
+                    node_id,
+                    email: EMAIL.to_owned(),
+                    ..Default::default()
+                })
+                .await?
+                .id;
+
+            if let Some(replaced_at) = replaced_at {
+                db.replace_user(params::ReplaceUser {
+                    uid,
+                    service_id,
+                    replaced_at: *replaced_at,
+                })
+                .await?;
+            }
+        }
+
+        let body = message_body(json!({
+            "event": "delete",
+            "uid": "test",
+            "iss": "example.com",
+        }));
+        process_account_event(&*db, service_id, &body).await?;
+
+        let users = get_users(&*db, service_id).await?;
+        assert_eq!(users.len(), 2);
+        assert!(users.iter().all(|user| user.replaced_at.is_some()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_user_who_is_not_in_the_db() -> DbResult<()> {
+        let pool = db_pool().await?;
+        let db = pool.get().await?;
+        let (service_id, _) = setup(&*db).await?;
+
+        let body = message_body(json!({
+            "event": "delete",
+            "uid": "test",
+            "iss": "example.com",
+        }));
+        process_account_event(&*db, service_id, &body).await?;
+
+        assert!(get_users(&*db, service_id).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reset_and_password_change() -> DbResult<()> {
+        let pool = db_pool().await?;
+        let db = pool.get().await?;
+        let (service_id, node_id) = setup(&*db).await?;
+
+        db.post_user(params::PostUser {
+            service_id,
+            node_id,
+            email: EMAIL.to_owned(),
+            generation: 12,
+            keys_changed_at: Some(10),
+            ..Default::default()
+        })
+        .await?;
+
+        // The generation number is recorded as one less than its new value
+        let body = message_body(json!({
+            "event": "reset",
+            "uid": "test",
+            "iss": "example.com",
+            "generation": 43,
+        }));
+        process_account_event(&*db, service_id, &body).await?;
+
+        let user = get_users(&*db, service_id).await?.remove(0);
+        assert_eq!(user.generation, 42);
+        assert_eq!(user.keys_changed_at, Some(10));
+
+        // The generation number can't move backwards
+        let body = message_body(json!({
+            "event": "passwordChange",
+            "uid": "test",
+            "iss": "example.com",
+            "generation": 20,
+        }));
+        process_account_event(&*db, service_id, &body).await?;
+
+        let user = get_users(&*db, service_id).await?.remove(0);
+        assert_eq!(user.generation, 42);
+
+        let body = message_body(json!({
+            "event": "passwordChange",
+            "uid": "test",
+            "iss": "example.com",
+            "generation": 52,
+        }));
+        process_account_event(&*db, service_id, &body).await?;
+
+        let user = get_users(&*db, service_id).await?.remove(0);
+        assert_eq!(user.generation, 51);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_messages_are_dropped() -> DbResult<()> {
+        let pool = db_pool().await?;
+        let db = pool.get().await?;
+        let (service_id, _) = setup(&*db).await?;
+
+        process_account_event(&*db, service_id, "not json").await?;
+        process_account_event(
+            &*db,
+            service_id,
+            &message_body(json!({ "event": "party", "uid": EMAIL })),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_users(db: &dyn Db, service_id: i32) -> DbResult<results::GetUsers> {
+        db.get_users(params::GetUsers {
+            service_id,
+            email: EMAIL.to_owned(),
+        })
+        .await
+    }
+}
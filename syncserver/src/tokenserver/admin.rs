@@ -0,0 +1,371 @@
+//! An HTTP API that allows operators to inspect and change node assignments.
+//!
+//! The API lives under `/__admin__` and is disabled unless `tokenserver.admin_secret` is set.
+//! Every request must present that secret as a bearer token in the `Authorization` header.
+//! Requests made while the API is disabled are answered with a 404, as if the routes did not
+//! exist.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{
+    dev::Payload,
+    http::{header::AUTHORIZATION, HeaderValue, StatusCode},
+    web::{self, Json, Path, Query},
+    FromRequest, HttpRequest, HttpResponse,
+};
+use futures::future::{self, Ready};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokenserver_common::error::{ErrorLocation, TokenserverError};
+
+use super::{
+    db::{models::Db, params, pool::DbPool, results},
+    extractors::{get_server_state, SYNC_SERVICE_NAME},
+};
+
+/// Registers the admin API's routes.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/__admin__")
+            .route("/nodes", web::get().to(get_nodes))
+            .route("/nodes/{node_id}", web::patch().to(update_node))
+            .route("/users", web::get().to(get_users))
+            .route("/users/{uid}", web::get().to(get_user))
+            .route("/users/{uid}/reassign", web::post().to(reassign_user)),
+    );
+}
+
+/// Extracted from requests that carry the configured admin secret. Handlers take it as their
+/// first argument so that the secret's checked before the rest of the request is extracted, and
+/// only then acquire a database connection from it.
+pub struct AdminAuth {
+    db_pool: Box<dyn DbPool>,
+}
+
+impl AdminAuth {
+    async fn db(&self) -> Result<Box<dyn Db>, TokenserverError> {
+        self.db_pool.get().await.map_err(|e| TokenserverError {
+            context: format!("Couldn't acquire a database connection: {}", e),
+            ..TokenserverError::internal_error()
+        })
+    }
+}
+
+impl FromRequest for AdminAuth {
+    type Config = ();
+    type Error = TokenserverError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        future::ready(get_server_state(req).and_then(|state| {
+            let state = state.as_ref().as_ref();
+            check_admin_secret(
+                state.and_then(|state| state.admin_secret.as_deref()),
+                req.headers().get(AUTHORIZATION),
+            )?;
+
+            // An admin secret is only ever configured along with the rest of the Tokenserver
+            // state
+            state
+                .map(|state| AdminAuth {
+                    db_pool: state.db_pool.clone(),
+                })
+                .ok_or_else(|| not_found("Not found"))
+        }))
+    }
+}
+
+/// Checks the `Authorization` header of a request against the configured admin secret.
+fn check_admin_secret(
+    admin_secret: Option<&str>,
+    authorization_header: Option<&HeaderValue>,
+) -> Result<(), TokenserverError> {
+    let admin_secret = admin_secret.ok_or_else(|| not_found("Not found"))?;
+    let token = authorization_header
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.split_once(' '))
+        .filter(|(auth_type, _)| auth_type.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token)
+        .ok_or_else(|| TokenserverError::unauthorized("Unauthorized".to_owned()))?;
+
+    // Compare digests of the secrets rather than the secrets themselves, so the time taken by
+    // the comparison doesn't reveal how much of the configured secret a caller guessed correctly
+    if Sha256::digest(token.as_bytes()) == Sha256::digest(admin_secret.as_bytes()) {
+        Ok(())
+    } else {
+        Err(TokenserverError::unauthorized("Unauthorized".to_owned()))
+    }
+}
+
+/// The fields of a node that may be changed via the admin API.
+#[derive(Deserialize)]
+pub struct NodeUpdate {
+    pub downed: Option<bool>,
+    pub backoff: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct UsersQuery {
+    pub email: String,
+}
+
+/// Lists the nodes for the Sync service, along with their load and capacity.
+pub async fn get_nodes(auth: AdminAuth) -> Result<HttpResponse, TokenserverError> {
+    let db = auth.db().await?;
+    let service_id = get_service_id(&*db).await?;
+    let nodes = db.get_nodes(params::GetNodes { service_id }).await?;
+
+    Ok(HttpResponse::Ok().json(nodes))
+}
+
+/// Marks a node as downed or backed-off (or clears those flags).
+pub async fn update_node(
+    auth: AdminAuth,
+    node_id: Path<i64>,
+    update: Json<NodeUpdate>,
+) -> Result<HttpResponse, TokenserverError> {
+    let db = auth.db().await?;
+    let service_id = get_service_id(&*db).await?;
+    let node = get_node(&*db, service_id, *node_id).await?;
+
+    db.update_node(params::UpdateNode {
+        service_id,
+        node: node.node.clone(),
+        downed: update.downed.map(i32::from),
+        backoff: update.backoff.map(i32::from),
+        ..Default::default()
+    })
+    .await?;
+    info!(
+        "Tokenserver admin: updated node {} (downed: {:?}, backoff: {:?})",
+        node.node, update.downed, update.backoff
+    );
+
+    Ok(HttpResponse::Ok().json(get_node(&*db, service_id, *node_id).await?))
+}
+
+/// Lists the user records associated with an email address, most recently-created first.
+pub async fn get_users(
+    auth: AdminAuth,
+    query: Query<UsersQuery>,
+) -> Result<HttpResponse, TokenserverError> {
+    let db = auth.db().await?;
+    let service_id = get_service_id(&*db).await?;
+    let users = db
+        .get_users(params::GetUsers {
+            service_id,
+            email: query.into_inner().email,
+        })
+        .await?;
+
+    Ok(HttpResponse::Ok().json(users))
+}
+
+/// Looks up a user record by uid.
+pub async fn get_user(auth: AdminAuth, uid: Path<i64>) -> Result<HttpResponse, TokenserverError> {
+    let db = auth.db().await?;
+    let service_id = get_service_id(&*db).await?;
+
+    Ok(HttpResponse::Ok().json(get_user_by_uid(&*db, service_id, *uid).await?))
+}
+
+/// Forces a user to be reassigned to a new node by marking their current record as replaced. The
+/// next time the user requests a token, a new record will be created for them on the
+/// least-loaded node.
+pub async fn reassign_user(
+    auth: AdminAuth,
+    uid: Path<i64>,
+) -> Result<HttpResponse, TokenserverError> {
+    let db = auth.db().await?;
+    let service_id = get_service_id(&*db).await?;
+    let user = get_user_by_uid(&*db, service_id, *uid).await?;
+
+    if user.replaced_at.is_none() {
+        let replaced_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+
+        db.replace_user(params::ReplaceUser {
+            uid: user.uid,
+            service_id,
+            replaced_at,
+        })
+        .await?;
+        info!(
+            "Tokenserver admin: marked user {} for reassignment",
+            user.uid
+        );
+    }
+
+    Ok(HttpResponse::Ok().json(get_user_by_uid(&*db, service_id, *uid).await?))
+}
+
+async fn get_service_id(db: &dyn Db) -> Result<i32, TokenserverError> {
+    Ok(db
+        .get_service_id(params::GetServiceId {
+            service: SYNC_SERVICE_NAME.to_owned(),
+        })
+        .await?
+        .id)
+}
+
+async fn get_node(
+    db: &dyn Db,
+    service_id: i32,
+    node_id: i64,
+) -> Result<results::GetNode, TokenserverError> {
+    db.get_nodes(params::GetNodes { service_id })
+        .await?
+        .into_iter()
+        .find(|node| node.id == node_id)
+        .ok_or_else(|| not_found("Unknown node"))
+}
+
+async fn get_user_by_uid(
+    db: &dyn Db,
+    service_id: i32,
+    uid: i64,
+) -> Result<results::UserRecord, TokenserverError> {
+    db.get_user_by_uid(params::GetUserByUid { service_id, uid })
+        .await?
+        .ok_or_else(|| not_found("Unknown user"))
+}
+
+fn not_found(description: &str) -> TokenserverError {
+    TokenserverError {
+        status: "not-found",
+        location: ErrorLocation::Url,
+        description: description.to_owned(),
+        http_status: StatusCode::NOT_FOUND,
+        context: description.to_owned(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::{
+        dev::Service,
+        test::{self, TestRequest},
+        App,
+    };
+    use tokenserver_common::NodeType;
+
+    use crate::server::metrics::Metrics;
+    use crate::tokenserver::{
+        auth::MockVerifier, db::mock::MockDbPool as MockTokenserverPool, ServerState,
+    };
+
+    #[test]
+    fn test_admin_api_disabled_without_secret() {
+        let header = HeaderValue::from_static("Bearer secret");
+        let error = check_admin_secret(None, Some(&header)).unwrap_err();
+
+        assert_eq!(error.http_status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_admin_api_requires_secret() {
+        // No Authorization header
+        let error = check_admin_secret(Some("secret"), None).unwrap_err();
+        assert_eq!(
+            error,
+            TokenserverError::unauthorized("Unauthorized".to_owned())
+        );
+
+        // Wrong authorization scheme
+        let header = HeaderValue::from_static("Basic secret");
+        let error = check_admin_secret(Some("secret"), Some(&header)).unwrap_err();
+        assert_eq!(
+            error,
+            TokenserverError::unauthorized("Unauthorized".to_owned())
+        );
+
+        // Wrong secret
+        let header = HeaderValue::from_static("Bearer wrong");
+        let error = check_admin_secret(Some("secret"), Some(&header)).unwrap_err();
+        assert_eq!(
+            error,
+            TokenserverError::unauthorized("Unauthorized".to_owned())
+        );
+
+        // Correct secret
+        let header = HeaderValue::from_static("bearer secret");
+        assert!(check_admin_secret(Some("secret"), Some(&header)).is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_admin_api_http_disabled_without_secret() {
+        let mut app =
+            test::init_service(App::new().data(Some(make_state(None))).configure(configure)).await;
+        let req = TestRequest::get()
+            .uri("/__admin__/nodes")
+            .header("authorization", "Bearer secret")
+            .to_request();
+        let sresp = app.call(req).await.unwrap();
+
+        assert_eq!(sresp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_admin_api_http_requires_secret() {
+        let mut app = test::init_service(
+            App::new()
+                .data(Some(make_state(Some("secret"))))
+                .configure(configure),
+        )
+        .await;
+        let req = TestRequest::get()
+            .uri("/__admin__/nodes")
+            .header("authorization", "Bearer wrong")
+            .to_request();
+        let sresp = app.call(req).await.unwrap();
+        assert_eq!(sresp.status(), StatusCode::UNAUTHORIZED);
+
+        // The secret's checked before the request body is parsed
+        let req = TestRequest::patch()
+            .uri("/__admin__/nodes/1")
+            .header("content-type", "application/json")
+            .set_payload("not json")
+            .to_request();
+        let sresp = app.call(req).await.unwrap();
+        assert_eq!(sresp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_admin_api_http_get_nodes() {
+        let mut app = test::init_service(
+            App::new()
+                .data(Some(make_state(Some("secret"))))
+                .configure(configure),
+        )
+        .await;
+        let req = TestRequest::get()
+            .uri("/__admin__/nodes")
+            .header("authorization", "Bearer secret")
+            .to_request();
+        let sresp = app.call(req).await.unwrap();
+        assert_eq!(sresp.status(), StatusCode::OK);
+
+        let body = test::read_body(sresp).await;
+        assert_eq!(body, "[]");
+    }
+
+    fn make_state(admin_secret: Option<&str>) -> ServerState {
+        ServerState {
+            fxa_email_domain: "test.com".to_owned(),
+            fxa_metrics_hash_secret: "".to_owned(),
+            browserid_verifier: Box::new(MockVerifier::default()),
+            oauth_verifier: Box::new(MockVerifier::default()),
+            db_pool: Box::new(MockTokenserverPool::new()),
+            node_capacity_release_rate: None,
+            node_type: NodeType::default(),
+            metrics: Box::new(Metrics::sink()),
+            token_duration: 3600,
+            admin_secret: admin_secret.map(str::to_owned),
+        }
+    }
+}
@@ -5,11 +5,10 @@ use std::fmt;
 
 use async_trait::async_trait;
 use dyn_clone::{self, DynClone};
-use pyo3::{
-    prelude::{IntoPy, PyErr, PyModule, PyObject, Python},
-    types::IntoPyDict,
-};
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use tokenserver_common::error::TokenserverError;
 
 /// Represents the origin of the token used by Sync clients to access their data.
@@ -38,7 +37,7 @@ impl fmt::Display for TokenserverOrigin {
 }
 
 /// The plaintext needed to build a token.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct MakeTokenPlaintext {
     pub node: String,
     pub fxa_kid: String,
@@ -50,66 +49,68 @@ pub struct MakeTokenPlaintext {
     pub tokenserver_origin: TokenserverOrigin,
 }
 
-impl IntoPy<PyObject> for MakeTokenPlaintext {
-    fn into_py(self, py: Python<'_>) -> PyObject {
-        let dict = [
-            ("node", self.node),
-            ("fxa_kid", self.fxa_kid),
-            ("fxa_uid", self.fxa_uid),
-            ("hashed_device_id", self.hashed_device_id),
-            ("hashed_fxa_uid", self.hashed_fxa_uid),
-            ("tokenserver_origin", self.tokenserver_origin.to_string()),
-        ]
-        .into_py_dict(py);
-
-        // These need to be set separately since they aren't strings, and
-        // Rust doesn't support heterogeneous arrays
-        dict.set_item("expires", self.expires).unwrap();
-        dict.set_item("uid", self.uid).unwrap();
-
-        dict.into()
-    }
+/// The payload of a token, as signed by Tokenserver and decoded by the storage nodes.
+#[derive(Serialize)]
+struct TokenPayload {
+    #[serde(flatten)]
+    plaintext: MakeTokenPlaintext,
+    salt: String,
 }
 
-/// An adapter to the tokenlib Python library.
+/// A native implementation of the token minting performed by the
+/// [tokenlib](https://github.com/mozilla-services/tokenlib) Python library.
 pub struct Tokenlib;
 
 impl Tokenlib {
+    const SIGNING_INFO: &'static [u8] = b"services.mozilla.com/tokenlib/v1/signing";
+    const DERIVE_INFO_PREFIX: &'static str = "services.mozilla.com/tokenlib/v1/derive/";
+
     /// Builds the token and derived secret to be returned by Tokenserver.
     pub fn get_token_and_derived_secret(
         plaintext: MakeTokenPlaintext,
         shared_secret: &str,
     ) -> Result<(String, String), TokenserverError> {
-        Python::with_gil(|py| {
-            // `import tokenlib`
-            let module = PyModule::import(py, "tokenlib").map_err(|e| {
-                e.print_and_set_sys_last_vars(py);
-                e
-            })?;
-            // `kwargs = { 'secret': shared_secret }`
-            let kwargs = [("secret", shared_secret)].into_py_dict(py);
-            // `token = tokenlib.make_token(plaintext, **kwargs)`
-            let token = module
-                .getattr("make_token")?
-                .call((plaintext,), Some(kwargs))
-                .map_err(|e| {
-                    e.print_and_set_sys_last_vars(py);
-                    e
-                })
-                .and_then(|x| x.extract())?;
-            // `derived_secret = tokenlib.get_derived_secret(token, **kwargs)`
-            let derived_secret = module
-                .getattr("get_derived_secret")?
-                .call((&token,), Some(kwargs))
-                .map_err(|e| {
-                    e.print_and_set_sys_last_vars(py);
-                    e
-                })
-                .and_then(|x| x.extract())?;
-            // `return (token, derived_secret)`
-            Ok((token, derived_secret))
-        })
-        .map_err(pyerr_to_tokenserver_error)
+        let salt = hex::encode(rand::thread_rng().gen::<[u8; 3]>());
+        let token = Self::make_token(plaintext, salt.clone(), shared_secret)?;
+        let derived_secret = Self::get_derived_secret(&token, &salt, shared_secret)?;
+
+        Ok((token, derived_secret))
+    }
+
+    /// Serializes the plaintext and appends an HMAC-SHA256 signature keyed with a secret derived
+    /// from the shared secret.
+    fn make_token(
+        plaintext: MakeTokenPlaintext,
+        salt: String,
+        shared_secret: &str,
+    ) -> Result<String, TokenserverError> {
+        let mut token_bytes = serde_json::to_vec(&TokenPayload { plaintext, salt })
+            .map_err(|e| internal_error(format!("Failed to serialize token payload: {}", e)))?;
+        let signing_secret =
+            syncserver_common::hkdf_expand_32(Self::SIGNING_INFO, None, shared_secret.as_bytes())
+                .map_err(internal_error)?;
+        let mut hmac = Hmac::<Sha256>::new_from_slice(&signing_secret)
+            .map_err(|e| internal_error(format!("HMAC Error: {:?}", e)))?;
+        hmac.update(&token_bytes);
+        token_bytes.extend_from_slice(&hmac.finalize().into_bytes());
+
+        Ok(base64::encode_config(&token_bytes, base64::URL_SAFE))
+    }
+
+    /// Derives the secret the client uses to sign its Hawk requests with the given token.
+    fn get_derived_secret(
+        token: &str,
+        salt: &str,
+        shared_secret: &str,
+    ) -> Result<String, TokenserverError> {
+        let derived_secret = syncserver_common::hkdf_expand_32(
+            format!("{}{}", Self::DERIVE_INFO_PREFIX, token).as_bytes(),
+            Some(salt.as_bytes()),
+            shared_secret.as_bytes(),
+        )
+        .map_err(internal_error)?;
+
+        Ok(base64::encode_config(&derived_secret, base64::URL_SAFE))
     }
 }
 
@@ -143,9 +144,9 @@ impl<T: Clone + Send + Sync> VerifyToken for MockVerifier<T> {
     }
 }
 
-fn pyerr_to_tokenserver_error(e: PyErr) -> TokenserverError {
+fn internal_error(context: String) -> TokenserverError {
     TokenserverError {
-        context: e.to_string(),
+        context,
         ..TokenserverError::internal_error()
     }
 }
@@ -1,19 +1,29 @@
-use actix_web::{error::BlockingError, web};
 use async_trait::async_trait;
-use pyo3::{
-    prelude::{Py, PyAny, PyErr, PyModule, Python},
-    types::{IntoPyDict, PyString},
-};
+use jsonwebtoken::{errors::ErrorKind as JwtErrorKind, Algorithm, DecodingKey, Header, Validation};
+use reqwest::{Client as ReqwestClient, StatusCode};
 use serde::{Deserialize, Serialize};
-use serde_json;
 use tokenserver_common::error::TokenserverError;
 use tokenserver_settings::{Jwk, Settings};
 use tokio::time;
 
 use super::VerifyToken;
 
-use core::time::Duration;
-use std::convert::TryFrom;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+/// The scope required for a token to be used to access Sync.
+const SYNC_SCOPE: &str = "https://identity.mozilla.com/apps/oldsync";
+
+/// The JWT `typ` header values that identify an FxA access token.
+const ACCESS_TOKEN_TYPES: [&str; 2] = ["at+jwt", "application/at+jwt"];
+
+/// The initial delay before retrying a failed JWKS refresh. The delay doubles after each
+/// consecutive failure, up to the refresh interval.
+const JWKS_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
 
 /// The information extracted from a valid OAuth token.
 #[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
@@ -23,72 +33,296 @@ pub struct VerifyOutput {
     pub generation: Option<i64>,
 }
 
-/// The verifier used to verify OAuth tokens.
+/// A public key used to verify JWT access tokens locally.
 #[derive(Clone)]
-pub struct Verifier {
-    // Note that we do not need to use an Arc here, since Py is already a reference-counted
-    // pointer
-    inner: Py<PyAny>,
-    timeout: u64,
+struct VerifyingKey(DecodingKey<'static>);
+
+impl VerifyingKey {
+    fn new(kty: &str, alg: &str, n: &str, e: &str) -> Result<Self, &'static str> {
+        if kty != "RSA" {
+            return Err("OAuth JWK must have a key type of \"RSA\"");
+        }
+
+        if alg != "RS256" {
+            return Err("OAuth JWK must use the \"RS256\" algorithm");
+        }
+
+        Ok(Self(DecodingKey::from_rsa_components(n, e).into_static()))
+    }
 }
 
-impl Verifier {
-    const FILENAME: &'static str = "verify.py";
+impl TryFrom<&Jwk> for VerifyingKey {
+    type Error = &'static str;
+
+    fn try_from(jwk: &Jwk) -> Result<Self, Self::Error> {
+        Self::new(&jwk.kty, &jwk.alg, &jwk.n, &jwk.e)
+    }
+}
+
+/// The public keys used to verify JWT access tokens, keyed by key ID.
+#[derive(Default)]
+struct JwkCache {
+    /// The keys configured in the settings. These are never replaced by a refresh.
+    pinned: HashMap<String, VerifyingKey>,
+    /// The keys most recently fetched from FxA's `/v1/jwks` endpoint.
+    fetched: HashMap<String, VerifyingKey>,
+    /// When the keys were last refetched because a token referenced an unknown key ID.
+    last_refetch: Option<Instant>,
+}
+
+impl JwkCache {
+    fn is_empty(&self) -> bool {
+        self.pinned.is_empty() && self.fetched.is_empty()
+    }
+
+    fn get(&self, kid: &str) -> Option<&VerifyingKey> {
+        self.pinned.get(kid).or_else(|| self.fetched.get(kid))
+    }
+
+    fn values(&self) -> impl Iterator<Item = &VerifyingKey> {
+        self.pinned.values().chain(self.fetched.values())
+    }
+}
+
+/// The verifier used to verify OAuth tokens. If any JWKs are configured or have been fetched from
+/// FxA, JWT access tokens are verified locally; otherwise, tokens are verified by making a request
+/// to the FxA OAuth server.
+#[derive(Clone)]
+pub struct Verifier {
+    keys: Arc<RwLock<JwkCache>>,
+    verify_url: String,
+    jwks_url: String,
+    /// How often the JWKs are refreshed from FxA, or `None` if they are never fetched.
+    jwks_refresh_interval: Option<Duration>,
+    /// The minimum time between refetches triggered by unknown key IDs.
+    jwks_refetch_interval: Duration,
+    // reqwest's async client uses an `Arc` internally, so we don't need to use one here to take
+    // advantage of keep-alive connections across threads.
+    request_client: ReqwestClient,
 }
 
 impl TryFrom<&Settings> for Verifier {
-    type Error = TokenserverError;
-
-    fn try_from(settings: &Settings) -> Result<Self, TokenserverError> {
-        let inner: Py<PyAny> = Python::with_gil::<_, Result<Py<PyAny>, PyErr>>(|py| {
-            let code = include_str!("verify.py");
-            let module = PyModule::from_code(py, code, Self::FILENAME, Self::FILENAME)?;
-            let kwargs = {
-                let dict = [("server_url", &settings.fxa_oauth_server_url)].into_py_dict(py);
-                let parse_jwk = |jwk: &Jwk| {
-                    let dict = [
-                        ("kty", &jwk.kty),
-                        ("alg", &jwk.alg),
-                        ("kid", &jwk.kid),
-                        ("use", &jwk.use_of_key),
-                        ("n", &jwk.n),
-                        ("e", &jwk.e),
-                    ]
-                    .into_py_dict(py);
-                    dict.set_item("fxa-createdAt", jwk.fxa_created_at).unwrap();
-
-                    dict
-                };
-
-                let jwks = match (
-                    &settings.fxa_oauth_primary_jwk,
-                    &settings.fxa_oauth_secondary_jwk,
-                ) {
-                    (Some(primary_jwk), Some(secondary_jwk)) => {
-                        Some(vec![parse_jwk(primary_jwk), parse_jwk(secondary_jwk)])
-                    }
-                    (Some(jwk), None) | (None, Some(jwk)) => Some(vec![parse_jwk(jwk)]),
-                    (None, None) => None,
-                };
-                dict.set_item("jwks", jwks).unwrap();
-                dict
-            };
-            let object: Py<PyAny> = module
-                .getattr("FxaOAuthClient")?
-                .call((), Some(kwargs))
-                .map_err(|e| {
-                    e.print_and_set_sys_last_vars(py);
-                    e
-                })?
-                .into();
-
-            Ok(object)
-        })
-        .map_err(super::pyerr_to_tokenserver_error)?;
+    type Error = &'static str;
+
+    fn try_from(settings: &Settings) -> Result<Self, Self::Error> {
+        let pinned = settings
+            .fxa_oauth_primary_jwk
+            .iter()
+            .chain(settings.fxa_oauth_secondary_jwk.iter())
+            .map(|jwk| Ok((jwk.kid.clone(), VerifyingKey::try_from(jwk)?)))
+            .collect::<Result<HashMap<_, _>, Self::Error>>()?;
+        let server_url = settings.fxa_oauth_server_url.trim_end_matches('/');
 
         Ok(Self {
-            inner,
-            timeout: settings.fxa_oauth_request_timeout,
+            keys: Arc::new(RwLock::new(JwkCache {
+                pinned,
+                ..Default::default()
+            })),
+            verify_url: format!("{}/v1/verify", server_url),
+            jwks_url: format!("{}/v1/jwks", server_url),
+            jwks_refresh_interval: Some(settings.fxa_oauth_jwks_refresh_interval)
+                .filter(|&interval| interval > 0)
+                .map(Duration::from_secs),
+            jwks_refetch_interval: Duration::from_secs(settings.fxa_oauth_jwks_refetch_interval),
+            request_client: ReqwestClient::builder()
+                .timeout(Duration::from_secs(settings.fxa_oauth_request_timeout))
+                .use_rustls_tls()
+                .build()
+                .map_err(|_| "failed to build OAuth reqwest client")?,
+        })
+    }
+}
+
+impl Verifier {
+    /// Fetches the JWKs from FxA immediately and then periodically, retrying with exponential
+    /// backoff if a fetch fails. Does nothing if fetching JWKs is disabled.
+    pub fn spawn_jwks_refresher(&self) {
+        let refresh_interval = match self.jwks_refresh_interval {
+            Some(refresh_interval) => refresh_interval,
+            None => return,
+        };
+        let verifier = self.clone();
+
+        tokio::spawn(async move {
+            let mut backoff = JWKS_INITIAL_BACKOFF;
+
+            loop {
+                match verifier.fetch_jwks().await {
+                    Ok(()) => {
+                        backoff = JWKS_INITIAL_BACKOFF;
+                        time::delay_for(refresh_interval).await;
+                    }
+                    Err(e) => {
+                        warn!("Failed to refresh OAuth JWKs: {}", e);
+                        time::delay_for(backoff).await;
+                        backoff = (backoff * 2).min(refresh_interval);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Fetches the current JWKs from FxA, replacing any previously fetched keys.
+    async fn fetch_jwks(&self) -> Result<(), String> {
+        let response = self
+            .request_client
+            .get(&self.jwks_url)
+            .send()
+            .await
+            .map_err(|e| format!("Request error occurred during JWKS request to FxA: {}", e))?;
+
+        if response.status() != StatusCode::OK {
+            return Err(format!(
+                "FxA returned a status code other than 200 ({})",
+                response.status().as_u16()
+            ));
+        }
+
+        let jwks = response
+            .json::<JwksResponse>()
+            .await
+            .map_err(|e| format!("Invalid JWKS response received from FxA: {}", e))?;
+        let fetched = jwks
+            .keys
+            .into_iter()
+            .filter_map(|jwk| {
+                // Keys we can't use to verify access tokens are skipped rather than treated as
+                // errors so that FxA can publish other kinds of keys alongside them
+                VerifyingKey::new(&jwk.kty, &jwk.alg, &jwk.n, &jwk.e)
+                    .ok()
+                    .map(|key| (jwk.kid, key))
+            })
+            .collect();
+
+        self.keys
+            .write()
+            .expect("OAuth JWK cache lock poisoned")
+            .fetched = fetched;
+
+        Ok(())
+    }
+
+    /// Refetches the JWKs if a token references a key ID we don't know about, which happens when
+    /// FxA rotates its keys. Refetches are rate-limited so that tokens with bogus key IDs can't be
+    /// used to flood FxA with requests.
+    async fn refetch_jwks_if_unknown(&self, kid: &str) {
+        if self.jwks_refresh_interval.is_none() {
+            return;
+        }
+
+        let should_refetch = {
+            let mut keys = self.keys.write().expect("OAuth JWK cache lock poisoned");
+
+            if keys.get(kid).is_some()
+                || keys
+                    .last_refetch
+                    .map_or(false, |last| last.elapsed() < self.jwks_refetch_interval)
+            {
+                false
+            } else {
+                keys.last_refetch = Some(Instant::now());
+                true
+            }
+        };
+
+        if should_refetch {
+            if let Err(e) = self.fetch_jwks().await {
+                warn!(
+                    "Failed to refetch OAuth JWKs for unknown key ID {}: {}",
+                    kid, e
+                );
+            }
+        }
+    }
+
+    /// Verifies a JWT access token against the cached JWKs.
+    fn verify_jwt_locally(
+        &self,
+        token: &str,
+        header: &Header,
+    ) -> Result<TokenClaims, TokenserverError> {
+        if header.alg != Algorithm::RS256 {
+            return Err(invalid_token(format!(
+                "Unsupported OAuth JWT algorithm: {:?}",
+                header.alg
+            )));
+        }
+
+        if !header
+            .typ
+            .as_deref()
+            .map_or(false, |typ| ACCESS_TOKEN_TYPES.contains(&typ))
+        {
+            return Err(invalid_token("OAuth JWT is not an access token".to_owned()));
+        }
+
+        let keys = self.keys.read().expect("OAuth JWK cache lock poisoned");
+        // If the token identifies its key, only that key may be used to verify it
+        let candidates: Vec<&VerifyingKey> = match &header.kid {
+            Some(kid) => keys.get(kid).into_iter().collect(),
+            None => keys.values().collect(),
+        };
+        let validation = Validation::new(Algorithm::RS256);
+        let mut result = Err(invalid_token(format!(
+            "Unknown OAuth JWT key ID: {}",
+            header.kid.as_deref().unwrap_or_default()
+        )));
+
+        for key in candidates {
+            match jsonwebtoken::decode::<JwtClaims>(token, &key.0, &validation) {
+                Ok(token_data) => return Ok(token_data.claims.into()),
+                Err(e) => {
+                    result = Err(match e.kind() {
+                        JwtErrorKind::ExpiredSignature => {
+                            invalid_token("Expired OAuth JWT".to_owned())
+                        }
+                        _ => invalid_token(format!("Invalid OAuth JWT: {}", e)),
+                    });
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Verifies a token by making a request to the FxA OAuth server.
+    async fn verify_remotely(&self, token: String) -> Result<TokenClaims, TokenserverError> {
+        let response = self
+            .request_client
+            .post(&self.verify_url)
+            .json(&VerifyRequest { token })
+            .send()
+            .await
+            .map_err(|e| {
+                if e.is_timeout() {
+                    TokenserverError {
+                        context: "OAuth verification timeout".to_owned(),
+                        ..TokenserverError::resource_unavailable()
+                    }
+                } else {
+                    invalid_token(format!(
+                        "Request error occurred during OAuth request to FxA: {}",
+                        e
+                    ))
+                }
+            })?;
+
+        if response.status() != StatusCode::OK {
+            return Err(invalid_token(format!(
+                "FxA returned a status code other than 200 ({})",
+                response.status().as_u16()
+            )));
+        }
+
+        response.json::<TokenClaims>().await.map_err(|e| {
+            if e.is_timeout() {
+                TokenserverError {
+                    context: "OAuth verification timeout".to_owned(),
+                    ..TokenserverError::resource_unavailable()
+                }
+            } else {
+                invalid_token(format!("Invalid OAuth verify output: {}", e))
+            }
         })
     }
 }
@@ -100,74 +334,412 @@ impl VerifyToken for Verifier {
     /// Verifies an OAuth token. Returns `VerifyOutput` for valid tokens and a `TokenserverError`
     /// for invalid tokens.
     async fn verify(&self, token: String) -> Result<VerifyOutput, TokenserverError> {
-        // We don't want to move `self` into the body of the closure here because we'd need to
-        // clone it. Cloning it is only necessary if we need to verify the token remotely via FxA,
-        // since that would require passing `self` to a separate thread. Passing &Self to a closure
-        // gives us the flexibility to clone only when necessary.
-        let verify_inner = |verifier: &Self| {
-            let maybe_verify_output_string = Python::with_gil(|py| {
-                let client = verifier.inner.as_ref(py);
-                // `client.verify_token(token)`
-                let result: &PyAny = client
-                    .getattr("verify_token")?
-                    .call((token,), None)
-                    .map_err(|e| {
-                        e.print_and_set_sys_last_vars(py);
-                        e
-                    })?;
+        let has_keys = !self
+            .keys
+            .read()
+            .expect("OAuth JWK cache lock poisoned")
+            .is_empty();
 
-                if result.is_none() {
-                    Ok(None)
-                } else {
-                    let verify_output_python_string = result.downcast::<PyString>()?;
-                    verify_output_python_string.extract::<String>().map(Some)
+        // Tokens that aren't JWTs can only be verified by FxA
+        let claims = match jsonwebtoken::decode_header(&token) {
+            Ok(header) if has_keys => {
+                if let Some(kid) = &header.kid {
+                    self.refetch_jwks_if_unknown(kid).await;
                 }
-            })
-            .map_err(|e| TokenserverError {
-                context: format!("pyo3 error in OAuth verifier: {}", e),
-                ..TokenserverError::invalid_credentials("Unauthorized".to_owned())
-            })?;
 
-            match maybe_verify_output_string {
-                Some(verify_output_string) => {
-                    serde_json::from_str::<VerifyOutput>(&verify_output_string).map_err(|e| {
-                        TokenserverError {
-                            context: format!("Invalid OAuth verify output: {}", e),
-                            ..TokenserverError::invalid_credentials("Unauthorized".to_owned())
-                        }
-                    })
-                }
-                None => Err(TokenserverError {
-                    context: "Invalid OAuth token".to_owned(),
-                    ..TokenserverError::invalid_credentials("Unauthorized".to_owned())
-                }),
+                self.verify_jwt_locally(&token, &header)?
             }
+            _ => self.verify_remotely(token).await?,
         };
 
-        let verifier = self.clone();
+        if !claims.scope.iter().any(|scope| scope_matches(scope)) {
+            return Err(invalid_token(
+                "OAuth token does not have the Sync scope".to_owned(),
+            ));
+        }
 
-        // If the JWK is not cached or if the token is not a JWT/wasn't signed by a known key
-        // type, PyFxA will make a request to FxA to retrieve it, blocking this thread. To
-        // improve performance, we make the request on a thread in a threadpool specifically
-        // used for blocking operations. The JWK should _always_ be cached in production to
-        // maximize performance.
-        let fut = web::block(move || verify_inner(&verifier));
-
-        // The PyFxA OAuth client does not offer a way to set a request timeout, so we set one here
-        // by timing out the future if the verification process blocks its thread for longer
-        // than the specified number of seconds.
-        time::timeout(Duration::from_secs(self.timeout), fut)
-            .await
-            .map_err(|_| TokenserverError {
-                context: "OAuth verification timeout".to_owned(),
-                ..TokenserverError::resource_unavailable()
-            })?
-            .map_err(|e| match e {
-                BlockingError::Error(inner) => inner,
-                BlockingError::Canceled => TokenserverError {
-                    context: "Tokenserver threadpool operation failed".to_owned(),
-                    ..TokenserverError::internal_error()
-                },
+        Ok(VerifyOutput {
+            fxa_uid: claims.user,
+            generation: claims.generation,
+        })
+    }
+}
+
+/// Returns true if the given scope grants access to Sync. A scope grants access to itself and to
+/// any scope nested beneath it.
+fn scope_matches(scope: &str) -> bool {
+    SYNC_SCOPE.strip_prefix(scope).map_or(false, |rest| {
+        rest.is_empty() || rest.starts_with('/') || rest.starts_with('#')
+    })
+}
+
+fn invalid_token(context: String) -> TokenserverError {
+    TokenserverError {
+        context,
+        ..TokenserverError::invalid_credentials("Unauthorized".to_owned())
+    }
+}
+
+/// The request body sent to the FxA OAuth server's verification endpoint.
+#[derive(Serialize)]
+struct VerifyRequest {
+    token: String,
+}
+
+/// The response body returned by the FxA OAuth server's JWKS endpoint.
+#[derive(Deserialize)]
+struct JwksResponse {
+    keys: Vec<RemoteJwk>,
+}
+
+/// A JWK as published by FxA. Unlike `tokenserver_settings::Jwk`, only the fields needed to verify
+/// tokens are required.
+#[derive(Deserialize)]
+struct RemoteJwk {
+    kty: String,
+    #[serde(default)]
+    alg: String,
+    kid: String,
+    #[serde(default)]
+    n: String,
+    #[serde(default)]
+    e: String,
+}
+
+/// The claims included in the payload of an FxA JWT access token.
+#[derive(Deserialize)]
+struct JwtClaims {
+    sub: String,
+    #[serde(default)]
+    scope: String,
+    #[serde(rename = "fxa-generation")]
+    generation: Option<i64>,
+}
+
+/// The information about a token common to both local and remote verification.
+#[derive(Deserialize)]
+struct TokenClaims {
+    user: String,
+    #[serde(default)]
+    scope: Vec<String>,
+    generation: Option<i64>,
+}
+
+impl From<JwtClaims> for TokenClaims {
+    fn from(claims: JwtClaims) -> Self {
+        Self {
+            user: claims.sub,
+            scope: claims
+                .scope
+                .split_whitespace()
+                .map(ToOwned::to_owned)
+                .collect(),
+            generation: claims.generation,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use jsonwebtoken::EncodingKey;
+    use mockito;
+    use serde_json::json;
+
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const TEST_KID: &str = "20221025-test";
+    const TEST_N: &str = "wUjJC-EU10UB3iGVaRTfm0WNQ7BzgTd4AAqf7op3YfcynShVSMSui_wlme4mguGHvJwENF\
+                          mwG0bbfCIRJ3bJv2_2FT2nMLqNR_HHrsaGu07eyIyxRFNEVtZiTR6UPVI-tS_9Q_Qr1Utc\
+                          aEmt4ZuzxC5Et8GcLyppSI5wpvpVYB0--RYZHegF5NemcNO4y4o1n-fvsdo_FA2gCHc-SW\
+                          HvR_Ui89iMds0ehqt-WWtdPzIiUVnbSVS0M9q4xxcJZWc62ZQxMWuAsCd4PNvAz1kQ356q\
+                          3IGBNMcdQ8WMdEVHWa38qIpkJaNvZT4QaXhkSb5j_66aXSLWctGbZLbnJgc4gw";
+    const TEST_PRIVATE_KEY: &str = include_str!("test_oauth_key.pem");
+
+    fn test_jwk(kid: &str) -> Jwk {
+        Jwk {
+            kty: "RSA".to_owned(),
+            alg: "RS256".to_owned(),
+            kid: kid.to_owned(),
+            fxa_created_at: 1_666_656_000,
+            use_of_key: "sig".to_owned(),
+            n: TEST_N.to_owned(),
+            e: "AQAB".to_owned(),
+        }
+    }
+
+    fn jwks_body(kids: &[&str]) -> serde_json::Value {
+        let keys: Vec<serde_json::Value> = kids
+            .iter()
+            .map(|kid| {
+                json!({
+                    "kty": "RSA",
+                    "alg": "RS256",
+                    "kid": kid,
+                    "use": "sig",
+                    "fxa-createdAt": 1_666_656_000,
+                    "n": TEST_N,
+                    "e": "AQAB",
+                })
             })
+            .collect();
+
+        json!({ "keys": keys })
+    }
+
+    fn make_jwt(kid: &str, typ: &str, claims: serde_json::Value) -> String {
+        let header = Header {
+            typ: Some(typ.to_owned()),
+            kid: Some(kid.to_owned()),
+            ..Header::new(Algorithm::RS256)
+        };
+        let key = EncodingKey::from_rsa_pem(TEST_PRIVATE_KEY.as_bytes()).unwrap();
+
+        jsonwebtoken::encode(&header, &claims, &key).unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[actix_rt::test]
+    async fn test_oauth_verifier_jwt_success() {
+        let verifier = Verifier::try_from(&Settings {
+            fxa_oauth_primary_jwk: Some(test_jwk(TEST_KID)),
+            fxa_oauth_jwks_refresh_interval: 0,
+            ..Default::default()
+        })
+        .unwrap();
+        let token = make_jwt(
+            TEST_KID,
+            "at+jwt",
+            json!({
+                "sub": "test_uid",
+                "scope": "profile https://identity.mozilla.com/apps/oldsync",
+                "fxa-generation": 1234,
+                "exp": now() + 3600,
+            }),
+        );
+
+        let result = verifier.verify(token).await.unwrap();
+        let expected_result = VerifyOutput {
+            fxa_uid: "test_uid".to_owned(),
+            generation: Some(1234),
+        };
+
+        assert_eq!(expected_result, result);
+    }
+
+    #[actix_rt::test]
+    async fn test_oauth_verifier_jwt_failure_cases() {
+        let verifier = Verifier::try_from(&Settings {
+            fxa_oauth_primary_jwk: Some(test_jwk(TEST_KID)),
+            fxa_oauth_jwks_refresh_interval: 0,
+            ..Default::default()
+        })
+        .unwrap();
+        let claims = json!({
+            "sub": "test_uid",
+            "scope": SYNC_SCOPE,
+            "exp": now() + 3600,
+        });
+
+        // Expired token
+        {
+            let token = make_jwt(
+                TEST_KID,
+                "at+jwt",
+                json!({
+                    "sub": "test_uid",
+                    "scope": SYNC_SCOPE,
+                    "exp": now() - 3600,
+                }),
+            );
+            let error = verifier.verify(token).await.unwrap_err();
+            let expected_error = invalid_token("Expired OAuth JWT".to_owned());
+            assert_eq!(expected_error, error);
+        }
+
+        // Token signed with an unknown key
+        {
+            let token = make_jwt("unknown", "at+jwt", claims.clone());
+            let error = verifier.verify(token).await.unwrap_err();
+            let expected_error = invalid_token("Unknown OAuth JWT key ID: unknown".to_owned());
+            assert_eq!(expected_error, error);
+        }
+
+        // Token that isn't an access token
+        {
+            let token = make_jwt(TEST_KID, "JWT", claims.clone());
+            let error = verifier.verify(token).await.unwrap_err();
+            let expected_error = invalid_token("OAuth JWT is not an access token".to_owned());
+            assert_eq!(expected_error, error);
+        }
+
+        // Token without the Sync scope
+        {
+            let token = make_jwt(
+                TEST_KID,
+                "at+jwt",
+                json!({
+                    "sub": "test_uid",
+                    "scope": "profile",
+                    "exp": now() + 3600,
+                }),
+            );
+            let error = verifier.verify(token).await.unwrap_err();
+            let expected_error =
+                invalid_token("OAuth token does not have the Sync scope".to_owned());
+            assert_eq!(expected_error, error);
+        }
+
+        // Token with a tampered signature
+        {
+            let mut token = make_jwt(TEST_KID, "at+jwt", claims);
+            token.push('A');
+            let error = verifier.verify(token).await.unwrap_err();
+            assert_eq!(error.status, "invalid-credentials");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_oauth_verifier_remote_success() {
+        let body = json!({
+            "user": "test_uid",
+            "client_id": "test_client",
+            "scope": [SYNC_SCOPE],
+            "generation": 1234,
+        });
+        let mock = mockito::mock("POST", "/v1/verify")
+            .with_header("content-type", "application/json")
+            .with_body(body.to_string())
+            .create();
+        let verifier = Verifier::try_from(&Settings {
+            fxa_oauth_server_url: mockito::server_url(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let result = verifier.verify("test".to_owned()).await.unwrap();
+        mock.assert();
+
+        let expected_result = VerifyOutput {
+            fxa_uid: "test_uid".to_owned(),
+            generation: Some(1234),
+        };
+
+        assert_eq!(expected_result, result);
+    }
+
+    #[actix_rt::test]
+    async fn test_oauth_verifier_remote_failure() {
+        let mock = mockito::mock("POST", "/v1/verify")
+            .with_status(400)
+            .with_header("content-type", "application/json")
+            .with_body(json!({ "code": 400, "errno": 108 }).to_string())
+            .create();
+        let verifier = Verifier::try_from(&Settings {
+            fxa_oauth_server_url: mockito::server_url(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let error = verifier.verify("test".to_owned()).await.unwrap_err();
+        mock.assert();
+
+        let expected_error =
+            invalid_token("FxA returned a status code other than 200 (400)".to_owned());
+        assert_eq!(expected_error, error);
+    }
+
+    #[actix_rt::test]
+    async fn test_oauth_verifier_fetch_jwks() {
+        let mock = mockito::mock("GET", "/v1/jwks")
+            .with_header("content-type", "application/json")
+            .with_body(jwks_body(&[TEST_KID]).to_string())
+            .create();
+        let verifier = Verifier::try_from(&Settings {
+            fxa_oauth_server_url: mockito::server_url(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        verifier.fetch_jwks().await.unwrap();
+        mock.assert();
+
+        let token = make_jwt(
+            TEST_KID,
+            "at+jwt",
+            json!({
+                "sub": "test_uid",
+                "scope": SYNC_SCOPE,
+                "exp": now() + 3600,
+            }),
+        );
+        let result = verifier.verify(token).await.unwrap();
+        let expected_result = VerifyOutput {
+            fxa_uid: "test_uid".to_owned(),
+            generation: None,
+        };
+
+        assert_eq!(expected_result, result);
+    }
+
+    #[actix_rt::test]
+    async fn test_oauth_verifier_refetch_unknown_kid() {
+        let verifier = Verifier::try_from(&Settings {
+            fxa_oauth_server_url: mockito::server_url(),
+            fxa_oauth_primary_jwk: Some(test_jwk("old")),
+            ..Default::default()
+        })
+        .unwrap();
+        let claims = json!({
+            "sub": "test_uid",
+            "scope": SYNC_SCOPE,
+            "exp": now() + 3600,
+        });
+
+        // A token signed with a key we haven't seen yet triggers a refetch
+        {
+            let mock = mockito::mock("GET", "/v1/jwks")
+                .with_header("content-type", "application/json")
+                .with_body(jwks_body(&["old", TEST_KID]).to_string())
+                .expect(1)
+                .create();
+
+            let token = make_jwt(TEST_KID, "at+jwt", claims.clone());
+            let result = verifier.verify(token).await.unwrap();
+            mock.assert();
+
+            assert_eq!(result.fxa_uid, "test_uid");
+        }
+
+        // Another unknown key ID shortly afterwards does not trigger a second refetch
+        {
+            let mock = mockito::mock("GET", "/v1/jwks")
+                .with_header("content-type", "application/json")
+                .with_body(jwks_body(&["old", TEST_KID]).to_string())
+                .expect(0)
+                .create();
+
+            let token = make_jwt("unknown", "at+jwt", claims);
+            let error = verifier.verify(token).await.unwrap_err();
+            mock.assert();
+
+            let expected_error = invalid_token("Unknown OAuth JWT key ID: unknown".to_owned());
+            assert_eq!(expected_error, error);
+        }
+    }
+
+    #[test]
+    fn test_scope_matches() {
+        assert!(scope_matches(SYNC_SCOPE));
+        assert!(scope_matches("https://identity.mozilla.com/apps"));
+        assert!(!scope_matches("https://identity.mozilla.com/apps/old"));
+        assert!(!scope_matches("profile"));
     }
 }
@@ -84,6 +84,21 @@ impl Db for MockDb {
         Box::pin(future::ok(results::GetUsers::default()))
     }
 
+    fn get_user_by_uid(
+        &self,
+        _params: params::GetUserByUid,
+    ) -> DbFuture<'_, results::GetUserByUid> {
+        Box::pin(future::ok(None))
+    }
+
+    fn get_old_users(&self, _params: params::GetOldUsers) -> DbFuture<'_, results::GetOldUsers> {
+        Box::pin(future::ok(results::GetOldUsers::default()))
+    }
+
+    fn delete_user(&self, _params: params::DeleteUser) -> DbFuture<'_, results::DeleteUser> {
+        Box::pin(future::ok(()))
+    }
+
     fn get_or_create_user(
         &self,
         _params: params::GetOrCreateUser,
@@ -116,22 +131,26 @@ impl Db for MockDb {
         Box::pin(future::ok(results::GetUser::default()))
     }
 
-    #[cfg(test)]
     fn post_node(&self, _params: params::PostNode) -> DbFuture<'_, results::PostNode> {
         Box::pin(future::ok(results::PostNode::default()))
     }
 
-    #[cfg(test)]
     fn get_node(&self, _params: params::GetNode) -> DbFuture<'_, results::GetNode> {
         Box::pin(future::ok(results::GetNode::default()))
     }
 
-    #[cfg(test)]
+    fn get_nodes(&self, _params: params::GetNodes) -> DbFuture<'_, results::GetNodes> {
+        Box::pin(future::ok(results::GetNodes::default()))
+    }
+
+    fn update_node(&self, _params: params::UpdateNode) -> DbFuture<'_, results::UpdateNode> {
+        Box::pin(future::ok(()))
+    }
+
     fn unassign_node(&self, _params: params::UnassignNode) -> DbFuture<'_, results::UnassignNode> {
         Box::pin(future::ok(()))
     }
 
-    #[cfg(test)]
     fn remove_node(&self, _params: params::RemoveNode) -> DbFuture<'_, results::RemoveNode> {
         Box::pin(future::ok(()))
     }
@@ -3,3 +3,6 @@ pub mod models;
 pub mod params;
 pub mod pool;
 pub mod results;
+#[cfg(test)]
+pub mod test;
+pub mod traced;
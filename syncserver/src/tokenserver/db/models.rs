@@ -25,6 +25,9 @@ use crate::sync_db_method;
 /// "retired" from the db.
 const MAX_GENERATION: i64 = i64::MAX;
 
+/// The rate at which capacity is released from nodes when no rate is configured.
+pub const DEFAULT_CAPACITY_RELEASE_RATE: f32 = 0.1;
+
 pub type DbFuture<'a, T> = LocalBoxFuture<'a, Result<T, DbError>>;
 pub type DbResult<T> = result::Result<T, DbError>;
 type Conn = PooledConnection<ConnectionManager<MysqlConnection>>;
@@ -217,7 +220,6 @@ impl TokenserverDb {
 
     /// Gets the least-loaded node that has available slots.
     fn get_best_node_sync(&self, params: params::GetBestNode) -> DbResult<results::GetBestNode> {
-        const DEFAULT_CAPACITY_RELEASE_RATE: f32 = 0.1;
         const GET_BEST_NODE_QUERY: &str = r#"
               SELECT id, node
                 FROM nodes
@@ -350,6 +352,79 @@ impl TokenserverDb {
             .map_err(Into::into)
     }
 
+    /// Gets the user record with the given uid and service ID, if one exists.
+    fn get_user_by_uid_sync(
+        &self,
+        params: params::GetUserByUid,
+    ) -> DbResult<results::GetUserByUid> {
+        const QUERY: &str = r#"
+                     SELECT uid, email, generation, keys_changed_at, client_state, nodes.node,
+                            created_at, replaced_at
+                       FROM users
+            LEFT OUTER JOIN nodes ON users.nodeid = nodes.id
+                      WHERE users.service = ?
+                        AND uid = ?
+        "#;
+
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.get_user_by_uid", None);
+
+        diesel::sql_query(QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Bigint, _>(params.uid)
+            .get_result::<results::UserRecord>(&self.inner.conn)
+            .optional()
+            .map_err(Into::into)
+    }
+
+    /// Gets a page of the user records for the given service that were replaced before the given
+    /// timestamp, most recently-replaced first.
+    fn get_old_users_sync(&self, params: params::GetOldUsers) -> DbResult<results::GetOldUsers> {
+        const QUERY: &str = r#"
+                     SELECT uid, email, generation, keys_changed_at, client_state, nodes.node,
+                            nodes.downed, created_at, replaced_at
+                       FROM users
+            LEFT OUTER JOIN nodes ON users.nodeid = nodes.id
+                      WHERE users.service = ?
+                        AND replaced_at IS NOT NULL
+                        AND replaced_at < ?
+                   ORDER BY replaced_at DESC, uid DESC
+                      LIMIT ?
+                     OFFSET ?
+        "#;
+
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.get_old_users", None);
+
+        diesel::sql_query(QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Bigint, _>(params.replaced_before)
+            .bind::<Bigint, _>(params.limit)
+            .bind::<Bigint, _>(params.offset)
+            .load::<results::OldUser>(&self.inner.conn)
+            .map_err(Into::into)
+    }
+
+    /// Deletes the user record with the given uid and service ID. The record's been replaced, so
+    /// its slot on the node already belongs to the replacing record and the node's left alone.
+    fn delete_user_sync(&self, params: params::DeleteUser) -> DbResult<results::DeleteUser> {
+        const QUERY: &str = r#"
+            DELETE FROM users
+             WHERE service = ?
+               AND uid = ?
+        "#;
+
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.delete_user", None);
+
+        diesel::sql_query(QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Bigint, _>(params.uid)
+            .execute(&self.inner.conn)
+            .map(|_| ())
+            .map_err(Into::into)
+    }
+
     /// Gets the user with the given email and service ID, or if one doesn't exist, allocates a new
     /// user.
     fn get_or_create_user_sync(
@@ -576,7 +651,6 @@ impl TokenserverDb {
             .map_err(Into::into)
     }
 
-    #[cfg(test)]
     fn post_node_sync(&self, params: params::PostNode) -> DbResult<results::PostNode> {
         const QUERY: &str = r#"
             INSERT INTO nodes (service, node, available, current_load, capacity, downed, backoff)
@@ -597,7 +671,6 @@ impl TokenserverDb {
             .map_err(Into::into)
     }
 
-    #[cfg(test)]
     fn get_node_sync(&self, params: params::GetNode) -> DbResult<results::GetNode> {
         const QUERY: &str = r#"
             SELECT *
@@ -611,7 +684,53 @@ impl TokenserverDb {
             .map_err(Into::into)
     }
 
-    #[cfg(test)]
+    /// Gets all of the nodes for the given service.
+    fn get_nodes_sync(&self, params: params::GetNodes) -> DbResult<results::GetNodes> {
+        const QUERY: &str = r#"
+              SELECT *
+                FROM nodes
+               WHERE service = ?
+            ORDER BY id
+        "#;
+
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.get_nodes", None);
+
+        diesel::sql_query(QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .load::<results::GetNode>(&self.inner.conn)
+            .map_err(Into::into)
+    }
+
+    /// Update the given fields of a node, leaving any fields that are `None` unchanged.
+    fn update_node_sync(&self, params: params::UpdateNode) -> DbResult<results::UpdateNode> {
+        const QUERY: &str = r#"
+            UPDATE nodes
+               SET available = COALESCE(?, available),
+                   current_load = COALESCE(?, current_load),
+                   capacity = COALESCE(?, capacity),
+                   downed = COALESCE(?, downed),
+                   backoff = COALESCE(?, backoff)
+             WHERE service = ?
+               AND node = ?
+        "#;
+
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.update_node", None);
+
+        diesel::sql_query(QUERY)
+            .bind::<Nullable<Integer>, _>(params.available)
+            .bind::<Nullable<Integer>, _>(params.current_load)
+            .bind::<Nullable<Integer>, _>(params.capacity)
+            .bind::<Nullable<Integer>, _>(params.downed)
+            .bind::<Nullable<Integer>, _>(params.backoff)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Text, _>(&params.node)
+            .execute(&self.inner.conn)
+            .map(|_| ())
+            .map_err(Into::into)
+    }
+
     fn unassign_node_sync(&self, params: params::UnassignNode) -> DbResult<results::UnassignNode> {
         const QUERY: &str = r#"
             UPDATE users
@@ -632,7 +751,6 @@ impl TokenserverDb {
             .map_err(Into::into)
     }
 
-    #[cfg(test)]
     fn remove_node_sync(&self, params: params::RemoveNode) -> DbResult<results::RemoveNode> {
         const QUERY: &str = "DELETE FROM nodes WHERE id = ?";
 
@@ -674,6 +792,9 @@ impl Db for TokenserverDb {
     sync_db_method!(get_best_node, get_best_node_sync, GetBestNode);
     sync_db_method!(add_user_to_node, add_user_to_node_sync, AddUserToNode);
     sync_db_method!(get_users, get_users_sync, GetUsers);
+    sync_db_method!(get_user_by_uid, get_user_by_uid_sync, GetUserByUid);
+    sync_db_method!(get_old_users, get_old_users_sync, GetOldUsers);
+    sync_db_method!(delete_user, delete_user_sync, DeleteUser);
     sync_db_method!(get_or_create_user, get_or_create_user_sync, GetOrCreateUser);
     sync_db_method!(get_service_id, get_service_id_sync, GetServiceId);
 
@@ -699,16 +820,11 @@ impl Db for TokenserverDb {
         SetUserReplacedAt
     );
 
-    #[cfg(test)]
     sync_db_method!(post_node, post_node_sync, PostNode);
-
-    #[cfg(test)]
     sync_db_method!(get_node, get_node_sync, GetNode);
-
-    #[cfg(test)]
+    sync_db_method!(get_nodes, get_nodes_sync, GetNodes);
+    sync_db_method!(update_node, update_node_sync, UpdateNode);
     sync_db_method!(unassign_node, unassign_node_sync, UnassignNode);
-
-    #[cfg(test)]
     sync_db_method!(remove_node, remove_node_sync, RemoveNode);
 
     #[cfg(test)]
@@ -737,6 +853,12 @@ pub trait Db {
 
     fn get_users(&self, params: params::GetUsers) -> DbFuture<'_, results::GetUsers>;
 
+    fn get_user_by_uid(&self, params: params::GetUserByUid) -> DbFuture<'_, results::GetUserByUid>;
+
+    fn get_old_users(&self, params: params::GetOldUsers) -> DbFuture<'_, results::GetOldUsers>;
+
+    fn delete_user(&self, params: params::DeleteUser) -> DbFuture<'_, results::DeleteUser>;
+
     fn get_or_create_user(
         &self,
         params: params::GetOrCreateUser,
@@ -759,16 +881,16 @@ pub trait Db {
     #[cfg(test)]
     fn get_user(&self, params: params::GetUser) -> DbFuture<'_, results::GetUser>;
 
-    #[cfg(test)]
     fn post_node(&self, params: params::PostNode) -> DbFuture<'_, results::PostNode>;
 
-    #[cfg(test)]
     fn get_node(&self, params: params::GetNode) -> DbFuture<'_, results::GetNode>;
 
-    #[cfg(test)]
+    fn get_nodes(&self, params: params::GetNodes) -> DbFuture<'_, results::GetNodes>;
+
+    fn update_node(&self, params: params::UpdateNode) -> DbFuture<'_, results::UpdateNode>;
+
     fn unassign_node(&self, params: params::UnassignNode) -> DbFuture<'_, results::UnassignNode>;
 
-    #[cfg(test)]
     fn remove_node(&self, params: params::RemoveNode) -> DbFuture<'_, results::RemoveNode>;
 
     #[cfg(test)]
@@ -782,33 +904,18 @@ mod tests {
     use std::thread;
     use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-    use syncserver_settings::Settings;
-
-    use crate::tokenserver::db::pool::{DbPool, TokenserverPool};
+    use crate::tokenserver::db::{
+        pool::DbPool,
+        test::{db_pool, setup, setup_with_node},
+    };
 
     #[tokio::test]
     async fn test_update_generation() -> DbResult<()> {
         let pool = db_pool().await?;
         let db = pool.get().await?;
 
-        // Add a service
-        let service_id = db
-            .post_service(params::PostService {
-                service: "sync-1.5".to_owned(),
-                pattern: "{node}/1.5/{uid}".to_owned(),
-            })
-            .await?
-            .id;
-
-        // Add a node
-        let node_id = db
-            .post_node(params::PostNode {
-                service_id,
-                node: "https://node1".to_owned(),
-                ..Default::default()
-            })
-            .await?
-            .id;
+        // Add a service and a node
+        let (service_id, node_id) = setup(&*db).await?;
 
         // Add a user
         let email = "test_user";
@@ -2053,13 +2160,237 @@ mod tests {
         Ok(())
     }
 
-    async fn db_pool() -> DbResult<TokenserverPool> {
-        let _ = env_logger::try_init();
+    #[tokio::test]
+    async fn test_get_old_users_and_delete_user() -> DbResult<()> {
+        let pool = db_pool().await?;
+        let db = pool.get().await?;
 
-        let mut settings = Settings::test_settings().tokenserver;
-        settings.run_migrations = true;
-        let use_test_transactions = true;
+        // Add a service and a node with three users assigned to it
+        let (service_id, node_id) = setup_with_node(
+            &*db,
+            params::PostNode {
+                node: "https://node1".to_owned(),
+                current_load: 3,
+                capacity: 10,
+                available: 7,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        // Add an active user, a user replaced long ago, and a user replaced recently
+        let mut uids = vec![];
+        for email in &["active_user", "old_user", "recent_user"] {
+            let uid = db
+                .post_user(params::PostUser {
+                    service_id,
+                    node_id,
+                    email: (*email).to_owned(),
+                    ..Default::default()
+                })
+                .await?
+                .id;
+            uids.push(uid);
+        }
+
+        db.set_user_replaced_at(params::SetUserReplacedAt {
+            uid: uids[1],
+            replaced_at: 1000,
+        })
+        .await?;
+        db.set_user_replaced_at(params::SetUserReplacedAt {
+            uid: uids[2],
+            replaced_at: 5000,
+        })
+        .await?;
 
-        TokenserverPool::new(&settings, &Metrics::noop(), use_test_transactions)
+        // Only the users replaced before the cutoff are returned
+        let old_users = db
+            .get_old_users(params::GetOldUsers {
+                service_id,
+                replaced_before: 2000,
+                limit: 10,
+                offset: 0,
+            })
+            .await?;
+        assert_eq!(old_users.len(), 1);
+        assert_eq!(old_users[0].uid, uids[1]);
+        assert_eq!(old_users[0].email, "old_user");
+        assert_eq!(old_users[0].node.as_deref(), Some("https://node1"));
+        assert_eq!(old_users[0].downed, Some(0));
+
+        // The most recently-replaced users are returned first
+        let old_users = db
+            .get_old_users(params::GetOldUsers {
+                service_id,
+                replaced_before: 10000,
+                limit: 10,
+                offset: 0,
+            })
+            .await?;
+        let old_uids: Vec<i64> = old_users.iter().map(|user| user.uid).collect();
+        assert_eq!(old_uids, vec![uids[2], uids[1]]);
+
+        let old_users = db
+            .get_old_users(params::GetOldUsers {
+                service_id,
+                replaced_before: 10000,
+                limit: 1,
+                offset: 1,
+            })
+            .await?;
+        assert_eq!(old_users.len(), 1);
+        assert_eq!(old_users[0].uid, uids[1]);
+
+        // Deleting a replaced user leaves its node's load alone
+        db.delete_user(params::DeleteUser {
+            service_id,
+            uid: uids[1],
+        })
+        .await?;
+
+        let old_users = db
+            .get_old_users(params::GetOldUsers {
+                service_id,
+                replaced_before: 10000,
+                limit: 10,
+                offset: 0,
+            })
+            .await?;
+        assert_eq!(old_users.len(), 1);
+        assert_eq!(old_users[0].uid, uids[2]);
+
+        let node = db.get_node(params::GetNode { id: node_id }).await?;
+        assert_eq!(node.current_load, 3);
+        assert_eq!(node.available, 7);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_update_node() -> DbResult<()> {
+        let pool = db_pool().await?;
+        let db = pool.get().await?;
+
+        // Add a service and a node
+        let (service_id, node_id) = setup_with_node(
+            &*db,
+            params::PostNode {
+                node: "https://node".to_owned(),
+                current_load: 5,
+                capacity: 100,
+                available: 10,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        // Update only some of the node's fields
+        db.update_node(params::UpdateNode {
+            service_id,
+            node: "https://node".to_owned(),
+            capacity: Some(200),
+            downed: Some(1),
+            ..Default::default()
+        })
+        .await?;
+
+        let node = db.get_node(params::GetNode { id: node_id }).await?;
+        assert_eq!(node.capacity, 200);
+        assert_eq!(node.downed, 1);
+        assert_eq!(node.available, 10);
+        assert_eq!(node.current_load, 5);
+        assert_eq!(node.backoff, 0);
+
+        // Clear the downed flag and back off the node
+        db.update_node(params::UpdateNode {
+            service_id,
+            node: "https://node".to_owned(),
+            downed: Some(0),
+            backoff: Some(1),
+            ..Default::default()
+        })
+        .await?;
+
+        let node = db.get_node(params::GetNode { id: node_id }).await?;
+        assert_eq!(node.capacity, 200);
+        assert_eq!(node.downed, 0);
+        assert_eq!(node.backoff, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_nodes_and_get_user_by_uid() -> DbResult<()> {
+        let pool = db_pool().await?;
+        let db = pool.get().await?;
+
+        // Add a service
+        let service_id = db
+            .post_service(params::PostService {
+                service: "sync-1.5".to_owned(),
+                pattern: "{node}/1.5/{uid}".to_owned(),
+            })
+            .await?
+            .id;
+
+        // Add two nodes
+        let node_id = db
+            .post_node(params::PostNode {
+                service_id,
+                node: "https://node1".to_owned(),
+                capacity: 100,
+                ..Default::default()
+            })
+            .await?
+            .id;
+        db.post_node(params::PostNode {
+            service_id,
+            node: "https://node2".to_owned(),
+            capacity: 200,
+            ..Default::default()
+        })
+        .await?;
+
+        let nodes = db.get_nodes(params::GetNodes { service_id }).await?;
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].id, node_id);
+        assert_eq!(nodes[0].capacity, 100);
+        assert_eq!(nodes[1].node, "https://node2");
+        assert_eq!(nodes[1].capacity, 200);
+
+        // Add a user
+        let uid = db
+            .post_user(params::PostUser {
+                service_id,
+                node_id,
+                email: "test_user@mozilla.com".to_owned(),
+                generation: 1234,
+                client_state: "aaaa".to_owned(),
+                ..Default::default()
+            })
+            .await?
+            .id;
+
+        let user = db
+            .get_user_by_uid(params::GetUserByUid { service_id, uid })
+            .await?
+            .unwrap();
+        assert_eq!(user.email, "test_user@mozilla.com");
+        assert_eq!(user.generation, 1234);
+        assert_eq!(user.client_state, "aaaa");
+        assert_eq!(user.node.as_deref(), Some("https://node1"));
+        assert_eq!(user.replaced_at, None);
+
+        // A nonexistent user is not found
+        assert!(db
+            .get_user_by_uid(params::GetUserByUid {
+                service_id,
+                uid: uid + 1,
+            })
+            .await?
+            .is_none());
+
+        Ok(())
     }
 }
@@ -16,6 +16,11 @@ pub struct GetNode {
     pub id: i64,
 }
 
+#[derive(Default)]
+pub struct GetNodes {
+    pub service_id: i32,
+}
+
 #[derive(Default)]
 pub struct PostService {
     pub service: String,
@@ -27,6 +32,28 @@ pub struct GetUsers {
     pub email: String,
 }
 
+/// The parameters used to fetch a page of user records that were replaced before
+/// `replaced_before`, a timestamp in milliseconds.
+#[derive(Default)]
+pub struct GetOldUsers {
+    pub service_id: i32,
+    pub replaced_before: i64,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+#[derive(Default)]
+pub struct GetUserByUid {
+    pub service_id: i32,
+    pub uid: i64,
+}
+
+#[derive(Default)]
+pub struct DeleteUser {
+    pub service_id: i32,
+    pub uid: i64,
+}
+
 #[derive(Clone, Default)]
 pub struct GetOrCreateUser {
     pub service_id: i32,
@@ -114,12 +141,21 @@ pub struct GetUser {
     pub id: i64,
 }
 
-#[cfg(test)]
+#[derive(Default)]
+pub struct UpdateNode {
+    pub service_id: i32,
+    pub node: String,
+    pub available: Option<i32>,
+    pub current_load: Option<i32>,
+    pub capacity: Option<i32>,
+    pub downed: Option<i32>,
+    pub backoff: Option<i32>,
+}
+
 pub struct UnassignNode {
     pub node_id: i64,
 }
 
-#[cfg(test)]
 pub struct RemoveNode {
     pub node_id: i64,
 }
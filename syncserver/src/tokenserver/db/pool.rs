@@ -10,6 +10,7 @@ use syncserver_db_common::{error::DbError, GetPoolState, PoolState};
 use tokenserver_settings::Settings;
 
 use super::models::{Db, DbResult, TokenserverDb};
+use super::traced::TracedDb;
 use crate::db;
 use crate::diesel::Connection;
 use crate::server::metrics::Metrics;
@@ -110,12 +111,12 @@ impl DbPool for TokenserverPool {
         let conn =
             db::run_on_blocking_threadpool(move || pool.inner.get().map_err(DbError::from)).await?;
 
-        Ok(Box::new(TokenserverDb::new(
+        Ok(Box::new(TracedDb::new(Box::new(TokenserverDb::new(
             conn,
             &self.metrics,
             self.service_id,
             self.spanner_node_id,
-        )) as Box<dyn Db>)
+        )))) as Box<dyn Db>)
     }
 
     fn box_clone(&self) -> Box<dyn DbPool> {
@@ -25,6 +25,55 @@ pub struct GetRawUser {
 
 pub type GetUsers = Vec<GetRawUser>;
 
+/// Represents a replaced user record, along with the node to which it was assigned.
+#[derive(Clone, Debug, Default, QueryableByName)]
+pub struct OldUser {
+    #[sql_type = "Bigint"]
+    pub uid: i64,
+    #[sql_type = "Text"]
+    pub email: String,
+    #[sql_type = "Bigint"]
+    pub generation: i64,
+    #[sql_type = "Nullable<Bigint>"]
+    pub keys_changed_at: Option<i64>,
+    #[sql_type = "Text"]
+    pub client_state: String,
+    #[sql_type = "Nullable<Text>"]
+    pub node: Option<String>,
+    #[sql_type = "Nullable<Integer>"]
+    pub downed: Option<i32>,
+    #[sql_type = "Bigint"]
+    pub created_at: i64,
+    #[sql_type = "Nullable<Bigint>"]
+    pub replaced_at: Option<i64>,
+}
+
+pub type GetOldUsers = Vec<OldUser>;
+
+/// Represents a user record, along with the node to which it is assigned.
+#[derive(Clone, Debug, Default, QueryableByName, Serialize)]
+pub struct UserRecord {
+    #[sql_type = "Bigint"]
+    pub uid: i64,
+    #[sql_type = "Text"]
+    pub email: String,
+    #[sql_type = "Bigint"]
+    pub generation: i64,
+    #[sql_type = "Nullable<Bigint>"]
+    pub keys_changed_at: Option<i64>,
+    #[sql_type = "Text"]
+    pub client_state: String,
+    #[sql_type = "Nullable<Text>"]
+    pub node: Option<String>,
+    #[sql_type = "Bigint"]
+    pub created_at: i64,
+    #[sql_type = "Nullable<Bigint>"]
+    pub replaced_at: Option<i64>,
+}
+
+pub type GetUserByUid = Option<UserRecord>;
+pub type DeleteUser = ();
+
 #[derive(Debug, Default, Eq, PartialEq)]
 pub struct AllocateUser {
     pub uid: i64,
@@ -103,11 +152,9 @@ pub struct GetUser {
     pub keys_changed_at: Option<i64>,
 }
 
-#[cfg(test)]
 pub type PostNode = LastInsertId;
 
-#[cfg(test)]
-#[derive(Default, QueryableByName)]
+#[derive(Debug, Default, QueryableByName, Serialize)]
 pub struct GetNode {
     #[sql_type = "Bigint"]
     pub id: i64,
@@ -128,6 +175,8 @@ pub struct GetNode {
     pub backoff: i32,
 }
 
+pub type GetNodes = Vec<GetNode>;
+
 #[cfg(test)]
 #[derive(Default, QueryableByName)]
 pub struct PostService {
@@ -143,8 +192,8 @@ pub type SetUserReplacedAt = ();
 
 pub type Check = bool;
 
-#[cfg(test)]
+pub type UpdateNode = ();
+
 pub type UnassignNode = ();
 
-#[cfg(test)]
 pub type RemoveNode = ();
@@ -0,0 +1,50 @@
+//! Fixtures shared by the tests using the Tokenserver db
+use syncserver_settings::Settings;
+
+use super::{
+    models::{Db, DbResult},
+    params,
+    pool::TokenserverPool,
+};
+use crate::server::metrics::Metrics;
+
+/// A pool of connections to the test db, each in a test transaction
+pub async fn db_pool() -> DbResult<TokenserverPool> {
+    let _ = env_logger::try_init();
+
+    let mut settings = Settings::test_settings().tokenserver;
+    settings.run_migrations = true;
+    let use_test_transactions = true;
+
+    TokenserverPool::new(&settings, &Metrics::noop(), use_test_transactions)
+}
+
+/// Add the sync-1.5 service with a node, returning their ids
+pub async fn setup(db: &dyn Db) -> DbResult<(i32, i64)> {
+    setup_with_node(
+        db,
+        params::PostNode {
+            node: "https://node1".to_owned(),
+            ..Default::default()
+        },
+    )
+    .await
+}
+
+/// Add the sync-1.5 service with the given node (whose `service_id` is filled
+/// in), returning their ids
+pub async fn setup_with_node(db: &dyn Db, node: params::PostNode) -> DbResult<(i32, i64)> {
+    let service_id = db
+        .post_service(params::PostService {
+            service: "sync-1.5".to_owned(),
+            pattern: "{node}/1.5/{uid}".to_owned(),
+        })
+        .await?
+        .id;
+    let node_id = db
+        .post_node(params::PostNode { service_id, ..node })
+        .await?
+        .id;
+
+    Ok((service_id, node_id))
+}
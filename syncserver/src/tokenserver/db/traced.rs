@@ -0,0 +1,68 @@
+//! A tokenserver `Db` recording a tracing span for each call.
+use super::models::{Db, DbFuture};
+use super::{params, results};
+use crate::telemetry;
+
+pub struct TracedDb {
+    inner: Box<dyn Db>,
+}
+
+impl TracedDb {
+    pub fn new(inner: Box<dyn Db>) -> Self {
+        Self { inner }
+    }
+}
+
+macro_rules! traced_db_method {
+    ($name:ident, $type:ident) => {
+        fn $name(&self, params: params::$type) -> DbFuture<'_, results::$type> {
+            Box::pin(telemetry::in_span(
+                concat!("tokenserver.db.", stringify!($name)),
+                self.inner.$name(params),
+            ))
+        }
+    };
+}
+
+impl Db for TracedDb {
+    traced_db_method!(replace_user, ReplaceUser);
+    traced_db_method!(replace_users, ReplaceUsers);
+    traced_db_method!(post_user, PostUser);
+    traced_db_method!(put_user, PutUser);
+
+    fn check(&self) -> DbFuture<'_, results::Check> {
+        Box::pin(telemetry::in_span(
+            "tokenserver.db.check",
+            self.inner.check(),
+        ))
+    }
+
+    traced_db_method!(get_node_id, GetNodeId);
+    traced_db_method!(get_best_node, GetBestNode);
+    traced_db_method!(add_user_to_node, AddUserToNode);
+    traced_db_method!(get_users, GetUsers);
+    traced_db_method!(get_user_by_uid, GetUserByUid);
+    traced_db_method!(get_old_users, GetOldUsers);
+    traced_db_method!(delete_user, DeleteUser);
+    traced_db_method!(get_or_create_user, GetOrCreateUser);
+    traced_db_method!(get_service_id, GetServiceId);
+
+    #[cfg(test)]
+    traced_db_method!(set_user_created_at, SetUserCreatedAt);
+
+    #[cfg(test)]
+    traced_db_method!(set_user_replaced_at, SetUserReplacedAt);
+
+    #[cfg(test)]
+    traced_db_method!(get_user, GetUser);
+
+    traced_db_method!(post_node, PostNode);
+    traced_db_method!(get_node, GetNode);
+    traced_db_method!(get_nodes, GetNodes);
+    traced_db_method!(update_node, UpdateNode);
+    traced_db_method!(unassign_node, UnassignNode);
+    traced_db_method!(remove_node, RemoveNode);
+
+    #[cfg(test)]
+    traced_db_method!(post_service, PostService);
+}
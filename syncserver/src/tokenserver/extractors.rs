@@ -35,7 +35,7 @@ lazy_static! {
     static ref CLIENT_STATE_REGEX: Regex = Regex::new("^[a-zA-Z0-9._-]{1,32}$").unwrap();
 }
 
-const SYNC_SERVICE_NAME: &str = "sync-1.5";
+pub(super) const SYNC_SERVICE_NAME: &str = "sync-1.5";
 
 /// Information from the request needed to process a Tokenserver request.
 #[derive(Debug, Default, Eq, PartialEq)]
@@ -655,7 +655,9 @@ impl FromRequest for TokenserverMetrics {
     }
 }
 
-fn get_server_state(req: &HttpRequest) -> Result<&Data<Option<ServerState>>, TokenserverError> {
+pub(super) fn get_server_state(
+    req: &HttpRequest,
+) -> Result<&Data<Option<ServerState>>, TokenserverError> {
     req.app_data::<Data<Option<ServerState>>>()
         .ok_or_else(|| TokenserverError {
             context: "Failed to load the application state".to_owned(),
@@ -1349,6 +1351,7 @@ mod tests {
                 .unwrap(),
             ),
             token_duration: TOKEN_DURATION,
+            admin_secret: None,
         }
     }
 }
@@ -1,3 +1,5 @@
+pub mod account_events;
+pub mod admin;
 pub mod auth;
 pub mod db;
 pub mod extractors;
@@ -16,6 +18,7 @@ use tokenserver_settings::Settings;
 use crate::{
     error::ApiError,
     server::{metrics::Metrics, user_agent},
+    web::middleware::request_id::RequestId,
 };
 use auth::{browserid, oauth, VerifyToken};
 use db::{
@@ -36,14 +39,15 @@ pub struct ServerState {
     pub node_type: NodeType,
     pub metrics: Box<StatsdClient>,
     pub token_duration: u64,
+    pub admin_secret: Option<String>,
 }
 
 impl ServerState {
     pub fn from_settings(settings: &Settings, metrics: StatsdClient) -> Result<Self, ApiError> {
-        let oauth_verifier = Box::new(
-            oauth::Verifier::try_from(settings)
-                .expect("failed to create Tokenserver OAuth verifier"),
-        );
+        let oauth_verifier = oauth::Verifier::try_from(settings)
+            .expect("failed to create Tokenserver OAuth verifier");
+        oauth_verifier.spawn_jwks_refresher();
+        let oauth_verifier = Box::new(oauth_verifier);
         let browserid_verifier = Box::new(
             browserid::RemoteVerifier::try_from(settings)
                 .expect("failed to create Tokenserver BrowserID verifier"),
@@ -75,6 +79,7 @@ impl ServerState {
                     node_type: settings.node_type,
                     metrics: Box::new(metrics),
                     token_duration: settings.token_duration,
+                    admin_secret: settings.admin_secret.clone(),
                 }
             })
             .map_err(Into::into)
@@ -118,6 +123,9 @@ impl From<&RequestHead> for LogItems {
         }
         items.insert("uri.method".to_owned(), req_head.method.to_string());
         items.insert("uri.path".to_owned(), req_head.uri.to_string());
+        if let Some(request_id) = RequestId::get(req_head) {
+            items.insert("request_id".to_owned(), request_id);
+        }
 
         items
     }
@@ -6,7 +6,9 @@
     allow(dead_code, unused_imports, unused_variables)
 )]
 
-use std::convert::TryInto;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
 
 use chrono::offset::Utc;
 use hawk::{self, Header as HawkHeader, Key, RequestBuilder};
@@ -15,7 +17,7 @@ use serde::{Deserialize, Serialize};
 use sha2::Sha256;
 use syncserver_common;
 use syncserver_settings::Secrets;
-use time::Duration;
+use syncstorage_settings::Settings as SyncstorageSettings;
 
 use actix_web::dev::ConnectionInfo;
 use actix_web::http::Uri;
@@ -28,6 +30,11 @@ use crate::error::{ApiErrorKind, ApiResult};
 use crate::label;
 use crate::tokenserver::auth::TokenserverOrigin;
 
+/// Hawk headers' MACs are validated without regard to their timestamps, which
+/// are checked against the `hawk_timestamp_skew` setting afterwards
+#[cfg(not(feature = "no_auth"))]
+const UNLIMITED_SKEW: Duration = Duration::from_secs(u32::MAX as u64);
+
 /// A parsed and authenticated JSON payload
 /// extracted from the signed `id` property
 /// of a Hawk `Authorization` header.
@@ -58,14 +65,21 @@ pub struct HawkPayload {
     /// The Tokenserver that created this token.
     #[serde(default)]
     pub tokenserver_origin: TokenserverOrigin,
+
+    /// The index of the master secret that the payload was signed with.
+    #[serde(skip)]
+    pub secret_index: usize,
 }
 
 impl HawkPayload {
     /// Parse and authenticate a payload
-    /// using the supplied arguments.
+    /// using the supplied arguments,
+    /// recording its nonce.
     ///
     /// Assumes that the header string
     /// includes the `Hawk ` prefix.
+    #[cfg(test)]
+    #[allow(clippy::too_many_arguments)]
     fn new(
         header: &str,
         method: &str,
@@ -74,7 +88,40 @@ impl HawkPayload {
         port: u16,
         secrets: &Secrets,
         expiry: u64,
+        nonces: &NonceCache,
     ) -> ApiResult<HawkPayload> {
+        let (payload, nonce) = HawkPayload::authenticate(
+            header,
+            method,
+            path,
+            host,
+            port,
+            secrets,
+            expiry,
+            nonces.skew,
+        )?;
+        nonce.record(nonces)?;
+        Ok(payload)
+    }
+
+    /// Parse and authenticate a payload
+    /// using the supplied arguments,
+    /// returning the nonce to record once
+    /// the rest of the request is valid.
+    ///
+    /// Assumes that the header string
+    /// includes the `Hawk ` prefix.
+    #[allow(clippy::too_many_arguments)]
+    fn authenticate(
+        header: &str,
+        method: &str,
+        path: &str,
+        host: &str,
+        port: u16,
+        secrets: &Secrets,
+        expiry: u64,
+        skew: Duration,
+    ) -> ApiResult<(HawkPayload, HawkNonce)> {
         if header.len() < 5 || &header[0..5] != "Hawk " {
             Err(HawkErrorKind::MissingPrefix)?;
         }
@@ -84,46 +131,64 @@ impl HawkPayload {
 
         let payload = HawkPayload::extract_and_validate(id, secrets, expiry)?;
 
+        let (master_secret, _) = secrets
+            .iter()
+            .nth(payload.secret_index)
+            .ok_or_else(|| ApiErrorKind::Internal("Unknown master secret".to_owned()))?;
         let token_secret = syncserver_common::hkdf_expand_32(
             format!("services.mozilla.com/tokenlib/v1/derive/{}", id).as_bytes(),
             Some(payload.salt.as_bytes()),
-            &secrets.master_secret,
+            master_secret,
         )
         .map_err(|e| ApiErrorKind::Internal(format!("HKDF Error: {:?}", e)))?;
         let token_secret = base64::encode_config(&token_secret, base64::URL_SAFE);
 
         let request = RequestBuilder::new(method, host, port, path).request();
 
+        // A valid header always has a nonce and timestamp
+        let nonce = HawkNonce {
+            id: id.to_owned(),
+            nonce: header.nonce.clone().unwrap_or_default(),
+            ts: header
+                .ts
+                .and_then(|ts| ts.duration_since(UNIX_EPOCH).ok())
+                .map(|ts| ts.as_secs())
+                .unwrap_or_default(),
+        };
+
         #[cfg(feature = "no_auth")]
         {
-            Ok(payload)
+            Ok((payload, nonce))
         }
 
         #[cfg(not(feature = "no_auth"))]
         {
-            let mut duration: std::time::Duration = Duration::weeks(52)
-                .try_into()
-                .map_err(|_| ApiErrorKind::Internal("Duration::weeks".to_owned()))?;
-            if cfg!(test) {
-                // test cases are valid until 3018. Add millenia as required.
-                duration *= 1000;
-            }
-            if request.validate_header(
+            // The timestamp's checked separately, so that a stale one can be
+            // told apart from an invalid MAC
+            if !request.validate_header(
                 &header,
                 &Key::new(token_secret.as_bytes(), hawk::DigestAlgorithm::Sha256)?,
-                // Allow plenty of leeway for clock skew, because
-                // client timestamps tend to be all over the shop
-                duration,
+                UNLIMITED_SKEW,
             ) {
-                Ok(payload)
-            } else {
                 Err(HawkErrorKind::InvalidHeader)?
             }
+
+            let now = Utc::now().timestamp() as u64;
+            if now.abs_diff(nonce.ts) > skew.as_secs() {
+                Err(HawkErrorKind::StaleTimestamp {
+                    ts: now,
+                    tsm: timestamp_mac(now, &token_secret)?,
+                })?
+            }
+            Ok((payload, nonce))
         }
     }
 
     /// Decode the `id` property of a Hawk header
     /// and verify the payload part against the signature part.
+    ///
+    /// The signature may have been made with any of the master secrets,
+    /// which are tried in order.
     fn extract_and_validate(id: &str, secrets: &Secrets, expiry: u64) -> ApiResult<HawkPayload> {
         let decoded_id = base64::decode_config(id, base64::URL_SAFE)?;
         if decoded_id.len() <= 32 {
@@ -135,9 +200,21 @@ impl HawkPayload {
         let signature = &decoded_id[payload_length..];
 
         #[cfg(not(feature = "no_auth"))]
-        verify_hmac(payload, &secrets.signing_secret, signature)?;
+        let secret_index = {
+            let mut result: ApiResult<usize> = Err(HawkErrorKind::InvalidHeader.into());
+            for (index, (_, signing_secret)) in secrets.iter().enumerate() {
+                result = verify_hmac(payload, signing_secret, signature).map(|_| index);
+                if result.is_ok() {
+                    break;
+                }
+            }
+            result?
+        };
+        #[cfg(feature = "no_auth")]
+        let secret_index = 0;
 
-        let payload: HawkPayload = serde_json::from_slice(payload)?;
+        let mut payload: HawkPayload = serde_json::from_slice(payload)?;
+        payload.secret_index = secret_index;
 
         if expiry == 0 || (payload.expires.round() as u64) > expiry {
             Ok(payload)
@@ -157,18 +234,22 @@ impl HawkPayload {
             fxa_kid: "xxx_test".to_owned(),
             device_id: "xxx_test".to_owned(),
             tokenserver_origin: Default::default(),
+            secret_index: 0,
         }
     }
 }
 
 impl HawkPayload {
+    /// Authenticate a request's payload, returning the nonce to record once
+    /// the rest of the request is valid.
     pub fn extrude(
         header: &str,
         method: &str,
         secrets: &Secrets,
         ci: &ConnectionInfo,
         uri: &Uri,
-    ) -> ApiResult<Self> {
+        nonces: &NonceCache,
+    ) -> ApiResult<(Self, HawkNonce)> {
         let host_port: Vec<_> = ci.host().splitn(2, ':').collect();
         let host = host_port[0];
         let port = if host_port.len() == 2 {
@@ -192,10 +273,125 @@ impl HawkPayload {
             Utc::now().timestamp() as u64
         };
 
-        HawkPayload::new(header, method, path.as_str(), host, port, secrets, expiry)
+        HawkPayload::authenticate(
+            header,
+            method,
+            path.as_str(),
+            host,
+            port,
+            secrets,
+            expiry,
+            nonces.skew,
+        )
     }
 }
 
+/// An authenticated request's nonce. It's only recorded (rejecting replays)
+/// once the rest of the request is valid, so that a request rejected for
+/// other reasons doesn't use it up.
+#[must_use]
+pub struct HawkNonce {
+    id: String,
+    nonce: String,
+    ts: u64,
+}
+
+impl HawkNonce {
+    /// Remember the nonce, failing if it's already been used.
+    pub fn record(self, nonces: &NonceCache) -> ApiResult<()> {
+        #[cfg(not(feature = "no_auth"))]
+        {
+            let now = Utc::now().timestamp() as u64;
+            if !nonces.insert(&self.id, &self.nonce, self.ts, now) {
+                Err(HawkErrorKind::Replayed)?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The nonces of recently authenticated requests, remembered so that
+/// replayed requests can be rejected.
+///
+/// Requests are only accepted within `skew` of their timestamp, so a nonce
+/// only needs to be remembered until its request's timestamp falls outside of
+/// that window. At most `capacity` nonces are remembered, forgetting the
+/// oldest first.
+pub struct NonceCache {
+    skew: Duration,
+    capacity: usize,
+    seen: Mutex<SeenNonces>,
+}
+
+#[derive(Default)]
+struct SeenNonces {
+    /// (token id, nonce) pairs
+    nonces: HashSet<(String, String)>,
+    /// The pairs in the order they were seen, along with when (in seconds
+    /// since the epoch) they can be forgotten
+    expiries: VecDeque<(u64, (String, String))>,
+}
+
+impl From<&SyncstorageSettings> for NonceCache {
+    fn from(settings: &SyncstorageSettings) -> Self {
+        Self::new(
+            Duration::from_secs(settings.hawk_timestamp_skew.into()),
+            settings.hawk_nonce_cache_size as usize,
+        )
+    }
+}
+
+impl NonceCache {
+    pub fn new(skew: Duration, capacity: usize) -> Self {
+        Self {
+            skew,
+            capacity,
+            seen: Mutex::new(SeenNonces::default()),
+        }
+    }
+
+    /// Remember a request's nonce, returning false if it's already been seen.
+    fn insert(&self, id: &str, nonce: &str, ts: u64, now: u64) -> bool {
+        let mut seen = self.seen.lock().expect("NonceCache lock poisoned");
+        let seen = &mut *seen;
+
+        // Client clocks vary, so expiries are only roughly in order: a nonce
+        // may be remembered for longer than needed, but never less
+        while matches!(seen.expiries.front(), Some((expiry, _)) if *expiry < now) {
+            if let Some((_, key)) = seen.expiries.pop_front() {
+                seen.nonces.remove(&key);
+            }
+        }
+
+        let key = (id.to_owned(), nonce.to_owned());
+        if seen.nonces.contains(&key) {
+            return false;
+        }
+        if self.capacity == 0 {
+            return true;
+        }
+        while seen.nonces.len() >= self.capacity {
+            match seen.expiries.pop_front() {
+                Some((_, key)) => seen.nonces.remove(&key),
+                None => break,
+            };
+        }
+        seen.nonces.insert(key.clone());
+        seen.expiries
+            .push_back((ts.saturating_add(self.skew.as_secs()), key));
+        true
+    }
+}
+
+/// The `tsm` of a stale timestamp's `WWW-Authenticate` challenge: the
+/// server's time, MACed with the request's token key so the client can trust
+/// it to correct for its clock skew.
+fn timestamp_mac(ts: u64, token_secret: &str) -> ApiResult<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(token_secret.as_bytes())?;
+    mac.update(format!("hawk.1.ts\n{}\n", ts).as_bytes());
+    Ok(base64::encode(mac.finalize().into_bytes()))
+}
+
 /// Helper function for [HMAC](https://tools.ietf.org/html/rfc2104) verification.
 fn verify_hmac(info: &[u8], key: &[u8], expected: &[u8]) -> ApiResult<()> {
     let mut hmac = Hmac::<Sha256>::new_from_slice(key)?;
@@ -205,7 +401,16 @@ fn verify_hmac(info: &[u8], key: &[u8], expected: &[u8]) -> ApiResult<()> {
 
 #[cfg(test)]
 mod tests {
-    use super::{HawkPayload, Secrets};
+    use std::time::{Duration, SystemTime};
+
+    use actix_web::ResponseError;
+
+    use super::{timestamp_mac, HawkPayload, NonceCache, Secrets};
+
+    use chrono::offset::Utc;
+    use hawk::{Credentials, DigestAlgorithm, Key, RequestBuilder};
+
+    use crate::tokenserver::auth::{MakeTokenPlaintext, Tokenlib, TokenserverOrigin};
 
     #[test]
     fn valid_header() {
@@ -219,6 +424,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &nonces(),
         );
 
         assert!(result.is_ok());
@@ -247,6 +453,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &nonces(),
         );
 
         assert!(result.is_ok());
@@ -267,6 +474,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &nonces(),
         );
 
         assert!(result.is_err());
@@ -284,6 +492,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &nonces(),
         );
 
         assert!(result.is_err());
@@ -301,6 +510,7 @@ mod tests {
             fixture.request.port,
             &Secrets::new("wibble").unwrap(),
             fixture.expected.expires.round() as u64 - 1,
+            &nonces(),
         );
 
         assert!(result.is_err());
@@ -323,6 +533,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &nonces(),
         );
 
         assert!(result.is_err());
@@ -340,6 +551,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64,
+            &nonces(),
         );
 
         assert!(result.is_err());
@@ -358,6 +570,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &nonces(),
         );
 
         assert!(result.is_err());
@@ -376,6 +589,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &nonces(),
         );
 
         assert!(result.is_err());
@@ -394,6 +608,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &nonces(),
         );
 
         assert!(result.is_err());
@@ -412,6 +627,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &nonces(),
         );
 
         assert!(result.is_err());
@@ -433,6 +649,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &nonces(),
         );
 
         assert!(result.is_err());
@@ -451,6 +668,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &nonces(),
         );
 
         assert!(result.is_err());
@@ -469,11 +687,245 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &nonces(),
         );
 
         assert!(result.is_err());
     }
 
+    #[test]
+    fn tokenlib_round_trip() {
+        let shared_secret = "Ted Koppel is a robot";
+        let plaintext = test_token_plaintext();
+        let (token, derived_secret) =
+            Tokenlib::get_token_and_derived_secret(plaintext.clone(), shared_secret).unwrap();
+        let header = make_hawk_header(&token, &derived_secret, "/storage/1.5/1/storage/col2");
+
+        let payload = HawkPayload::new(
+            &header,
+            "GET",
+            "/storage/1.5/1/storage/col2",
+            "localhost",
+            5000,
+            &Secrets::new(shared_secret).unwrap(),
+            Utc::now().timestamp() as u64,
+            &nonces(),
+        )
+        .unwrap();
+
+        assert_eq!(payload.expires, plaintext.expires as f64);
+        assert_eq!(payload.node, plaintext.node);
+        assert_eq!(payload.user_id, plaintext.uid as u64);
+        assert_eq!(payload.fxa_uid, plaintext.fxa_uid);
+        assert_eq!(payload.fxa_kid, plaintext.fxa_kid);
+        assert_eq!(payload.device_id, plaintext.hashed_device_id);
+        assert_eq!(payload.tokenserver_origin, TokenserverOrigin::Rust);
+    }
+
+    #[test]
+    fn tokenlib_round_trip_wrong_secret() {
+        let (token, derived_secret) =
+            Tokenlib::get_token_and_derived_secret(test_token_plaintext(), "Ted Koppel is a robot")
+                .unwrap();
+        let header = make_hawk_header(&token, &derived_secret, "/storage/1.5/1/storage/col2");
+
+        let result = HawkPayload::new(
+            &header,
+            "GET",
+            "/storage/1.5/1/storage/col2",
+            "localhost",
+            5000,
+            &Secrets::new("Ted Koppel is not a robot").unwrap(),
+            Utc::now().timestamp() as u64,
+            &nonces(),
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn tokenlib_round_trip_rotated_secret() {
+        let (token, derived_secret) =
+            Tokenlib::get_token_and_derived_secret(test_token_plaintext(), "Ted Koppel is a robot")
+                .unwrap();
+        let header = make_hawk_header(&token, &derived_secret, "/storage/1.5/1/storage/col2");
+        let validate = |secrets: &[&str]| {
+            HawkPayload::new(
+                &header,
+                "GET",
+                "/storage/1.5/1/storage/col2",
+                "localhost",
+                5000,
+                &Secrets::from_list(secrets).unwrap(),
+                Utc::now().timestamp() as u64,
+                &nonces(),
+            )
+        };
+
+        let payload = validate(&["Ted Koppel is not a robot", "Ted Koppel is a robot"]).unwrap();
+        assert_eq!(payload.secret_index, 1);
+        let payload = validate(&["Ted Koppel is a robot", "Ted Koppel is not a robot"]).unwrap();
+        assert_eq!(payload.secret_index, 0);
+        assert!(validate(&["Ted Koppel is not a robot", "wibble"]).is_err());
+    }
+
+    #[test]
+    fn tokenlib_round_trip_wrong_path() {
+        let (token, derived_secret) =
+            Tokenlib::get_token_and_derived_secret(test_token_plaintext(), "Ted Koppel is a robot")
+                .unwrap();
+        let header = make_hawk_header(&token, &derived_secret, "/storage/1.5/1/storage/col2");
+
+        let result = HawkPayload::new(
+            &header,
+            "GET",
+            "/storage/1.5/1/storage/col3",
+            "localhost",
+            5000,
+            &Secrets::new("Ted Koppel is a robot").unwrap(),
+            Utc::now().timestamp() as u64,
+            &nonces(),
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn replayed_header() {
+        let fixture = TestFixture::new();
+        let nonces = nonces();
+        let validate = || {
+            HawkPayload::new(
+                &fixture.header.to_string(),
+                &fixture.request.method,
+                &fixture.request.path,
+                &fixture.request.host,
+                fixture.request.port,
+                &fixture.master_secret,
+                fixture.expected.expires.round() as u64 - 1,
+                &nonces,
+            )
+        };
+
+        assert!(validate().is_ok());
+        assert!(validate().is_err());
+    }
+
+    #[test]
+    fn stale_header() {
+        let (token, derived_secret) =
+            Tokenlib::get_token_and_derived_secret(test_token_plaintext(), "Ted Koppel is a robot")
+                .unwrap();
+        let header = make_hawk_header(&token, &derived_secret, "/storage/1.5/1/storage/col2");
+
+        let validate = |header: &str| {
+            HawkPayload::new(
+                header,
+                "GET",
+                "/storage/1.5/1/storage/col2",
+                "localhost",
+                5000,
+                &Secrets::new("Ted Koppel is a robot").unwrap(),
+                0,
+                &NonceCache::new(Duration::from_secs(60), 10),
+            )
+        };
+
+        assert!(validate(&header).is_ok());
+        // The fixture's header is years old
+        assert!(validate(&TestFixture::new().header.to_string()).is_err());
+
+        // Stale timestamps are answered with the server's time, MACed with the
+        // token key
+        let credentials = Credentials {
+            id: token,
+            key: Key::new(derived_secret.as_bytes(), DigestAlgorithm::Sha256).unwrap(),
+        };
+        let header = RequestBuilder::new("GET", "localhost", 5000, "/storage/1.5/1/storage/col2")
+            .request()
+            .make_header_full(
+                &credentials,
+                SystemTime::now() - Duration::from_secs(3600),
+                "stale",
+            )
+            .unwrap();
+        let response = validate(&format!("Hawk {}", header))
+            .unwrap_err()
+            .error_response();
+        let challenge = response
+            .headers()
+            .get("WWW-Authenticate")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let ts: u64 = challenge
+            .trim_start_matches("Hawk ts=\"")
+            .split('"')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(
+            challenge,
+            format!(
+                "Hawk ts=\"{}\", tsm=\"{}\", error=\"Stale timestamp\"",
+                ts,
+                timestamp_mac(ts, &derived_secret).unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn nonce_cache() {
+        let nonces = NonceCache::new(Duration::from_secs(60), 2);
+        assert!(nonces.insert("id", "a", 1000, 1000));
+        assert!(!nonces.insert("id", "a", 1000, 1000));
+        // Nonces are per token
+        assert!(nonces.insert("other id", "a", 1000, 1000));
+
+        // Forgotten once outside of the skew window
+        assert!(nonces.insert("id", "a", 1061, 1061));
+
+        // Or, oldest first, when the cache is full
+        assert!(nonces.insert("id", "b", 1061, 1061));
+        assert!(nonces.insert("id", "c", 1061, 1061));
+        assert!(nonces.insert("id", "a", 1061, 1061));
+        assert!(!nonces.insert("id", "c", 1061, 1061));
+    }
+
+    /// The test fixtures are valid until 3018. Add millenia as required.
+    fn nonces() -> NonceCache {
+        NonceCache::new(Duration::from_secs(1000 * 52 * 7 * 24 * 60 * 60), 100)
+    }
+
+    fn test_token_plaintext() -> MakeTokenPlaintext {
+        MakeTokenPlaintext {
+            node: "http://localhost:5000".to_owned(),
+            fxa_kid: "de697ad66d845b2873c9d7e13b8971af".to_owned(),
+            fxa_uid: "319b98f9961ff1dbdd07313cd6ba925a".to_owned(),
+            hashed_device_id: "2bcb92f4d4698c3d7b083a3c698a16ccd78bc2a8d20a96e4bb128ddceaf4e0b6"
+                .to_owned(),
+            hashed_fxa_uid: "0e8df5d41398a389913bd8402435649518af46493da1d4a437a46dc1784c501a"
+                .to_owned(),
+            expires: Utc::now().timestamp() as u64 + 3600,
+            uid: 1,
+            tokenserver_origin: TokenserverOrigin::Rust,
+        }
+    }
+
+    fn make_hawk_header(token: &str, derived_secret: &str, path: &str) -> String {
+        let credentials = Credentials {
+            id: token.to_owned(),
+            key: Key::new(derived_secret.as_bytes(), DigestAlgorithm::Sha256).unwrap(),
+        };
+        let header = RequestBuilder::new("GET", "localhost", 5000, path)
+            .request()
+            .make_header(&credentials)
+            .unwrap();
+
+        format!("Hawk {}", header)
+    }
+
     #[derive(Debug)]
     struct TestFixture {
         pub header: HawkHeader,
@@ -507,6 +959,7 @@ mod tests {
                     fxa_kid: "de697ad66d845b2873c9d7e13b8971af".to_owned(),
                     device_id: "2bcb92f4d4698c3d7b083a3c698a16ccd78bc2a8d20a96e4bb128ddceaf4e0b6".to_owned(),
                     tokenserver_origin: Default::default(),
+                    secret_index: 0,
                 },
             }
         }
@@ -0,0 +1,289 @@
+//! Decoding of compressed request bodies
+//!
+//! Clients may compress storage uploads with `Content-Encoding: gzip`, `deflate` or `br`. Bodies
+//! are decoded as they're read, and the decoded size is capped as it's written, so a small
+//! compressed body can't expand into an unbounded amount of memory (a "zip bomb").
+//!
+//! actix's own `dev::Decompress` isn't used for this: it inflates each chunk of the body in full
+//! before handing it on, so a limit checked against its output only applies after a single
+//! chunk's been expanded in memory. It also passes bodies with an unknown `Content-Encoding`
+//! through undecoded, where they should be rejected.
+use std::io::{self, Write};
+
+use actix_web::{dev::Payload, http::header::CONTENT_ENCODING, HttpRequest};
+use brotli::DecompressorWriter;
+use flate2::write::{GzDecoder, ZlibDecoder};
+use futures::StreamExt;
+
+use crate::label;
+use crate::web::error::ValidationErrorKind;
+use crate::web::extractors::RequestErrorLocation;
+
+/// Buffer size for the brotli decoder's internal buffer
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+/// Reads the entire request body, decoding it according to its `Content-Encoding`.
+///
+/// Fails if the decoded body would exceed `limit` bytes.
+pub async fn read_body(
+    req: &HttpRequest,
+    payload: &mut Payload,
+    limit: usize,
+) -> Result<Vec<u8>, ValidationErrorKind> {
+    let mut decoder = BodyDecoder::from_request(req, limit)?;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| {
+            warn!("⚠️ Payload read error: {:?}", e);
+            ValidationErrorKind::FromDetails(
+                "Mimetype/encoding/content-length error".to_owned(),
+                RequestErrorLocation::Header,
+                None,
+                None,
+            )
+        })?;
+        decoder.write_all(&chunk).map_err(|e| decoder.error(e))?;
+    }
+    decoder.finish()
+}
+
+/// Writes decoded output into a buffer, refusing to grow it past a limit.
+struct LimitedWriter {
+    buf: Vec<u8>,
+    limit: usize,
+    exceeded: bool,
+}
+
+impl LimitedWriter {
+    fn new(limit: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            limit,
+            exceeded: false,
+        }
+    }
+}
+
+impl Write for LimitedWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > self.limit {
+            self.exceeded = true;
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Decoded request body too large",
+            ));
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum BodyDecoder {
+    Identity(LimitedWriter),
+    Gzip(GzDecoder<LimitedWriter>),
+    Deflate(ZlibDecoder<LimitedWriter>),
+    Brotli(Box<DecompressorWriter<LimitedWriter>>),
+}
+
+impl BodyDecoder {
+    fn from_request(req: &HttpRequest, limit: usize) -> Result<Self, ValidationErrorKind> {
+        let encoding = match req.headers().get(CONTENT_ENCODING) {
+            Some(value) => value
+                .to_str()
+                .map_err(|_| unsupported_encoding("Unreadable Content-Encoding"))?
+                .trim()
+                .to_ascii_lowercase(),
+            None => return Ok(BodyDecoder::Identity(LimitedWriter::new(limit))),
+        };
+
+        let writer = LimitedWriter::new(limit);
+        Ok(match encoding.as_str() {
+            "" | "identity" => BodyDecoder::Identity(writer),
+            "gzip" | "x-gzip" => BodyDecoder::Gzip(GzDecoder::new(writer)),
+            "deflate" => BodyDecoder::Deflate(ZlibDecoder::new(writer)),
+            "br" => BodyDecoder::Brotli(Box::new(DecompressorWriter::new(
+                writer,
+                BROTLI_BUFFER_SIZE,
+            ))),
+            _ => {
+                return Err(unsupported_encoding(&format!(
+                    "Unsupported Content-Encoding: {:?}",
+                    encoding
+                )))
+            }
+        })
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            BodyDecoder::Identity(writer) => writer.write_all(data),
+            BodyDecoder::Gzip(decoder) => decoder.write_all(data),
+            BodyDecoder::Deflate(decoder) => decoder.write_all(data),
+            BodyDecoder::Brotli(decoder) => decoder.write_all(data),
+        }
+    }
+
+    fn finish(self) -> Result<Vec<u8>, ValidationErrorKind> {
+        let (result, encoding) = match self {
+            BodyDecoder::Identity(writer) => return Ok(writer.buf),
+            BodyDecoder::Gzip(decoder) => (decoder.finish(), "gzip"),
+            BodyDecoder::Deflate(decoder) => (decoder.finish(), "deflate"),
+            BodyDecoder::Brotli(decoder) => (
+                (*decoder).into_inner().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Truncated brotli stream")
+                }),
+                "br",
+            ),
+        };
+        match result {
+            Ok(writer) if !writer.exceeded => Ok(writer.buf),
+            Ok(_) => Err(too_large()),
+            Err(e) => {
+                warn!("⚠️ Could not decode {} request body: {:?}", encoding, e);
+                Err(corrupt_body(encoding))
+            }
+        }
+    }
+
+    /// Converts an error raised while decoding into a validation error.
+    fn error(&self, e: io::Error) -> ValidationErrorKind {
+        let (writer, encoding) = match self {
+            BodyDecoder::Identity(writer) => (writer, "identity"),
+            BodyDecoder::Gzip(decoder) => (decoder.get_ref(), "gzip"),
+            BodyDecoder::Deflate(decoder) => (decoder.get_ref(), "deflate"),
+            BodyDecoder::Brotli(decoder) => (decoder.get_ref(), "br"),
+        };
+        if writer.exceeded {
+            too_large()
+        } else {
+            warn!("⚠️ Could not decode {} request body: {:?}", encoding, e);
+            corrupt_body(encoding)
+        }
+    }
+}
+
+fn unsupported_encoding(description: &str) -> ValidationErrorKind {
+    ValidationErrorKind::FromDetails(
+        description.to_owned(),
+        RequestErrorLocation::Header,
+        Some("Content-Encoding".to_owned()),
+        label!("request.error.invalid_content_encoding"),
+    )
+}
+
+fn too_large() -> ValidationErrorKind {
+    ValidationErrorKind::FromDetails(
+        "size-limit-exceeded".to_owned(),
+        RequestErrorLocation::Body,
+        None,
+        label!("request.error.body_too_large"),
+    )
+}
+
+fn corrupt_body(encoding: &str) -> ValidationErrorKind {
+    ValidationErrorKind::FromDetails(
+        format!("Invalid {} encoded request body", encoding),
+        RequestErrorLocation::Body,
+        None,
+        label!("request.error.invalid_body_encoding"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use brotli::CompressorWriter;
+    use flate2::{
+        write::{GzEncoder, ZlibEncoder},
+        Compression,
+    };
+
+    use super::*;
+
+    async fn read(req: TestRequest, body: Vec<u8>, limit: usize) -> Result<Vec<u8>, String> {
+        let (req, mut payload) = req.set_payload(body).to_http_parts();
+        read_body(&req, &mut payload, limit)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn brotli(data: &[u8]) -> Vec<u8> {
+        let mut encoder = CompressorWriter::new(Vec::new(), BROTLI_BUFFER_SIZE, 5, 22);
+        encoder.write_all(data).unwrap();
+        encoder.into_inner()
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_body() {
+        let body = read(TestRequest::default(), b"[]".to_vec(), 2).await;
+        assert_eq!(body.unwrap(), b"[]");
+
+        let body = read(TestRequest::default(), b"[{}]".to_vec(), 2).await;
+        assert_eq!(body.unwrap_err(), "size-limit-exceeded");
+    }
+
+    #[actix_rt::test]
+    async fn test_gzip_body() {
+        let req = TestRequest::default().header("Content-Encoding", "gzip");
+        let body = read(req, gzip(b"[{\"id\": \"1\"}]"), 1024).await;
+        assert_eq!(body.unwrap(), b"[{\"id\": \"1\"}]");
+    }
+
+    #[actix_rt::test]
+    async fn test_deflate_body() {
+        let req = TestRequest::default().header("Content-Encoding", "deflate");
+        let body = read(req, deflate(b"[{\"id\": \"1\"}]"), 1024).await;
+        assert_eq!(body.unwrap(), b"[{\"id\": \"1\"}]");
+    }
+
+    #[actix_rt::test]
+    async fn test_brotli_body() {
+        let req = TestRequest::default().header("Content-Encoding", "br");
+        let body = read(req, brotli(b"[{\"id\": \"1\"}]"), 1024).await;
+        assert_eq!(body.unwrap(), b"[{\"id\": \"1\"}]");
+
+        let req = TestRequest::default().header("Content-Encoding", "br");
+        let body = read(req, brotli(&[b' '; 64 * 1024]), 1024).await;
+        assert_eq!(body.unwrap_err(), "size-limit-exceeded");
+    }
+
+    #[actix_rt::test]
+    async fn test_decoded_size_is_limited() {
+        // Compresses down to well under the limit
+        let bomb = gzip(&[b' '; 64 * 1024]);
+        assert!(bomb.len() < 1024);
+
+        let req = TestRequest::default().header("Content-Encoding", "gzip");
+        let body = read(req, bomb, 1024).await;
+        assert_eq!(body.unwrap_err(), "size-limit-exceeded");
+    }
+
+    #[actix_rt::test]
+    async fn test_corrupt_body() {
+        let req = TestRequest::default().header("Content-Encoding", "gzip");
+        let body = read(req, b"[]".to_vec(), 1024).await;
+        assert_eq!(body.unwrap_err(), "Invalid gzip encoded request body");
+    }
+
+    #[actix_rt::test]
+    async fn test_unsupported_encoding() {
+        let req = TestRequest::default().header("Content-Encoding", "zstd");
+        let body = read(req, b"[]".to_vec(), 1024).await;
+        assert_eq!(body.unwrap_err(), "Unsupported Content-Encoding: \"zstd\"");
+    }
+}
@@ -44,6 +44,10 @@ impl HawkError {
             HawkErrorKind::MissingId => Some("request.error.hawk.missing_id".to_owned()),
             HawkErrorKind::MissingPrefix => Some("request.error.hawk.missing_prefix".to_owned()),
             HawkErrorKind::Parse(_) => Some("request.error.hawk.parse_error".to_owned()),
+            HawkErrorKind::Replayed => Some("request.error.hawk.replayed".to_owned()),
+            HawkErrorKind::StaleTimestamp { .. } => {
+                Some("request.error.hawk.stale_timestamp".to_owned())
+            }
             HawkErrorKind::TruncatedId => Some("request.error.hawk.id_too_short".to_owned()),
             _ => None,
         }
@@ -89,6 +93,14 @@ pub enum HawkErrorKind {
     #[error("{}", _0)]
     Parse(ParseError),
 
+    #[error("nonce already used")]
+    Replayed,
+
+    /// `ts` is the server's time and `tsm` its MAC under the token key, for the
+    /// client to correct for its clock skew with
+    #[error("stale timestamp")]
+    StaleTimestamp { ts: u64, tsm: String },
+
     #[error("id property is too short")]
     TruncatedId,
 }
@@ -186,7 +198,7 @@ impl From<ValidationErrorKind> for ValidationError {
             {
                 match name.to_ascii_lowercase().as_str() {
                     "accept" => StatusCode::NOT_ACCEPTABLE,
-                    "content-type" => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "content-type" | "content-encoding" => StatusCode::UNSUPPORTED_MEDIA_TYPE,
                     _ => StatusCode::BAD_REQUEST,
                 }
             }
@@ -4,6 +4,7 @@
 //! relevant types, and failing correctly with the appropriate errors if issues arise.
 use std::{
     self, collections::HashMap, collections::HashSet, num::ParseIntError, str::FromStr, sync::Arc,
+    time::Duration,
 };
 
 use actix_web::{
@@ -12,7 +13,7 @@ use actix_web::{
         header::{qitem, Accept, ContentType, Header, HeaderMap},
         Uri,
     },
-    web::{Data, Json, Query},
+    web::{Data, Query},
     Error, FromRequest, HttpMessage, HttpRequest,
 };
 
@@ -21,6 +22,7 @@ use syncserver_settings::Secrets;
 
 use lazy_static::lazy_static;
 use mime::STAR_STAR;
+use opentelemetry::trace::Tracer;
 use regex::Regex;
 use serde::{
     de::{Deserializer, Error as SerdeError, IgnoredAny},
@@ -39,9 +41,11 @@ use crate::db::transaction::DbTransactionPool;
 use crate::error::{ApiError, ApiErrorKind};
 use crate::label;
 use crate::server::{metrics, ServerState, BSO_ID_REGEX, COLLECTION_ID_REGEX};
+use crate::telemetry;
 use crate::tokenserver::auth::TokenserverOrigin;
 use crate::web::{
-    auth::HawkPayload,
+    auth::{HawkPayload, NonceCache},
+    encoding::read_body,
     error::{HawkErrorKind, ValidationErrorKind},
     tags::Tags,
     DOCKER_FLOW_ENDPOINTS,
@@ -55,6 +59,14 @@ const BSO_MIN_SORTINDEX_VALUE: i32 = -999_999_999;
 
 const ACCEPTED_CONTENT_TYPES: [&str; 3] =
     ["application/json", "text/plain", "application/newlines"];
+// Collection reads and writes additionally accept the binary formats
+const COLLECTION_CONTENT_TYPES: [&str; 5] = [
+    "application/json",
+    "text/plain",
+    "application/newlines",
+    "application/cbor",
+    "application/msgpack",
+];
 
 lazy_static! {
     static ref KNOWN_BAD_PAYLOAD_REGEX: Regex =
@@ -198,45 +210,42 @@ impl FromRequest for BsoBodies {
         let content_type = format!("{}/{}", ctype.type_(), ctype.subtype());
         trace!("BSO Body content_type: {:?}", &content_type);
 
-        if !ACCEPTED_CONTENT_TYPES.contains(&content_type.as_ref()) {
-            return Box::pin(future::err(
-                ValidationErrorKind::FromDetails(
-                    format!("Invalid Content-Type {:?}", content_type),
-                    RequestErrorLocation::Header,
-                    Some("Content-Type".to_owned()),
-                    label!("request.error.invalid_content_type"),
-                )
-                .into(),
-            ));
-        }
-
-        // Load the entire request into a String
-        let fut = <String>::from_request(req, payload).map_err(|e| {
-            warn!("⚠️ Payload read error: {:?}", e);
-            ValidationErrorKind::FromDetails(
-                "Mimetype/encoding/content-length error".to_owned(),
-                RequestErrorLocation::Header,
-                None,
-                None,
-            )
-            .into()
-        });
+        let format = match ReplyFormat::from_content_type(&content_type) {
+            Some(format) => format,
+            None => {
+                return Box::pin(future::err(
+                    ValidationErrorKind::FromDetails(
+                        format!("Invalid Content-Type {:?}", content_type),
+                        RequestErrorLocation::Header,
+                        Some("Content-Type".to_owned()),
+                        label!("request.error.invalid_content_type"),
+                    )
+                    .into(),
+                ))
+            }
+        };
 
         // Avoid duplicating by defining our error func now, doesn't need the box wrapper
-        fn make_error() -> Error {
+        fn make_error(format: ReplyFormat) -> Error {
+            let (name, label) = match format {
+                ReplyFormat::Json | ReplyFormat::Newlines => {
+                    ("JSON", label!("request.validate.invalid_body_json"))
+                }
+                ReplyFormat::Cbor => ("CBOR", label!("request.validate.invalid_body_cbor")),
+                ReplyFormat::Msgpack => (
+                    "MessagePack",
+                    label!("request.validate.invalid_body_msgpack"),
+                ),
+            };
             ValidationErrorKind::FromDetails(
-                "Invalid JSON in request body".to_owned(),
+                format!("Invalid {} in request body", name),
                 RequestErrorLocation::Body,
                 Some("bsos".to_owned()),
-                label!("request.validate.invalid_body_json"),
+                label,
             )
             .into()
         }
 
-        // Define a new bool to check from a static closure to release the reference on the
-        // content_type header
-        let newlines: bool = content_type == "application/newlines";
-
         // Grab the max sizes
         let state = match req.app_data::<Data<ServerState>>() {
             Some(s) => s,
@@ -254,28 +263,54 @@ impl FromRequest for BsoBodies {
             }
         };
 
+        let max_request_bytes = state.limits.max_request_bytes as usize;
         let max_payload_size = state.limits.max_record_payload_bytes as usize;
         let max_post_bytes = state.limits.max_post_bytes as usize;
 
-        let fut = fut.and_then(move |body| {
-            // Get all the raw / values
-            let bsos: Vec<Value> = if newlines {
-                let mut bsos = Vec::new();
-                for item in body.lines() {
-                    // Check that its a valid JSON map like we expect
-                    if let Ok(raw_json) = serde_json::from_str::<Value>(item) {
-                        bsos.push(raw_json);
-                    } else {
-                        // Per Python version, BSO's must json deserialize
-                        return future::err(make_error());
+        // Load the entire (decoded) request body. The limit applies to the
+        // body as sent, whatever its format
+        let req = req.clone();
+        let mut payload = payload.take();
+        let fut = async move {
+            read_body(&req, &mut payload, max_request_bytes)
+                .await
+                .map_err(|e| -> Error { e.into() })
+        };
+
+        let fut = fut.and_then(move |body: Vec<u8>| {
+            // Get all the raw / values. Binary formats are decoded into the
+            // same JSON values, so the checks below apply to every format
+            let bsos: Vec<Value> = match format {
+                ReplyFormat::Newlines => {
+                    let body = match std::str::from_utf8(&body) {
+                        Ok(body) => body,
+                        Err(_) => return future::err(make_error(format)),
+                    };
+                    let mut bsos = Vec::new();
+                    for item in body.lines() {
+                        // Check that its a valid JSON map like we expect
+                        if let Ok(raw_json) = serde_json::from_str::<Value>(item) {
+                            bsos.push(raw_json);
+                        } else {
+                            // Per Python version, BSO's must json deserialize
+                            return future::err(make_error(format));
+                        }
                     }
+                    bsos
                 }
-                bsos
-            } else if let Ok(json_vals) = serde_json::from_str::<Vec<Value>>(&body) {
-                json_vals
-            } else {
-                // Per Python version, BSO's must json deserialize
-                return future::err(make_error());
+                ReplyFormat::Json => match serde_json::from_slice::<Vec<Value>>(&body) {
+                    Ok(json_vals) => json_vals,
+                    // Per Python version, BSO's must json deserialize
+                    Err(_) => return future::err(make_error(format)),
+                },
+                ReplyFormat::Cbor => match serde_cbor::from_slice::<Vec<Value>>(&body) {
+                    Ok(vals) => vals,
+                    Err(_) => return future::err(make_error(format)),
+                },
+                ReplyFormat::Msgpack => match rmp_serde::from_read_ref::<_, Vec<Value>>(&body) {
+                    Ok(vals) => vals,
+                    Err(_) => return future::err(make_error(format)),
+                },
             };
 
             // Validate all the BSO's, move invalid to our other list. Assume they'll all make
@@ -295,7 +330,7 @@ impl FromRequest for BsoBodies {
             for bso in bsos {
                 // Error out if its not a JSON mapping type
                 if !bso.is_object() {
-                    return future::err(make_error());
+                    return future::err(make_error(format));
                 }
                 // Save all id's we get, check for missing id, or duplicate.
                 let bso_id = if let Some(id) = bso.get("id").and_then(serde_json::Value::as_str) {
@@ -349,7 +384,7 @@ impl FromRequest for BsoBodies {
             future::ok(BsoBodies { valid, invalid })
         });
 
-        Box::pin(fut)
+        Box::pin(telemetry::in_span("extract.bso_bodies", fut))
     }
 }
 
@@ -425,19 +460,19 @@ impl FromRequest for BsoBody {
 
             let max_payload_size = state.limits.max_record_payload_bytes as usize;
 
-            let bso = <Json<BsoBody>>::from_request(&req, &mut payload)
-                .await
-                .map_err(|e| {
-                    warn!("⚠️ Could not parse BSO Body: {:?}", e);
-                    let err: ApiError = ValidationErrorKind::FromDetails(
-                        e.to_string(),
-                        RequestErrorLocation::Body,
-                        Some("bso".to_owned()),
-                        label!("request.validate.bad_bso_body"),
-                    )
-                    .into();
-                    err
-                })?;
+            let max_request_bytes = state.limits.max_request_bytes as usize;
+            let body = read_body(&req, &mut payload, max_request_bytes).await?;
+            let bso = serde_json::from_slice::<BsoBody>(&body).map_err(|e| {
+                warn!("⚠️ Could not parse BSO Body: {:?}", e);
+                let err: ApiError = ValidationErrorKind::FromDetails(
+                    e.to_string(),
+                    RequestErrorLocation::Body,
+                    Some("bso".to_owned()),
+                    label!("request.validate.bad_bso_body"),
+                )
+                .into();
+                err
+            })?;
 
             // Check the max payload size manually with our desired limit
             if bso
@@ -463,7 +498,7 @@ impl FromRequest for BsoBody {
                 )
                 .into());
             }
-            Ok(bso.into_inner())
+            Ok(bso)
         })
     }
 }
@@ -662,11 +697,125 @@ impl FromRequest for MetaRequest {
     }
 }
 
-/// Desired reply format for a Collection Get request
-#[derive(Copy, Clone, Debug)]
+/// Change notification request extractor
+///
+/// Extracts the user and the query parameters of a `/changes` long-poll request.
+pub struct ChangesRequest {
+    pub user_id: UserIdentifier,
+    pub tokenserver_origin: TokenserverOrigin,
+    /// Report collections modified after this time, or after the user's storage was last
+    /// modified if not given.
+    pub since: Option<SyncTimestamp>,
+    /// How long to wait for a change before answering the request.
+    pub timeout: Duration,
+    pub metrics: metrics::Metrics,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ChangesQueryParams {
+    #[serde(deserialize_with = "deserialize_sync_timestamp")]
+    since: Option<SyncTimestamp>,
+    timeout: Option<u64>,
+}
+
+impl FromRequest for ChangesRequest {
+    type Config = ();
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let mut payload = Payload::None;
+        async move {
+            let user_id = HawkIdentifier::from_request(&req, &mut payload).await?;
+            let params = Query::<ChangesQueryParams>::from_request(&req, &mut payload)
+                .await
+                .map_err(|e| {
+                    ValidationErrorKind::FromDetails(
+                        e.to_string(),
+                        RequestErrorLocation::QueryString,
+                        None,
+                        None,
+                    )
+                })?
+                .into_inner();
+            let max_timeout = match req.app_data::<Data<ServerState>>() {
+                Some(state) => state.max_changes_poll_timeout,
+                None => {
+                    error!("⚠️ Could not load the app state");
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Internal error".to_owned(),
+                        RequestErrorLocation::Unknown,
+                        Some("app_data".to_owned()),
+                        None,
+                    )
+                    .into());
+                }
+            };
+
+            Ok(ChangesRequest {
+                tokenserver_origin: user_id.tokenserver_origin,
+                user_id: user_id.into(),
+                since: params.since,
+                timeout: Duration::from_secs(
+                    params.timeout.map_or(max_timeout, |t| t.min(max_timeout)),
+                ),
+                metrics: metrics::Metrics::extract(&req).await?,
+            })
+        }
+        .boxed_local()
+    }
+}
+
+/// Desired reply format for a Collection Get or Post request
+///
+/// Also describes the format of a Collection Post's request body.
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum ReplyFormat {
     Json,
     Newlines,
+    Cbor,
+    Msgpack,
+}
+
+impl ReplyFormat {
+    fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type {
+            "application/json" | "text/plain" => Some(ReplyFormat::Json),
+            "application/newlines" => Some(ReplyFormat::Newlines),
+            "application/cbor" => Some(ReplyFormat::Cbor),
+            "application/msgpack" => Some(ReplyFormat::Msgpack),
+            _ => None,
+        }
+    }
+
+    /// Negotiate the reply format from the request's Accept header
+    fn from_accept(req: &HttpRequest) -> Result<Self, Error> {
+        let accept = get_accepted(req, &COLLECTION_CONTENT_TYPES, "application/json");
+        match accept.as_str() {
+            "application/newlines" => Ok(ReplyFormat::Newlines),
+            "application/cbor" => Ok(ReplyFormat::Cbor),
+            "application/msgpack" => Ok(ReplyFormat::Msgpack),
+            "application/json" | "" => Ok(ReplyFormat::Json),
+            _ => Err(ValidationErrorKind::FromDetails(
+                format!("Invalid Accept header specified: {:?}", accept),
+                RequestErrorLocation::Header,
+                Some("accept".to_string()),
+                label!("request.validate.invalid_accept_header"),
+            )
+            .into()),
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ReplyFormat::Json => "application/json",
+            ReplyFormat::Newlines => "application/newlines",
+            ReplyFormat::Cbor => "application/cbor",
+            ReplyFormat::Msgpack => "application/msgpack",
+        }
+    }
 }
 
 /// Collection Request Delete/Get extractor
@@ -677,6 +826,7 @@ pub struct CollectionRequest {
     pub user_id: UserIdentifier,
     pub tokenserver_origin: TokenserverOrigin,
     pub query: BsoQueryParams,
+    pub batch: Option<BatchRequest>,
     pub reply: ReplyFormat,
     pub metrics: metrics::Metrics,
 }
@@ -698,26 +848,15 @@ impl FromRequest for CollectionRequest {
                 .await?;
             let collection = collection.collection;
 
-            let accept = get_accepted(&req, &ACCEPTED_CONTENT_TYPES, "application/json");
-            let reply = match accept.as_str() {
-                "application/newlines" => ReplyFormat::Newlines,
-                "application/json" | "" => ReplyFormat::Json,
-                _ => {
-                    return Err(ValidationErrorKind::FromDetails(
-                        format!("Invalid Accept header specified: {:?}", accept),
-                        RequestErrorLocation::Header,
-                        Some("accept".to_string()),
-                        label!("request.validate.invalid_accept_header"),
-                    )
-                    .into());
-                }
-            };
+            let reply = ReplyFormat::from_accept(&req)?;
 
+            let batch = BatchRequestOpt::extract(&req).await?;
             Ok(CollectionRequest {
                 collection,
                 tokenserver_origin: user_id.tokenserver_origin,
                 user_id: user_id.into(),
                 query,
+                batch: batch.opt,
                 reply,
                 metrics: metrics::Metrics::extract(&req).await?,
             })
@@ -738,6 +877,7 @@ pub struct CollectionPostRequest {
     pub query: BsoQueryParams,
     pub bsos: BsoBodies,
     pub batch: Option<BatchRequest>,
+    pub reply: ReplyFormat,
     pub metrics: metrics::Metrics,
     pub quota_enabled: bool,
 }
@@ -772,6 +912,7 @@ impl FromRequest for CollectionPostRequest {
             };
 
             let max_post_records = i64::from(state.limits.max_post_records);
+            let reply = ReplyFormat::from_accept(&req)?;
 
             let (user_id, collection, query, mut bsos) =
                 <(HawkIdentifier, CollectionParam, BsoQueryParams, BsoBodies)>::from_request(
@@ -817,6 +958,7 @@ impl FromRequest for CollectionPostRequest {
                 query,
                 bsos,
                 batch: batch.opt,
+                reply,
                 metrics: metrics::Metrics::extract(&req).await?,
                 quota_enabled: state.quota_enabled,
             })
@@ -1068,6 +1210,8 @@ impl HawkIdentifier {
         uri: &Uri,
         ci: &ConnectionInfo,
         secrets: &Secrets,
+        nonces: &NonceCache,
+        metrics: &metrics::Metrics,
     ) -> Result<Self, Error>
     where
         T: HttpMessage,
@@ -1084,6 +1228,8 @@ impl HawkIdentifier {
             .map_err(|e| -> ApiError { HawkErrorKind::Header(e).into() })?;
         let identifier = Self::generate(
             secrets,
+            nonces,
+            metrics,
             method,
             auth_header,
             ci,
@@ -1094,15 +1240,20 @@ impl HawkIdentifier {
         Ok(identifier)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn generate(
         secrets: &Secrets,
+        nonces: &NonceCache,
+        metrics: &metrics::Metrics,
         method: &str,
         header: &str,
         connection_info: &ConnectionInfo,
         uri: &Uri,
         exts: &mut Extensions,
     ) -> Result<Self, Error> {
-        let payload = HawkPayload::extrude(header, method, secrets, connection_info, uri)?;
+        let (payload, nonce) = telemetry::tracer().in_span("hawk.validate", |_| {
+            HawkPayload::extrude(header, method, secrets, connection_info, uri, nonces)
+        })?;
         let puid = Self::uid_from_path(uri)?;
         if payload.user_id != puid {
             warn!("⚠️ Hawk UID not in URI: {:?} {:?}", payload.user_id, uri);
@@ -1113,10 +1264,20 @@ impl HawkIdentifier {
                 label!("request.validate.hawk.uri_missing_uid"),
             ))?;
         }
+        // Only a valid request uses up its nonce
+        nonce.record(nonces)?;
 
         // Store the origin of the token so we can later use it as a tag when emitting metrics
         exts.insert(payload.tokenserver_origin);
 
+        // Track which master secret validated the token, to tell when a
+        // rotated out secret is no longer in use
+        metrics.incr_with_tag(
+            "request.hawk.secret",
+            "secret_index",
+            &payload.secret_index.to_string(),
+        );
+
         let user_id = HawkIdentifier {
             legacy_id: payload.user_id,
             fxa_uid: payload.fxa_uid,
@@ -1162,8 +1323,23 @@ impl FromRequest for HawkIdentifier {
                 return future::ready(Err(err.into()));
             }
         };
+        let state = match req.app_data::<Data<ServerState>>() {
+            Some(v) => v,
+            None => {
+                let err: ApiError = ApiErrorKind::NoServerState.into();
+                return future::ready(Err(err.into()));
+            }
+        };
 
-        let result = Self::extrude(&req, method.as_str(), uri, &connection_info, secrets);
+        let result = Self::extrude(
+            &req,
+            method.as_str(),
+            uri,
+            &connection_info,
+            secrets,
+            &state.hawk_nonces,
+            &metrics::Metrics::from(state.get_ref()),
+        );
 
         if let Ok(ref hawk_id) = result {
             // Store the origin of the token as an extra to be included when emitting a Sentry error
@@ -1728,6 +1904,7 @@ macro_rules! impl_emit_api_metric {
 }
 
 impl_emit_api_metric!(MetaRequest);
+impl_emit_api_metric!(ChangesRequest);
 impl_emit_api_metric!(CollectionRequest);
 impl_emit_api_metric!(CollectionPostRequest);
 impl_emit_api_metric!(BsoRequest);
@@ -1764,7 +1941,7 @@ mod tests {
     use tokio::sync::RwLock;
 
     use crate::db::mock::{MockDb, MockDbPool};
-    use crate::server::{metrics, ServerState};
+    use crate::server::{changes::BroadcastHub, metrics, shutdown::InFlight, ServerState};
 
     use crate::web::auth::HawkPayload;
 
@@ -1804,6 +1981,17 @@ mod tests {
             ),
             quota_enabled: syncstorage_settings.enable_quota,
             deadman: Arc::new(RwLock::new(Deadman::default())),
+            changes: Arc::new(BroadcastHub::default()),
+            max_changes_poll_timeout: syncstorage_settings.max_changes_poll_timeout,
+            response_compression_min_bytes: syncstorage_settings.response_compression_min_bytes
+                as usize,
+            rate_limiter: None,
+            backoff_interval: syncstorage_settings.backoff_interval,
+            backoff_interval_jitter: syncstorage_settings.backoff_interval_jitter,
+            backoff_on_pool_saturation: syncstorage_settings.backoff_on_pool_saturation,
+            hawk_nonces: Arc::new(NonceCache::from(&syncstorage_settings)),
+            access_log_sample_rate: syncstorage_settings.access_log_sample_rate,
+            transactions: Arc::new(InFlight::default()),
         }
     }
 
@@ -1847,6 +2035,14 @@ mod tests {
     async fn post_collection(
         qs: &str,
         body: &serde_json::Value,
+    ) -> Result<CollectionPostRequest, Error> {
+        post_collection_as(qs, "application/json; charset=UTF-8", body.to_string()).await
+    }
+
+    async fn post_collection_as(
+        qs: &str,
+        content_type: &str,
+        body: impl Into<Bytes>,
     ) -> Result<CollectionPostRequest, Error> {
         let payload = HawkPayload::test_default(*USER_ID);
         let state = make_state();
@@ -1857,7 +2053,7 @@ mod tests {
             if !qs.is_empty() { "?" } else { "" },
             qs
         );
-        let bod_str = body.to_string();
+        let body = body.into();
         let header =
             create_valid_hawk_header(&payload, &secrets, "POST", &path, TEST_HOST, TEST_PORT);
         let req = TestRequest::with_uri(&format!("http://{}:{}{}", TEST_HOST, TEST_PORT, path))
@@ -1865,9 +2061,9 @@ mod tests {
             .data(secrets)
             .method(Method::POST)
             .header("authorization", header)
-            .header("content-type", "application/json; charset=UTF-8")
+            .header("content-type", content_type)
             .header("accept", "application/json;q=0.9,/;q=0.2")
-            .set_payload(bod_str.to_owned())
+            .set_payload(body.clone())
             .param("uid", &USER_ID_STR)
             .param("collection", "tabs")
             .to_http_request();
@@ -1876,7 +2072,7 @@ mod tests {
         // Not sure why but sending req through *::extract loses the body.
         // Compose a payload here and call the *::from_request
         let (_sender, mut payload) = h1::Payload::create(true);
-        payload.unread_data(Bytes::from(bod_str.to_owned()));
+        payload.unread_data(body);
         CollectionPostRequest::from_request(&req, &mut payload.into()).await
     }
 
@@ -2212,6 +2408,60 @@ mod tests {
         assert_eq!(result.bsos.invalid.len(), 2);
     }
 
+    #[actix_rt::test]
+    async fn test_binary_collection_post_request() {
+        let bsos = vec![
+            BsoBody {
+                id: Some("123".to_owned()),
+                payload: Some("xxx".to_owned()),
+                sortindex: Some(23),
+                ..Default::default()
+            },
+            BsoBody {
+                id: Some("456".to_owned()),
+                payload: Some("x".repeat(SERVER_LIMITS.max_record_payload_bytes as usize + 1)),
+                ..Default::default()
+            },
+        ];
+
+        let cbor = serde_cbor::to_vec(&bsos).unwrap();
+        let msgpack = rmp_serde::to_vec_named(&bsos).unwrap();
+        for (content_type, body) in
+            vec![("application/cbor", cbor), ("application/msgpack", msgpack)]
+        {
+            let result = post_collection_as("", content_type, body)
+                .await
+                .expect("Could not get result in test_binary_collection_post_request");
+            assert_eq!(result.bsos.valid.len(), 1);
+            assert_eq!(result.bsos.valid[0].sortindex, Some(23));
+            // The same limits apply as for JSON bodies
+            assert_eq!(result.bsos.invalid["456"], "retry bytes");
+        }
+
+        // JSON isn't valid CBOR
+        let result = post_collection_as("", "application/cbor", b"[{}]".to_vec()).await;
+        let response: HttpResponse = result.err().unwrap().into();
+        assert_eq!(response.status(), 400);
+    }
+
+    #[test]
+    fn test_reply_format() {
+        let reply_format = |accept: &'static str| {
+            ReplyFormat::from_accept(
+                &TestRequest::with_header(ACCEPT, HeaderValue::from_static(accept))
+                    .to_http_request(),
+            )
+            .ok()
+        };
+        assert_eq!(reply_format("application/cbor"), Some(ReplyFormat::Cbor));
+        assert_eq!(
+            reply_format("application/json;q=0.5,application/msgpack"),
+            Some(ReplyFormat::Msgpack)
+        );
+        assert_eq!(reply_format("*/*"), Some(ReplyFormat::Json));
+        assert_eq!(reply_format("foo/bar"), None);
+    }
+
     #[actix_rt::test]
     async fn test_valid_collection_batch_post_request() {
         // If the "batch" parameter is has no value or has a value of "true"
@@ -2360,18 +2610,20 @@ mod tests {
         // the uid in the hawk payload should match the UID in the path.
         let hawk_payload = HawkPayload::test_default(*USER_ID);
         let mismatch_uid = "5";
-        let state = make_state();
         let secrets = Arc::clone(&SECRETS);
         let uri = format!("/1.5/{}/storage/col2", mismatch_uid);
         let header =
             create_valid_hawk_header(&hawk_payload, &secrets, "GET", &uri, TEST_HOST, TEST_PORT);
-        let req = TestRequest::with_uri(&uri)
-            .data(state)
-            .data(secrets)
-            .header("authorization", header)
-            .method(Method::GET)
-            .param("uid", mismatch_uid)
-            .to_http_request();
+        let make_req = || {
+            TestRequest::with_uri(&uri)
+                .data(make_state())
+                .data(Arc::clone(&secrets))
+                .header("authorization", header.clone())
+                .method(Method::GET)
+                .param("uid", mismatch_uid)
+                .to_http_request()
+        };
+        let req = make_req();
         let result = block_on(HawkIdentifier::extract(&req));
         assert!(result.is_err());
         let response: HttpResponse = result.err().unwrap().into();
@@ -2379,6 +2631,12 @@ mod tests {
         let body = extract_body_as_str(ServiceResponse::new(req, response));
         assert_eq!(body, "0");
 
+        // The rejected request didn't use up its nonce, so extracting it again
+        // reports the same error rather than a replay
+        let result = block_on(HawkIdentifier::extract(&make_req()));
+        let response: HttpResponse = result.err().unwrap().into();
+        assert_eq!(response.status(), 400);
+
         /* New tests for when we can use descriptive errors
 
         let err: serde_json::Value = serde_json::from_str(&body).unwrap();
@@ -1,8 +1,10 @@
 //! API Handlers
 use std::collections::HashMap;
 use std::convert::Into;
+use std::sync::Arc;
 
 use actix_web::{dev::HttpResponseBuilder, http::StatusCode, web::Data, HttpRequest, HttpResponse};
+use futures::StreamExt;
 use serde::Serialize;
 use serde_json::{json, Map, Value};
 use syncserver_common::{X_LAST_MODIFIED, X_WEAVE_NEXT_OFFSET, X_WEAVE_RECORDS};
@@ -10,18 +12,21 @@ use syncserver_db_common::{
     error::{DbError, DbErrorKind},
     params,
     results::{CreateBatch, Paginated},
-    Db,
+    util::SyncTimestamp,
+    Db, UserIdentifier,
 };
 use time;
+use tokio::time::{timeout_at, Instant};
 
 use crate::{
-    db::transaction::DbTransactionPool,
+    db::transaction::{storage_changed, DbTransactionPool},
     error::{ApiError, ApiErrorKind},
-    server::ServerState,
+    server::{prometheus::PrometheusRegistry, ServerState},
     tokenserver,
     web::extractors::{
-        BsoPutRequest, BsoRequest, CollectionPostRequest, CollectionRequest, EmitApiMetric,
-        HeartbeatRequest, MetaRequest, ReplyFormat, TestErrorRequest,
+        BatchRequest, BsoPutRequest, BsoRequest, ChangesRequest, CollectionPostRequest,
+        CollectionRequest, EmitApiMetric, HeartbeatRequest, MetaRequest, ReplyFormat,
+        TestErrorRequest,
     },
 };
 
@@ -44,6 +49,66 @@ pub async fn get_collections(
         .await
 }
 
+/// Long-polls for changes to the user's collections.
+///
+/// Responds with the timestamps of any collections modified after `since` as soon as there are
+/// some, or with a 304 if nothing changes before the request's timeout. Changes that don't leave
+/// a newer collection behind (such as a deleted collection) are waited out.
+pub async fn get_changes(
+    changes: ChangesRequest,
+    db_pool: DbTransactionPool,
+    state: Data<ServerState>,
+    request: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    changes.emit_api_metric("request.get_changes");
+    let deadline = Instant::now() + changes.timeout;
+
+    // Subscribe before reading the collection timestamps, so that no change committed after the
+    // read is missed
+    let mut subscription = state.changes.subscribe(changes.user_id.legacy_id);
+    let timestamps = get_collection_timestamps(&db_pool, &request, &changes.user_id).await?;
+    let since = changes.since.unwrap_or_else(|| {
+        let latest = timestamps.values().map(|modified| modified.as_i64()).max();
+        SyncTimestamp::from_milliseconds(latest.unwrap_or_default() as u64)
+    });
+
+    let mut changed: HashMap<_, _> = timestamps
+        .into_iter()
+        .filter(|(_, modified)| *modified > since)
+        .collect();
+    while changed.is_empty() {
+        match timeout_at(deadline, subscription.next()).await {
+            Ok(Some(_)) => {
+                changed = get_collection_timestamps(&db_pool, &request, &changes.user_id)
+                    .await?
+                    .into_iter()
+                    .filter(|(_, modified)| *modified > since)
+                    .collect();
+            }
+            // Nothing changed before the deadline
+            _ => return Ok(HttpResponse::NotModified().finish()),
+        }
+    }
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .header(X_WEAVE_RECORDS, changed.len().to_string())
+        .json(changed))
+}
+
+/// Reads the user's collection timestamps in a short transaction of its own, so that no
+/// transaction is held open while a long-poll request waits.
+async fn get_collection_timestamps(
+    db_pool: &DbTransactionPool,
+    request: &HttpRequest,
+    user_id: &UserIdentifier,
+) -> Result<HashMap<String, SyncTimestamp>, ApiError> {
+    db_pool
+        .transaction(request.clone(), |db| async move {
+            Ok(db.get_collection_timestamps(user_id.clone()).await?)
+        })
+        .await
+}
+
 pub async fn get_collection_counts(
     meta: MetaRequest,
     db_pool: DbTransactionPool,
@@ -105,7 +170,9 @@ pub async fn delete_all(
     db_pool
         .transaction_http(request, |db| async move {
             meta.emit_api_metric("request.delete_all");
-            Ok(HttpResponse::Ok().json(db.delete_storage(meta.user_id).await?))
+            Ok(storage_changed(
+                HttpResponse::Ok().json(db.delete_storage(meta.user_id).await?),
+            ))
         })
         .await
 }
@@ -117,6 +184,10 @@ pub async fn delete_collection(
 ) -> Result<HttpResponse, ApiError> {
     db_pool
         .transaction_http(request, |db| async move {
+            if let Some(ref batch) = coll.batch {
+                return delete_collection_batch(&coll, batch, db).await;
+            }
+
             let delete_bsos = !coll.query.ids.is_empty();
             let timestamp = if delete_bsos {
                 coll.emit_api_metric("request.delete_bsos");
@@ -135,11 +206,11 @@ pub async fn delete_collection(
                 .await
             };
 
-            let timestamp = match timestamp {
-                Ok(timestamp) => timestamp,
+            let (timestamp, changed) = match timestamp {
+                Ok(timestamp) => (timestamp, true),
                 Err(e) => {
                     if e.is_collection_not_found() || e.is_bso_not_found() {
-                        db.get_storage_timestamp(coll.user_id).await?
+                        (db.get_storage_timestamp(coll.user_id).await?, false)
                     } else {
                         return Err(e.into());
                     }
@@ -150,12 +221,42 @@ pub async fn delete_collection(
             if delete_bsos {
                 resp.header(X_LAST_MODIFIED, timestamp.as_header());
             }
-            Ok(resp.json(timestamp))
+            let resp = resp.json(timestamp);
+            Ok(if changed { storage_changed(resp) } else { resp })
         })
         .await
         .map_err(Into::into)
 }
 
+// Abort a pending batch, discarding everything appended to it.
+async fn delete_collection_batch(
+    coll: &CollectionRequest,
+    batch: &BatchRequest,
+    db: Box<dyn Db<'_> + '_>,
+) -> Result<HttpResponse, ApiError> {
+    coll.emit_api_metric("request.delete_collection_batch");
+    let id = batch_id(batch)?;
+    let is_valid = db
+        .validate_batch(params::ValidateBatch {
+            user_id: coll.user_id.clone(),
+            collection: coll.collection.clone(),
+            id: id.clone(),
+        })
+        .await?;
+    if !is_valid {
+        let err: DbError = DbErrorKind::BatchNotFound.into();
+        return Err(err.into());
+    }
+
+    db.delete_batch(params::DeleteBatch {
+        user_id: coll.user_id.clone(),
+        collection: coll.collection.clone(),
+        id,
+    })
+    .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
 pub async fn get_collection(
     coll: CollectionRequest,
     db_pool: DbTransactionPool,
@@ -163,6 +264,10 @@ pub async fn get_collection(
 ) -> Result<HttpResponse, ApiError> {
     db_pool
         .transaction_http(request, |db| async move {
+            if let Some(ref batch) = coll.batch {
+                return get_collection_batch(&coll, batch, db).await;
+            }
+
             coll.emit_api_metric("request.get_collection");
             let params = params::GetBsos {
                 user_id: coll.user_id.clone(),
@@ -188,6 +293,34 @@ pub async fn get_collection(
         .await
 }
 
+// Report the pending record count, pending bytes and expiry of a batch.
+async fn get_collection_batch(
+    coll: &CollectionRequest,
+    batch: &BatchRequest,
+    db: Box<dyn Db<'_> + '_>,
+) -> Result<HttpResponse, ApiError> {
+    coll.emit_api_metric("request.get_collection_batch");
+    let status = db
+        .get_batch_status(params::GetBatchStatus {
+            user_id: coll.user_id.clone(),
+            collection: coll.collection.clone(),
+            id: batch_id(batch)?,
+        })
+        .await?
+        .ok_or_else(|| -> ApiError {
+            ApiErrorKind::Db(DbErrorKind::BatchNotFound.into()).into()
+        })?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
+// Batches can only be aborted or queried by their id (not "?batch=true").
+fn batch_id(batch: &BatchRequest) -> Result<String, ApiError> {
+    batch
+        .id
+        .clone()
+        .ok_or_else(|| -> ApiError { ApiErrorKind::Db(DbErrorKind::BatchNotFound.into()).into() })
+}
+
 async fn finish_get_collection<T>(
     coll: &CollectionRequest,
     db: Box<dyn Db<'_> + '_>,
@@ -220,7 +353,9 @@ where
     }
 
     match coll.reply {
-        ReplyFormat::Json => Ok(resp.json(result.items)),
+        ReplyFormat::Json | ReplyFormat::Cbor | ReplyFormat::Msgpack => {
+            reply(resp, coll.reply, &result.items)
+        }
         ReplyFormat::Newlines => {
             let items: String = result
                 .items
@@ -270,13 +405,52 @@ pub async fn post_collection(
                 })
                 .await?;
 
-            Ok(HttpResponse::build(StatusCode::OK)
-                .header(X_LAST_MODIFIED, result.modified.as_header())
-                .json(result))
+            Ok(storage_changed(reply(
+                HttpResponse::build(StatusCode::OK)
+                    .header(X_LAST_MODIFIED, result.modified.as_header()),
+                coll.reply,
+                &result,
+            )?))
         })
         .await
 }
 
+/// Serialize a Collection Get or Post reply in the requested format
+///
+/// Newline delimited replies only apply to lists of BSOs, anything else is
+/// sent as JSON.
+fn reply<T>(
+    builder: &mut HttpResponseBuilder,
+    format: ReplyFormat,
+    body: &T,
+) -> Result<HttpResponse, DbError>
+where
+    T: Serialize,
+{
+    let body = match format {
+        ReplyFormat::Json | ReplyFormat::Newlines => return Ok(builder.json(body)),
+        ReplyFormat::Cbor => serde_cbor::to_vec(body).map_err(|e| e.to_string()),
+        ReplyFormat::Msgpack => rmp_serde::to_vec_named(body).map_err(|e| e.to_string()),
+    }
+    .map_err(|e| DbError::internal(&format!("Could not serialize reply: {}", e)))?;
+    Ok(builder.content_type(format.content_type()).body(body))
+}
+
+/// The reply to a batch Collection Post
+///
+/// Built as a struct rather than a `serde_json::Value`: with the
+/// `arbitrary_precision` feature, `Value` numbers only serialize correctly to
+/// JSON, not the binary reply formats.
+#[derive(Debug, Serialize)]
+struct PostBatchReply {
+    success: Vec<String>,
+    failed: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    batch: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified: Option<SyncTimestamp>,
+}
+
 // Append additional collection items into the given Batch, optionally commiting
 // the entire, accumulated if the `commit` flag is set.
 pub async fn post_collection_batch(
@@ -340,14 +514,14 @@ pub async fn post_collection_batch(
     let mut failed = coll.bsos.invalid;
     let bso_ids: Vec<_> = coll.bsos.valid.iter().map(|bso| bso.id.clone()).collect();
 
-    let mut resp: Value = json!({});
-
     macro_rules! handle_result {
         // collect up the successful and failed bso_ids into a response.
         ( $r: expr) => {
             match $r {
                 Ok(_) => success.extend(bso_ids.clone()),
-                Err(e) if e.is_conflict() || e.is_quota() => return Err(e.into()),
+                Err(e) if e.is_conflict() || e.is_quota() || e.is_batch_too_large() => {
+                    return Err(e.into())
+                }
                 _ => failed.extend(
                     bso_ids
                         .clone()
@@ -358,31 +532,35 @@ pub async fn post_collection_batch(
         };
     }
 
+    // Append any bsos included in this message to the requested batch. When
+    // committing, this means the bsos in the final "commit" message overwrite
+    // any prior versions of them appended by earlier messages, and count
+    // towards the batch's total record and byte limits like any others.
+    if !coll.bsos.valid.is_empty() {
+        let result = {
+            trace!("Batch: Appending to {}", &new_batch.id);
+            db.append_to_batch(params::AppendToBatch {
+                user_id: coll.user_id.clone(),
+                collection: coll.collection.clone(),
+                batch: new_batch.clone(),
+                bsos: coll.bsos.valid.into_iter().map(From::from).collect(),
+            })
+            .await
+        };
+        handle_result!(result);
+    }
+
     // If we're not committing the current set of records yet.
     if !breq.commit {
-        // and there are bsos included in this message.
-        if !coll.bsos.valid.is_empty() {
-            // Append the data to the requested batch.
-            let result = {
-                trace!("Batch: Appending to {}", &new_batch.id);
-                db.append_to_batch(params::AppendToBatch {
-                    user_id: coll.user_id.clone(),
-                    collection: coll.collection.clone(),
-                    batch: new_batch.clone(),
-                    bsos: coll.bsos.valid.into_iter().map(From::from).collect(),
-                })
-                .await
-            };
-            handle_result!(result);
-        }
-
         // Return the batch append response without committing the current
         // batch to the BSO table.
-        resp["success"] = json!(success);
-        resp["failed"] = json!(failed);
-
-        resp["batch"] = json!(&new_batch.id);
-        return Ok(HttpResponse::Accepted().json(resp));
+        let resp = PostBatchReply {
+            success,
+            failed,
+            batch: Some(new_batch.id),
+            modified: None,
+        };
+        return Ok(reply(&mut HttpResponse::Accepted(), coll.reply, &resp)?);
     }
 
     // We've been asked to commit the accumulated data, so get to it!
@@ -394,10 +572,7 @@ pub async fn post_collection_batch(
         })
         .await?;
 
-    // TODO: validate *actual* sizes of the batch items
-    // (max_total_records, max_total_bytes)
-    //
-    // First, write the pending batch BSO data into the BSO table.
+    // Write the pending batch BSO data into the BSO table.
     let modified = if let Some(batch) = batch {
         db.commit_batch(params::CommitBatch {
             user_id: user_id.clone(),
@@ -410,46 +585,19 @@ pub async fn post_collection_batch(
         return Err(ApiError::from(err));
     };
 
-    // Then, write the BSOs contained in the commit request into the BSO table.
-    // (This presumes that the BSOs contained in the final "commit" message are
-    // newer, and thus more "correct", than any prior BSO info that may have been
-    // included in the prior batch creation messages. The client shouldn't really
-    // be including BSOs with the commit message, however it does and we should
-    // handle that case.)
-    if !coll.bsos.valid.is_empty() {
-        trace!("Batch: writing commit message bsos");
-        let result = db
-            .post_bsos(params::PostBsos {
-                user_id: coll.user_id.clone(),
-                collection: coll.collection.clone(),
-                bsos: coll
-                    .bsos
-                    .valid
-                    .into_iter()
-                    .map(|batch_bso| params::PostCollectionBso {
-                        id: batch_bso.id,
-                        sortindex: batch_bso.sortindex,
-                        payload: batch_bso.payload,
-                        ttl: batch_bso.ttl,
-                    })
-                    .collect(),
-                for_batch: false,
-                failed: Default::default(),
-            })
-            .await
-            .map(|_| ());
-
-        handle_result!(result);
-    }
-
     // Always return success, failed, & modified
-    resp["success"] = json!(success);
-    resp["failed"] = json!(failed);
-    resp["modified"] = json!(modified);
-    trace!("Batch: Returning result: {}", &resp);
-    Ok(HttpResponse::build(StatusCode::OK)
-        .header(X_LAST_MODIFIED, modified.as_header())
-        .json(resp))
+    let resp = PostBatchReply {
+        success,
+        failed,
+        batch: None,
+        modified: Some(modified),
+    };
+    trace!("Batch: Returning result: {:?}", &resp);
+    Ok(storage_changed(reply(
+        HttpResponse::build(StatusCode::OK).header(X_LAST_MODIFIED, modified.as_header()),
+        coll.reply,
+        &resp,
+    )?))
 }
 
 pub async fn delete_bso(
@@ -467,7 +615,9 @@ pub async fn delete_bso(
                     id: bso_req.bso,
                 })
                 .await?;
-            Ok(HttpResponse::Ok().json(json!({ "modified": result })))
+            Ok(storage_changed(
+                HttpResponse::Ok().json(json!({ "modified": result })),
+            ))
         })
         .await
 }
@@ -515,9 +665,11 @@ pub async fn put_bso(
                 })
                 .await?;
 
-            Ok(HttpResponse::build(StatusCode::OK)
-                .header(X_LAST_MODIFIED, result.as_header())
-                .json(result))
+            Ok(storage_changed(
+                HttpResponse::build(StatusCode::OK)
+                    .header(X_LAST_MODIFIED, result.as_header())
+                    .json(result),
+            ))
         })
         .await
 }
@@ -632,6 +784,10 @@ pub async fn lbheartbeat(req: HttpRequest) -> Result<HttpResponse, ApiError> {
 
     let deadarc = state.deadman.clone();
     let mut deadman = *deadarc.read().await;
+    if deadman.shutting_down {
+        // We're shutting down: drain traffic from this instance
+        return Ok(HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).json(resp));
+    }
     if matches!(deadman.expiry, Some(expiry) if expiry <= time::Instant::now()) {
         // We're set to report a failed health check after a certain time (to
         // evict this instance and start a fresh one)
@@ -698,6 +854,16 @@ pub async fn lbheartbeat(req: HttpRequest) -> Result<HttpResponse, ApiError> {
     Ok(HttpResponseBuilder::new(status_code).json(json!(resp)))
 }
 
+/// The server's metrics in the Prometheus text format, when enabled
+pub async fn metrics(prometheus: Data<Option<Arc<PrometheusRegistry>>>) -> HttpResponse {
+    match prometheus.get_ref() {
+        Some(registry) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(registry.render()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
 // try returning an API error
 pub async fn test_error(
     _req: HttpRequest,
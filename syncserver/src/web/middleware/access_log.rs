@@ -0,0 +1,168 @@
+//! Access log for syncstorage requests
+//!
+//! A `request.summary` line is logged for a sample of the storage API's
+//! requests (`access_log_sample_rate` of them), with its fields in the mozlog
+//! `Fields`. Requests are identified by their route's path template rather
+//! than their raw path, so user ids aren't logged. Tokenserver requests are
+//! logged separately via its `LogItems`.
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use actix_web::{
+    dev::{BodySize, MessageBody, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::USER_AGENT,
+    web::Data,
+    Error,
+};
+use futures::future::{self, LocalBoxFuture};
+use rand::{thread_rng, Rng};
+use syncserver_common::X_WEAVE_RECORDS;
+
+use crate::server::{user_agent, ServerState, SYNC_VERSION_PATH};
+
+/// Strip the regexes from a route's pattern, e.g. `/1.5/{uid:[0-9]{1,10}}`
+/// becomes `/1.5/{uid}`.
+fn path_template(pattern: &str) -> String {
+    let mut template = String::with_capacity(pattern.len());
+    let mut depth = 0;
+    let mut in_name = false;
+    for c in pattern.chars() {
+        match c {
+            '{' => {
+                depth += 1;
+                if depth == 1 {
+                    in_name = true;
+                    template.push(c);
+                }
+            }
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    in_name = false;
+                    template.push(c);
+                }
+            }
+            ':' if depth == 1 => in_name = false,
+            _ if depth == 0 || in_name => template.push(c),
+            _ => (),
+        }
+    }
+    template
+}
+
+pub struct AccessLogMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for AccessLogMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        let sample_rate = sreq
+            .app_data::<Data<ServerState>>()
+            .map(|state| state.access_log_sample_rate)
+            .unwrap_or_default();
+        if sample_rate <= 0.0 || thread_rng().gen::<f64>() >= sample_rate {
+            return Box::pin(self.service.call(sreq));
+        }
+
+        let start = Instant::now();
+        let fut = self.service.call(sreq);
+        Box::pin(async move {
+            let sresp = fut.await?;
+            let path = match sresp.request().match_pattern() {
+                Some(pattern) if pattern.starts_with(&format!("/{}/", SYNC_VERSION_PATH)) => {
+                    path_template(&pattern)
+                }
+                _ => return Ok(sresp),
+            };
+
+            let req = sresp.request();
+            let (ua_browser, ua_os) = req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|ua| ua.to_str().ok())
+                .map(|ua| {
+                    let (_, metrics_os, metrics_browser) = user_agent::parse_user_agent(ua);
+                    (metrics_browser.to_owned(), metrics_os.to_owned())
+                })
+                .unwrap_or_default();
+            let bytes = match sresp.response().body().size() {
+                BodySize::Sized(size) => size as u64,
+                BodySize::Sized64(size) => size,
+                _ => 0,
+            };
+            let records = sresp
+                .headers()
+                .get(X_WEAVE_RECORDS)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_owned();
+
+            info!(
+                "request.summary";
+                "method" => req.method().as_str(),
+                "path" => path,
+                "code" => sresp.status().as_u16(),
+                "t" => start.elapsed().as_millis() as u64,
+                "bytes" => bytes,
+                "weave_records" => records,
+                "ua.browser.family" => ua_browser,
+                "ua.os.family" => ua_os
+            );
+            Ok(sresp)
+        })
+    }
+}
+
+/// Middleware logging a sample of syncstorage requests.
+#[derive(Debug, Default)]
+pub struct AccessLog;
+
+impl<S: 'static, B> Transform<S> for AccessLog
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AccessLogMiddleware<S>;
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        Box::pin(future::ok(AccessLogMiddleware { service }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_template() {
+        assert_eq!(
+            path_template("/1.5/{uid:[0-9]{1,10}}/storage/{collection:[a-zA-Z0-9._-]{1,32}}"),
+            "/1.5/{uid}/storage/{collection}"
+        );
+        assert_eq!(
+            path_template("/1.5/{uid}/info/quota"),
+            "/1.5/{uid}/info/quota"
+        );
+        assert_eq!(path_template("/__heartbeat__"), "/__heartbeat__");
+    }
+}
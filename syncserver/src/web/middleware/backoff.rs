@@ -0,0 +1,196 @@
+//! Load-aware `X-Weave-Backoff` headers
+//!
+//! Sync clients pause syncing for the number of seconds given in an
+//! `X-Weave-Backoff` response header. It's added to storage responses while
+//! the operator's maintenance flag is set, and, when
+//! `backoff_on_pool_saturation` is enabled, while the database pool is
+//! saturated.
+use std::path::Path;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    web::{self, Data},
+    Error,
+};
+use futures::future::{self, LocalBoxFuture};
+use rand::{thread_rng, Rng};
+use syncserver_common::X_WEAVE_BACKOFF;
+use syncserver_db_common::PoolState;
+use syncstorage_settings::Deadman;
+use tokio::{sync::RwLock, time::delay_for};
+
+use crate::server::{metrics::Metrics, ServerState};
+use crate::web::tags::Tags;
+
+/// Periodically check for the operator's maintenance flag file, recording
+/// its presence in the `Deadman` state.
+pub fn spawn_maintenance_watcher(interval: Duration, path: String, deadman: Arc<RwLock<Deadman>>) {
+    tokio::spawn(async move {
+        loop {
+            // Checking the file blocks, so keep it off the async threads
+            let flag = path.clone();
+            let maintenance =
+                match web::block(move || -> Result<bool, ()> { Ok(Path::new(&flag).exists()) })
+                    .await
+                {
+                    Ok(maintenance) => maintenance,
+                    Err(e) => {
+                        warn!("⚠️ Couldn't check the maintenance flag: {:?}", e);
+                        delay_for(interval).await;
+                        continue;
+                    }
+                };
+            {
+                let mut deadman = deadman.write().await;
+                if deadman.maintenance != maintenance {
+                    info!(
+                        "Maintenance flag {}",
+                        if maintenance { "set" } else { "cleared" }
+                    );
+                    deadman.maintenance = maintenance;
+                }
+            }
+            delay_for(interval).await;
+        }
+    });
+}
+
+/// Whether the pool's connections are all in use right now
+fn is_saturated(pool: &PoolState, deadman: &Deadman) -> bool {
+    let active = pool.connections.saturating_sub(pool.idle_connections);
+    active >= deadman.max_size && pool.idle_connections == 0
+}
+
+/// Why clients should currently back off, if they should
+async fn backoff_reason(state: &ServerState) -> Option<&'static str> {
+    let deadman = *state.deadman.read().await;
+    if deadman.maintenance {
+        Some("maintenance")
+    } else if state.backoff_on_pool_saturation && is_saturated(&state.db_pool.state(), &deadman) {
+        Some("pool_saturated")
+    } else {
+        None
+    }
+}
+
+/// The backoff interval plus a random jitter of up to `jitter` percent of it
+fn jittered(interval: u32, jitter: u32) -> u32 {
+    let max_jitter = (u64::from(interval) * u64::from(jitter) / 100) as u32;
+    interval.saturating_add(thread_rng().gen_range(0..=max_jitter))
+}
+
+pub struct WeaveBackoffMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for WeaveBackoffMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        // Only storage requests come from sync clients
+        if !sreq.path().starts_with("/1.5/") {
+            return Box::pin(self.service.call(sreq));
+        }
+
+        let state = sreq.app_data::<Data<ServerState>>().cloned();
+        let fut = self.service.call(sreq);
+        Box::pin(async move {
+            let mut resp = fut.await?;
+            let state = match state {
+                Some(state) => state,
+                None => return Ok(resp),
+            };
+            // Responses may already carry a (more specific) backoff, e.g. from
+            // rate limiting
+            if resp.headers().contains_key(X_WEAVE_BACKOFF) {
+                return Ok(resp);
+            }
+            if let Some(reason) = backoff_reason(&state).await {
+                let interval = jittered(state.backoff_interval, state.backoff_interval_jitter);
+                resp.headers_mut().insert(
+                    HeaderName::from_static(X_WEAVE_BACKOFF),
+                    HeaderValue::from(interval),
+                );
+                let mut tags = Tags::default();
+                tags.add_tag("reason", reason);
+                Metrics::from(state.get_ref()).incr_with_tags("storage.backoff", Some(tags));
+            }
+            Ok(resp)
+        })
+    }
+}
+
+/// Middleware to set the X-Weave-Backoff header while the server is under
+/// load or maintenance.
+#[derive(Debug, Default)]
+pub struct WeaveBackoff;
+
+impl<S: 'static, B> Transform<S> for WeaveBackoff
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = WeaveBackoffMiddleware<S>;
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        Box::pin(future::ok(WeaveBackoffMiddleware { service }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_saturated() {
+        let deadman = Deadman {
+            max_size: 10,
+            ..Default::default()
+        };
+        let pool = |connections, idle_connections| PoolState {
+            connections,
+            idle_connections,
+        };
+        assert!(!is_saturated(&pool(0, 0), &deadman));
+        assert!(!is_saturated(&pool(10, 1), &deadman));
+        assert!(is_saturated(&pool(10, 0), &deadman));
+
+        // A past saturation the health check hasn't cleared yet doesn't count
+        let deadman = Deadman {
+            clock_start: Some(time::Instant::now()),
+            ..deadman
+        };
+        assert!(!is_saturated(&pool(10, 1), &deadman));
+    }
+
+    #[test]
+    fn test_jittered() {
+        for _ in 0..100 {
+            let interval = jittered(1000, 10);
+            assert!((1000..=1100).contains(&interval));
+        }
+        assert_eq!(jittered(1000, 0), 1000);
+    }
+}
@@ -0,0 +1,306 @@
+//! Negotiated compression of storage and info responses
+//!
+//! Responses are compressed with brotli or gzip, whichever the client prefers
+//! in its `Accept-Encoding` header, once they reach the server's
+//! `response_compression_min_bytes` threshold. Smaller bodies aren't worth the
+//! CPU and are sent as is. Either way, every response from these routes
+//! carries `Vary: Accept-Encoding`.
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use actix_web::{
+    dev::{
+        Body, BodySize, MessageBody, ResponseBody, Service, ServiceRequest, ServiceResponse,
+        Transform,
+    },
+    http::header::{self, HeaderValue},
+    web::Data,
+    Error,
+};
+use brotli::CompressorWriter;
+use flate2::{write::GzEncoder, Compression};
+use futures::future::{self, poll_fn, LocalBoxFuture};
+
+use crate::error::{ApiError, ApiErrorKind};
+use crate::server::ServerState;
+
+/// Buffer size for the brotli encoder's internal buffer
+const BROTLI_BUFFER_SIZE: usize = 4096;
+/// Brotli quality level (0-11): favors speed, as responses are compressed
+/// on every request
+const BROTLI_QUALITY: u32 = 5;
+/// Brotli window size, as a power of two
+const BROTLI_LG_WINDOW_SIZE: u32 = 22;
+
+/// The content codings we're able to produce.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ContentCoding {
+    Brotli,
+    Gzip,
+}
+
+impl ContentCoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            ContentCoding::Brotli => "br",
+            ContentCoding::Gzip => "gzip",
+        }
+    }
+
+    fn encode(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            ContentCoding::Brotli => {
+                let mut encoder = CompressorWriter::new(
+                    Vec::new(),
+                    BROTLI_BUFFER_SIZE,
+                    BROTLI_QUALITY,
+                    BROTLI_LG_WINDOW_SIZE,
+                );
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(encoder.into_inner())
+            }
+            ContentCoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Picks the coding to respond with from an `Accept-Encoding` header value.
+///
+/// The coding with the highest quality value wins, with brotli preferred over
+/// gzip on a tie. Codings with a quality of 0 are never chosen.
+fn negotiate(accept_encoding: &str) -> Option<ContentCoding> {
+    let mut best: Option<(ContentCoding, f32)> = None;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let quality = parts
+            .filter_map(|param| {
+                let (name, value) = param.split_at(param.find('=')?);
+                if name.trim().eq_ignore_ascii_case("q") {
+                    value[1..].trim().parse::<f32>().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+            .unwrap_or(1.0);
+
+        let candidates: &[ContentCoding] = match coding.as_str() {
+            "br" => &[ContentCoding::Brotli],
+            "gzip" | "x-gzip" => &[ContentCoding::Gzip],
+            "*" => &[ContentCoding::Brotli, ContentCoding::Gzip],
+            _ => &[],
+        };
+        for &candidate in candidates {
+            if quality <= 0.0 {
+                continue;
+            }
+            best = match best {
+                Some((current, q))
+                    if q > quality || (q == quality && current == ContentCoding::Brotli) =>
+                {
+                    Some((current, q))
+                }
+                _ => Some((candidate, quality)),
+            };
+        }
+    }
+    best.map(|(coding, _)| coding)
+}
+
+/// Whether the path is a storage or info route (`/1.5/{uid}/storage...` or
+/// `/1.5/{uid}/info/...`).
+fn is_compressible_path(path: &str) -> bool {
+    let mut segments = path.trim_start_matches('/').split('/');
+    segments.next() == Some("1.5") && matches!(segments.nth(1), Some("storage") | Some("info"))
+}
+
+pub struct ResponseCompressionMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for ResponseCompressionMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + Unpin + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        let compressible = is_compressible_path(sreq.path());
+        let coding = if compressible {
+            sreq.headers()
+                .get(header::ACCEPT_ENCODING)
+                .and_then(|value| value.to_str().ok())
+                .and_then(negotiate)
+        } else {
+            None
+        };
+        let min_bytes = sreq
+            .app_data::<Data<ServerState>>()
+            .map(|state| state.response_compression_min_bytes);
+
+        let fut = self.service.call(sreq);
+        Box::pin(async move {
+            let mut resp = fut.await?;
+            if compressible {
+                // Caches must key every response from these paths on the
+                // request's encodings, compressed or not
+                resp.headers_mut()
+                    .append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+            }
+            let (coding, min_bytes) = match (coding, min_bytes) {
+                (Some(coding), Some(min_bytes)) => (coding, min_bytes),
+                _ => return Ok(passthrough(resp)),
+            };
+            if !resp.status().is_success() || resp.headers().contains_key(header::CONTENT_ENCODING)
+            {
+                return Ok(passthrough(resp));
+            }
+            match resp.response().body().size() {
+                BodySize::Sized(size) if size as usize >= min_bytes => (),
+                _ => return Ok(passthrough(resp)),
+            }
+            compress(resp, coding).await
+        })
+    }
+}
+
+/// Passes a response through unmodified.
+fn passthrough<B>(resp: ServiceResponse<B>) -> ServiceResponse<Body>
+where
+    B: MessageBody + Unpin + 'static,
+{
+    resp.map_body(|_, body| ResponseBody::Body(Body::Message(Box::new(body))))
+}
+
+async fn compress<B>(
+    mut resp: ServiceResponse<B>,
+    coding: ContentCoding,
+) -> Result<ServiceResponse<Body>, Error>
+where
+    B: MessageBody + Unpin + 'static,
+{
+    let mut body = resp.take_body();
+    let mut data = Vec::new();
+    while let Some(chunk) = poll_fn(|cx| Pin::new(&mut body).poll_next(cx)).await {
+        data.extend_from_slice(&chunk?);
+    }
+
+    let encoded = coding.encode(&data).map_err(|e| {
+        ApiError::from(ApiErrorKind::Internal(format!(
+            "Could not {} encode response: {}",
+            coding.as_str(),
+            e
+        )))
+    })?;
+
+    let headers = resp.headers_mut();
+    headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(coding.as_str()),
+    );
+    headers.remove(header::CONTENT_LENGTH);
+    Ok(resp.map_body(|_, _| ResponseBody::Body(Body::from(encoded))))
+}
+
+/// Middleware to compress storage and info responses according to the
+/// request's `Accept-Encoding` header.
+///
+/// The threshold is read from the `ServerState`, so responses from apps
+/// without syncstorage are never compressed.
+pub struct ResponseCompression;
+
+impl ResponseCompression {
+    pub fn new() -> Self {
+        ResponseCompression::default()
+    }
+}
+
+impl Default for ResponseCompression {
+    fn default() -> Self {
+        Self
+    }
+}
+
+impl<S: 'static, B> Transform<S> for ResponseCompression
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + Unpin + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ResponseCompressionMiddleware<S>;
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        Box::pin(future::ok(ResponseCompressionMiddleware { service }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate("gzip"), Some(ContentCoding::Gzip));
+        assert_eq!(negotiate("gzip, deflate, br"), Some(ContentCoding::Brotli));
+        assert_eq!(negotiate("br;q=0.5, gzip"), Some(ContentCoding::Gzip));
+        assert_eq!(negotiate("br;q=0, *"), Some(ContentCoding::Gzip));
+        assert_eq!(negotiate("*"), Some(ContentCoding::Brotli));
+        assert_eq!(negotiate("identity, deflate"), None);
+        assert_eq!(negotiate("gzip;q=0"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn test_compressible_path() {
+        assert!(is_compressible_path("/1.5/1/storage/bookmarks"));
+        assert!(is_compressible_path("/1.5/1/info/collections"));
+        assert!(!is_compressible_path("/1.5/1/changes"));
+        assert!(!is_compressible_path("/1.0/sync/1.5"));
+        assert!(!is_compressible_path("/__heartbeat__"));
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let data = br#"[{"id": "1", "payload": "abc"}]"#.repeat(100);
+
+        let mut decoded = Vec::new();
+        GzDecoder::new(&ContentCoding::Gzip.encode(&data).unwrap()[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+
+        let mut decoded = Vec::new();
+        brotli::Decompressor::new(
+            &ContentCoding::Brotli.encode(&data).unwrap()[..],
+            BROTLI_BUFFER_SIZE,
+        )
+        .read_to_end(&mut decoded)
+        .unwrap();
+        assert_eq!(decoded, data);
+    }
+}
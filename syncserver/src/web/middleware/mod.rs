@@ -1,5 +1,11 @@
+pub mod access_log;
+pub mod backoff;
+pub mod compress;
+pub mod ratelimit;
 pub mod rejectua;
+pub mod request_id;
 pub mod sentry;
+pub mod trace;
 pub mod weave;
 
 // # Web Middleware
@@ -0,0 +1,320 @@
+//! Per-user rate limiting of storage requests
+//!
+//! Each user gets a token bucket per class of route (info, read and write),
+//! keyed by the uid of their (Hawk authenticated) `HawkIdentifier`. Requests
+//! that find their bucket empty are answered with a 503, telling the client
+//! how long to back off.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header::RETRY_AFTER, Method},
+    web::Data,
+    Error, FromRequest, HttpResponse,
+};
+use futures::future::{self, Either, Ready};
+use syncserver_common::X_WEAVE_BACKOFF;
+use syncstorage_settings::{RateLimit, RateLimits};
+
+use crate::server::{metrics::Metrics, ServerState};
+use crate::web::{extractors::HawkIdentifier, tags::Tags};
+
+/// How often idle buckets are dropped from the limiter
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// The longest `Retry-After` sent, for limits that never refill
+const MAX_RETRY_AFTER: u64 = 3600;
+
+/// The classes of storage routes, each limited separately.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum RouteClass {
+    Info,
+    Read,
+    Write,
+}
+
+impl RouteClass {
+    /// Classify a request, returning `None` for anything outside of the
+    /// storage API (`/1.5/{uid}/...`)
+    fn from_request(method: &Method, path: &str) -> Option<Self> {
+        let mut segments = path.trim_start_matches('/').split('/');
+        if segments.next() != Some("1.5") {
+            return None;
+        }
+        if segments.nth(1) == Some("info") {
+            return Some(RouteClass::Info);
+        }
+        match *method {
+            Method::GET | Method::HEAD => Some(RouteClass::Read),
+            _ => Some(RouteClass::Write),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            RouteClass::Info => "info",
+            RouteClass::Read => "read",
+            RouteClass::Write => "write",
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// The bucket's tokens as of `now`, including those refilled since it was
+    /// last updated
+    fn tokens_at(&self, limit: &RateLimit, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * limit.per_second).min(f64::from(limit.burst))
+    }
+}
+
+struct Buckets {
+    buckets: HashMap<(u64, RouteClass), Bucket>,
+    last_sweep: Instant,
+}
+
+/// Token buckets for every user recently seen, shared between workers.
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    fn limit(&self, class: RouteClass) -> &RateLimit {
+        match class {
+            RouteClass::Info => &self.limits.info,
+            RouteClass::Read => &self.limits.read,
+            RouteClass::Write => &self.limits.write,
+        }
+    }
+
+    /// Take a token from the user's bucket for the route class.
+    ///
+    /// Returns how long until a token is available when the bucket is empty.
+    fn check(&self, uid: u64, class: RouteClass, now: Instant) -> Result<(), Duration> {
+        let limit = self.limit(class);
+        let mut buckets = self.buckets.lock().expect("RateLimiter lock poisoned");
+
+        if now.saturating_duration_since(buckets.last_sweep) >= SWEEP_INTERVAL {
+            // Full buckets are the same as new ones, so there's no need to
+            // keep them around
+            let limiter = self;
+            buckets.buckets.retain(|(_, class), bucket| {
+                let limit = limiter.limit(*class);
+                bucket.tokens_at(limit, now) < f64::from(limit.burst)
+            });
+            buckets.last_sweep = now;
+        }
+
+        let bucket = buckets
+            .buckets
+            .entry((uid, class))
+            .or_insert_with(|| Bucket {
+                tokens: f64::from(limit.burst),
+                updated: now,
+            });
+        let tokens = bucket.tokens_at(limit, now);
+        bucket.updated = now;
+        if tokens >= 1.0 {
+            bucket.tokens = tokens - 1.0;
+            return Ok(());
+        }
+        bucket.tokens = tokens;
+        if limit.per_second > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - tokens) / limit.per_second))
+        } else {
+            Err(Duration::from_secs(MAX_RETRY_AFTER))
+        }
+    }
+}
+
+/// Middleware to rate limit storage requests per user.
+///
+/// Requests that fail authentication are passed through, to be rejected by
+/// the handler's `HawkIdentifier` extraction as usual.
+#[derive(Debug, Default)]
+pub struct UserRateLimit;
+
+impl<S, B> Transform<S> for UserRateLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = UserRateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(UserRateLimitMiddleware { service })
+    }
+}
+
+pub struct UserRateLimitMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for UserRateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<Ready<Result<Self::Response, Self::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        let limited = (|| {
+            let state = sreq.app_data::<Data<ServerState>>()?;
+            let limiter = state.rate_limiter.as_ref()?;
+            let class = RouteClass::from_request(sreq.method(), sreq.path())?;
+            // The identifier's cached in the request's extensions, so the
+            // handler doesn't authenticate the request a second time
+            let user_id = HawkIdentifier::from_request(sreq.request(), &mut Payload::None)
+                .into_inner()
+                .ok()?;
+            let wait = limiter
+                .check(user_id.legacy_id, class, Instant::now())
+                .err()?;
+            Some((state.clone(), class, wait))
+        })();
+
+        match limited {
+            Some((state, class, wait)) => {
+                trace!("Rate limiting {} request: {:?}", class.as_str(), wait);
+                let mut tags = Tags::default();
+                tags.add_tag("route_class", class.as_str());
+                Metrics::from(state.get_ref()).incr_with_tags("error.ratelimited", Some(tags));
+
+                let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+                Either::Left(future::ok(
+                    sreq.into_response(
+                        HttpResponse::ServiceUnavailable()
+                            .header(X_WEAVE_BACKOFF, retry_after.to_string())
+                            .header(RETRY_AFTER, retry_after.to_string())
+                            .body("0".to_owned())
+                            .into_body(),
+                    ),
+                ))
+            }
+            None => Either::Right(self.service.call(sreq)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimits {
+            enabled: true,
+            info: RateLimit {
+                burst: 1,
+                per_second: 0.0,
+            },
+            read: RateLimit {
+                burst: 2,
+                per_second: 0.5,
+            },
+            write: RateLimit {
+                burst: 1,
+                per_second: 1.0,
+            },
+        })
+    }
+
+    #[test]
+    fn test_route_class() {
+        let class = RouteClass::from_request;
+        assert_eq!(
+            class(&Method::GET, "/1.5/1/info/collections"),
+            Some(RouteClass::Info)
+        );
+        assert_eq!(
+            class(&Method::GET, "/1.5/1/storage/bookmarks"),
+            Some(RouteClass::Read)
+        );
+        assert_eq!(
+            class(&Method::POST, "/1.5/1/storage/bookmarks"),
+            Some(RouteClass::Write)
+        );
+        assert_eq!(class(&Method::DELETE, "/1.5/1"), Some(RouteClass::Write));
+        assert_eq!(class(&Method::GET, "/__heartbeat__"), None);
+        assert_eq!(class(&Method::GET, "/1.0/sync/1.5"), None);
+    }
+
+    #[test]
+    fn test_bucket_refills() {
+        let limiter = limiter();
+        let now = Instant::now();
+        assert!(limiter.check(1, RouteClass::Read, now).is_ok());
+        assert!(limiter.check(1, RouteClass::Read, now).is_ok());
+        assert_eq!(
+            limiter.check(1, RouteClass::Read, now),
+            Err(Duration::from_secs(2))
+        );
+
+        // Other users and route classes have their own buckets
+        assert!(limiter.check(2, RouteClass::Read, now).is_ok());
+        assert!(limiter.check(1, RouteClass::Write, now).is_ok());
+
+        let later = now + Duration::from_secs(2);
+        assert!(limiter.check(1, RouteClass::Read, later).is_ok());
+        assert!(limiter.check(1, RouteClass::Read, later).is_err());
+    }
+
+    #[test]
+    fn test_no_refill() {
+        let limiter = limiter();
+        let now = Instant::now();
+        assert!(limiter.check(1, RouteClass::Info, now).is_ok());
+        assert_eq!(
+            limiter.check(1, RouteClass::Info, now + Duration::from_secs(60)),
+            Err(Duration::from_secs(MAX_RETRY_AFTER))
+        );
+    }
+
+    #[test]
+    fn test_sweep() {
+        let limiter = limiter();
+        let now = Instant::now();
+        limiter.check(1, RouteClass::Read, now).unwrap();
+        limiter.check(2, RouteClass::Info, now).unwrap();
+
+        // user 1's read bucket has refilled, user 2's info bucket never will
+        limiter
+            .check(3, RouteClass::Write, now + SWEEP_INTERVAL)
+            .unwrap();
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(!buckets.buckets.contains_key(&(1, RouteClass::Read)));
+        assert!(buckets.buckets.contains_key(&(2, RouteClass::Info)));
+        assert!(buckets.buckets.contains_key(&(3, RouteClass::Write)));
+    }
+}
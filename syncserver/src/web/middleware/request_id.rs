@@ -0,0 +1,151 @@
+//! `X-Request-Id` assignment
+//!
+//! Each request is identified by the `X-Request-Id` header it was sent with
+//! (e.g. by a load balancer or client), or by a newly generated id. The id is
+//! recorded in the request's `Tags` and tokenserver `LogItems`, added to the
+//! slog scope its handling is logged within, and echoed back in the response.
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use actix_web::{
+    dev::{RequestHead, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    Error, HttpMessage,
+};
+use futures::future::{self, LocalBoxFuture};
+use slog::slog_o;
+use syncserver_common::X_REQUEST_ID;
+use uuid::Uuid;
+
+/// The longest `X-Request-Id` accepted from a request
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// A request's id, stored in its extensions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// The request's id, if one's been assigned
+    pub fn get(req_head: &RequestHead) -> Option<String> {
+        req_head
+            .extensions()
+            .get::<RequestId>()
+            .map(|request_id| request_id.0.clone())
+    }
+
+    /// Accept the id a request was sent with, as long as it's of a reasonable
+    /// length and printable, otherwise generate one.
+    fn from_header(header: Option<&HeaderValue>) -> Self {
+        header
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| {
+                (1..=MAX_REQUEST_ID_LENGTH).contains(&id.len())
+                    && id.chars().all(|c| c.is_ascii_graphic())
+            })
+            .map(|id| RequestId(id.to_owned()))
+            .unwrap_or_else(|| RequestId(Uuid::new_v4().to_string()))
+    }
+}
+
+/// Polls a future within a slog scope, so its logging includes the scope's
+/// logger's values.
+struct LogScoped<F> {
+    logger: slog::Logger,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for LogScoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let future = &mut this.future;
+        slog_scope::scope(&this.logger, || future.as_mut().poll(cx))
+    }
+}
+
+pub struct RequestIdHeaderMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestIdHeaderMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        let request_id = RequestId::from_header(sreq.headers().get(X_REQUEST_ID));
+        sreq.extensions_mut().insert(request_id.clone());
+
+        let logger = slog_scope::logger().new(slog_o!("request_id" => request_id.0.clone()));
+        let fut = slog_scope::scope(&logger, || self.service.call(sreq));
+        Box::pin(async move {
+            let mut resp = LogScoped {
+                logger,
+                future: Box::pin(fut),
+            }
+            .await?;
+            if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+                resp.headers_mut()
+                    .insert(HeaderName::from_static(X_REQUEST_ID), value);
+            }
+            Ok(resp)
+        })
+    }
+}
+
+/// Middleware to assign each request an id, echoed in the `X-Request-Id`
+/// response header.
+#[derive(Debug, Default)]
+pub struct RequestIdHeader;
+
+impl<S: 'static, B> Transform<S> for RequestIdHeader
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestIdHeaderMiddleware<S>;
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        Box::pin(future::ok(RequestIdHeaderMiddleware { service }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_header() {
+        let id =
+            |value: &'static str| RequestId::from_header(Some(&HeaderValue::from_static(value)));
+        assert_eq!(id("abc-123"), RequestId("abc-123".to_owned()));
+
+        // Generated when missing or unusable
+        for request_id in &[
+            RequestId::from_header(None),
+            id(""),
+            id("has spaces"),
+            RequestId::from_header(Some(&HeaderValue::from_str(&"a".repeat(129)).unwrap())),
+        ] {
+            assert!(Uuid::parse_str(&request_id.0).is_ok());
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! A tracing span for each request
+//!
+//! The span continues the trace of any W3C `traceparent` header the request
+//! carries, and is the parent of the spans recorded while handling it.
+use std::task::{Context as TaskContext, Poll};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    Error,
+};
+use futures::future::{self, LocalBoxFuture};
+use opentelemetry::{
+    trace::{FutureExt, SpanKind, StatusCode, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+
+use crate::telemetry;
+
+pub struct RequestTracingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestTracingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        let parent_cx = telemetry::extract_context(sreq.headers());
+        let tracer = telemetry::tracer();
+        let span = tracer
+            .span_builder(format!("HTTP {}", sreq.method()))
+            .with_kind(SpanKind::Server)
+            .with_attributes(vec![
+                KeyValue::new("http.method", sreq.method().to_string()),
+                KeyValue::new("http.target", sreq.path().to_owned()),
+            ])
+            .start_with_context(&tracer, &parent_cx);
+        let cx = Context::current_with_span(span);
+
+        // Inner middleware may begin handling the request (e.g. extracting
+        // the Hawk identifier) before returning its future
+        let fut = {
+            let _guard = cx.clone().attach();
+            self.service.call(sreq)
+        };
+        Box::pin(async move {
+            let result = fut.with_context(cx.clone()).await;
+            let span = cx.span();
+            match &result {
+                Ok(resp) => {
+                    let status = resp.status();
+                    span.set_attribute(KeyValue::new(
+                        "http.status_code",
+                        i64::from(status.as_u16()),
+                    ));
+                    if status.is_server_error() {
+                        span.set_status(StatusCode::Error, status.to_string());
+                    }
+                }
+                Err(e) => span.set_status(StatusCode::Error, e.to_string()),
+            }
+            span.end();
+            result
+        })
+    }
+}
+
+/// Middleware recording a tracing span for each request.
+#[derive(Debug, Default)]
+pub struct RequestTracing;
+
+impl<S: 'static, B> Transform<S> for RequestTracing
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestTracingMiddleware<S>;
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        Box::pin(future::ok(RequestTracingMiddleware { service }))
+    }
+}
@@ -1,5 +1,6 @@
 //! Web authentication, handlers, and middleware
 pub mod auth;
+pub mod encoding;
 pub mod error;
 pub mod extractors;
 pub mod handlers;
@@ -7,11 +8,12 @@ pub mod middleware;
 pub mod tags;
 
 // Known DockerFlow commands for Ops callbacks
-pub const DOCKER_FLOW_ENDPOINTS: [&str; 4] = [
+pub const DOCKER_FLOW_ENDPOINTS: [&str; 5] = [
     "/__heartbeat__",
     "/__lbheartbeat__",
     "/__version__",
     "/__error__",
+    "/__metrics__",
 ];
 
 #[macro_export]
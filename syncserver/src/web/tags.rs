@@ -17,6 +17,7 @@ use serde_json::value::Value;
 use slog::{Key, Record, KV};
 
 use crate::server::user_agent::parse_user_agent;
+use crate::web::middleware::request_id::RequestId;
 
 #[derive(Clone, Debug, Default)]
 pub struct Tags {
@@ -142,6 +143,10 @@ impl From<&RequestHead> for Tags {
         // `uri.path` causes too much cardinality for influx but keep it in
         // extra for sentry
         extra.insert("uri.path".to_owned(), req_head.uri.to_string());
+        // Likewise the request id
+        if let Some(request_id) = RequestId::get(req_head) {
+            extra.insert("request_id".to_owned(), request_id);
+        }
         Tags { tags, extra }
     }
 }
@@ -42,6 +42,12 @@ pub struct Deadman {
     pub previous_count: usize,
     pub clock_start: Option<time::Instant>,
     pub expiry: Option<time::Instant>,
+    /// Set while the operator's maintenance flag (`maintenance_file`) is
+    /// present, asking clients to back off
+    pub maintenance: bool,
+    /// Set once the server's received SIGTERM, failing the check so load
+    /// balancers drain traffic from this instance
+    pub shutting_down: bool,
 }
 
 impl From<&Settings> for Deadman {
@@ -94,6 +100,50 @@ pub struct Settings {
     /// Percentage of `lbheartbeat_ttl` time to "jitter" (adds additional,
     /// randomized time)
     pub lbheartbeat_ttl_jitter: u32,
+    /// The longest time (in seconds) a `/changes` long-poll request may wait
+    /// for a change before it is answered
+    pub max_changes_poll_timeout: u64,
+    /// The smallest storage or info response body (in bytes) that's
+    /// compressed when the client accepts a gzip or brotli encoding
+    pub response_compression_min_bytes: u32,
+
+    /// Per-user request rate limits
+    pub rate_limits: RateLimits,
+
+    /// Time (in seconds) clients are asked to back off for, via
+    /// `X-Weave-Backoff`, while the server is under maintenance (or the
+    /// database pool is saturated, with `backoff_on_pool_saturation`)
+    pub backoff_interval: u32,
+    /// Percentage of `backoff_interval` time to "jitter" (adds additional,
+    /// randomized time)
+    pub backoff_interval_jitter: u32,
+    /// Whether clients are also asked to back off while the database pool is
+    /// saturated
+    pub backoff_on_pool_saturation: bool,
+    /// Path of the operator's maintenance flag: clients are asked to back off
+    /// while this file exists
+    pub maintenance_file: Option<String>,
+    /// How often (in seconds) to check for the maintenance flag
+    pub maintenance_check_interval: u64,
+
+    /// Largest difference (in seconds) allowed between a Hawk request's
+    /// timestamp and the server's clock
+    pub hawk_timestamp_skew: u32,
+    /// Maximum number of Hawk nonces remembered to detect replayed requests
+    pub hawk_nonce_cache_size: u32,
+
+    /// Fraction of storage requests recorded in the access log
+    pub access_log_sample_rate: f64,
+
+    /// The longest time (in seconds) the server keeps serving after SIGTERM,
+    /// while failing `/__lbheartbeat__`, for in-flight transactions to finish
+    /// before it stops and aborts them
+    pub shutdown_drain_period: u64,
+    /// How long (in seconds) the server keeps failing `/__lbheartbeat__` after
+    /// SIGTERM, so load balancers notice and stop routing to it, before it
+    /// may stop early with no transactions in flight. Capped by
+    /// `shutdown_drain_period`
+    pub shutdown_lb_drain_period: u64,
 }
 
 impl Default for Settings {
@@ -114,6 +164,19 @@ impl Default for Settings {
             enabled: true,
             lbheartbeat_ttl: None,
             lbheartbeat_ttl_jitter: 25,
+            max_changes_poll_timeout: 30,
+            response_compression_min_bytes: KILOBYTE,
+            rate_limits: RateLimits::default(),
+            backoff_interval: 1800,
+            backoff_interval_jitter: 25,
+            backoff_on_pool_saturation: false,
+            maintenance_file: None,
+            maintenance_check_interval: 5,
+            hawk_timestamp_skew: 60,
+            hawk_nonce_cache_size: 100_000,
+            access_log_sample_rate: 1.0,
+            shutdown_drain_period: 30,
+            shutdown_lb_drain_period: 10,
         }
     }
 }
@@ -143,6 +206,20 @@ impl Settings {
     pub fn uses_spanner(&self) -> bool {
         self.database_url.as_str().starts_with("spanner://")
     }
+
+    /// The database file path of a `sqlite://` url: either relative
+    /// (`sqlite://syncstorage.db`) or absolute (`sqlite:///var/lib/syncstorage.db`)
+    pub fn sqlite_database_path(&self) -> Option<&str> {
+        if !self.uses_sqlite() {
+            None
+        } else {
+            Some(&self.database_url["sqlite://".len()..])
+        }
+    }
+
+    pub fn uses_sqlite(&self) -> bool {
+        self.database_url.as_str().starts_with("sqlite://")
+    }
 }
 
 /// Server-enforced limits for request payloads.
@@ -189,3 +266,57 @@ impl Default for ServerLimits {
         }
     }
 }
+
+/// Per-user request rate limits, for each class of storage route.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub enabled: bool,
+
+    /// Limit for `/info/` requests.
+    pub info: RateLimit,
+
+    /// Limit for other reads (`GET` and `HEAD` requests).
+    pub read: RateLimit,
+
+    /// Limit for writes (`POST`, `PUT` and `DELETE` requests).
+    pub write: RateLimit,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            info: RateLimit {
+                burst: 60,
+                per_second: 1.0,
+            },
+            read: RateLimit {
+                burst: 120,
+                per_second: 2.0,
+            },
+            write: RateLimit {
+                burst: 60,
+                per_second: 1.0,
+            },
+        }
+    }
+}
+
+/// A token bucket rate limit: allows bursts of up to `burst` requests,
+/// refilled at `per_second` requests per second.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            burst: 60,
+            per_second: 1.0,
+        }
+    }
+}
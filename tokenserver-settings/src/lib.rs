@@ -27,14 +27,23 @@ pub struct Settings {
     pub fxa_oauth_server_url: String,
     /// The timeout to be used when making requests to the FxA OAuth verification server.
     pub fxa_oauth_request_timeout: u64,
-    /// The JWK to be used to verify OAuth tokens. Passing a JWK to the PyFxA Python library
-    /// prevents it from making an external API call to FxA to get the JWK, yielding substantial
+    /// The JWK to be used to verify OAuth tokens. Configuring a JWK allows JWT access tokens to be
+    /// verified locally rather than via an external API call to FxA, yielding substantial
     /// performance benefits. This value should match that on the `/v1/jwks` endpoint on the FxA
     /// Auth Server.
     pub fxa_oauth_primary_jwk: Option<Jwk>,
     /// A secondary JWK to be used to verify OAuth tokens. This is intended to be used to enable
     /// seamless key rotations on FxA.
     pub fxa_oauth_secondary_jwk: Option<Jwk>,
+    /// The interval, in seconds, at which the JWKs used to verify OAuth tokens are refreshed from
+    /// the `/v1/jwks` endpoint on the FxA OAuth server. The JWKs are first fetched at startup.
+    /// Setting this to 0 disables fetching JWKs, in which case only the JWKs configured above are
+    /// used to verify tokens locally.
+    pub fxa_oauth_jwks_refresh_interval: u64,
+    /// The minimum number of seconds between refetches of the JWKs triggered by tokens signed
+    /// with an unknown key. This allows new keys to be picked up promptly when FxA rotates its
+    /// keys without letting bogus tokens flood FxA with requests.
+    pub fxa_oauth_jwks_refetch_interval: u64,
     /// The issuer expected in the BrowserID verification response.
     pub fxa_browserid_issuer: String,
     /// The audience to be sent to the FxA BrowserID verification server.
@@ -59,14 +68,11 @@ pub struct Settings {
     pub run_migrations: bool,
     /// The database ID of the Spanner node.
     pub spanner_node_id: Option<i32>,
-    /// The number of additional blocking threads to add to the blocking threadpool to handle
-    /// OAuth verification requests to FxA. This value is added to the `ACTIX_THREADPOOL` env var.
-    /// Note that this setting only applies if the OAuth public JWK is not cached, since OAuth
-    /// verifications do not require requests to FXA if the JWK is set on Tokenserver. The server
-    /// will return an error at startup if the JWK is not cached and this setting is `None`.
-    pub additional_blocking_threads_for_fxa_requests: Option<u32>,
     /// The amount of time in seconds before a token provided by Tokenserver expires.
     pub token_duration: u64,
+    /// The shared secret that must be presented as a bearer token to use the admin API under
+    /// `/__admin__`. The admin API is disabled if this is not set.
+    pub admin_secret: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -95,6 +101,8 @@ impl Default for Settings {
             fxa_oauth_request_timeout: 10,
             fxa_oauth_primary_jwk: None,
             fxa_oauth_secondary_jwk: None,
+            fxa_oauth_jwks_refresh_interval: 3600,
+            fxa_oauth_jwks_refetch_interval: 60,
             fxa_browserid_audience: "https://token.stage.mozaws.net".to_owned(),
             fxa_browserid_issuer: "api-accounts.stage.mozaws.net".to_owned(),
             fxa_browserid_server_url: "https://verifier.stage.mozaws.net/v2".to_owned(),
@@ -105,8 +113,8 @@ impl Default for Settings {
             statsd_label: "syncstorage.tokenserver".to_owned(),
             run_migrations: cfg!(test),
             spanner_node_id: None,
-            additional_blocking_threads_for_fxa_requests: Some(1),
             token_duration: 3600,
+            admin_secret: None,
         }
     }
 }